@@ -0,0 +1,275 @@
+//! Field oriented current control.
+//!
+//! Runs once per PWM period: the measured stationary currents are rotated into the
+//! rotor frame, `Id` and `Iq` are regulated by two PI controllers and the resulting
+//! voltage is limited to the modulation circle and rotated back for the modulator.
+//!
+//! Currents are Q15 fractions of the full scale current of the current sense, voltages
+//! Q15 fractions of the bus voltage, as taken by [`crate::svpwm::duties`].
+
+use crate::pid::{Gains, Pid};
+use crate::svpwm::{circle_headroom, LINEAR_LIMIT};
+use crate::transform::{inverse_park, park, q15, AlphaBeta, Dq};
+use crate::trig::sin_cos;
+
+/// PI gains in per unit that cancel the winding pole for a closed loop bandwidth of
+/// `bandwidth_hz`.
+///
+/// `resistance` and `inductance` are phase values in ohm and henry, the per unit bases
+/// are the bus voltage and the full scale current of the current sense.
+pub fn tuned_gains(
+    resistance: f32,
+    inductance: f32,
+    bandwidth_hz: f32,
+    bus_volts: f32,
+    full_scale_amps: f32,
+) -> Gains {
+    let w = 2.0 * core::f32::consts::PI * bandwidth_hz;
+    let base_ohms = bus_volts / full_scale_amps;
+    Gains::pi(inductance * w / base_ohms, resistance * w / base_ohms)
+}
+
+/// `Id` and `Iq` controllers with the voltage limit.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CurrentLoop {
+    d: Pid,
+    q: Pid,
+    /// radius of the voltage circle
+    limit: i16,
+    setpoint: Dq<i16>,
+    feed_forward: Dq<i16>,
+    current: Dq<i16>,
+    voltage: Dq<i16>,
+}
+
+impl CurrentLoop {
+    /// Controller run at `sample_hz`, the PWM frequency, with the same gains on both axes
+    /// and the voltage limited to the linear range of the modulator.
+    pub fn new(gains: Gains, sample_hz: f32) -> Self {
+        let mut current_loop = Self {
+            d: Pid::new(gains, sample_hz),
+            q: Pid::new(gains, sample_hz),
+            limit: 0,
+            setpoint: Dq::default(),
+            feed_forward: Dq::default(),
+            current: Dq::default(),
+            voltage: Dq::default(),
+        };
+        current_loop.set_limit(q15(LINEAR_LIMIT));
+        current_loop
+    }
+
+    pub fn set_gains(&mut self, d: Gains, q: Gains) {
+        self.d.set_gains(d);
+        self.q.set_gains(q);
+    }
+
+    /// Radius of the voltage circle as a fraction of the bus, at most
+    /// [`LINEAR_LIMIT`] without overmodulation.
+    pub fn set_limit(&mut self, limit: i16) {
+        self.limit = limit;
+        self.d.set_limits(-limit as i32, limit as i32);
+        self.q.set_limits(-limit as i32, limit as i32);
+    }
+
+    pub fn limit(&self) -> i16 {
+        self.limit
+    }
+
+    /// Current setpoints, `q` makes torque and `d` weakens or strengthens the field.
+    pub fn set_setpoint(&mut self, setpoint: Dq<i16>) {
+        self.setpoint = setpoint;
+    }
+
+    pub fn setpoint(&self) -> Dq<i16> {
+        self.setpoint
+    }
+
+    /// Voltage added to the controller outputs, e.g. the back-EMF or cross coupling.
+    pub fn set_feed_forward(&mut self, feed_forward: Dq<i16>) {
+        self.feed_forward = feed_forward;
+    }
+
+    pub fn feed_forward(&self) -> Dq<i16> {
+        self.feed_forward
+    }
+
+    /// Run one period with the measured currents and the electrical angle of the rotor,
+    /// returning the stationary voltage to modulate.
+    #[inline]
+    pub fn update(&mut self, currents: AlphaBeta<i16>, angle: u16) -> AlphaBeta<i16> {
+        let (sin, cos) = sin_cos(angle);
+        self.current = park(currents, sin, cos);
+
+        let d = self.d.update(
+            self.setpoint.d as i32,
+            self.current.d as i32,
+            self.feed_forward.d as i32,
+        ) as i16;
+        // d has priority, q gets what is left of the circle
+        let headroom = circle_headroom(d, self.limit) as i32;
+        self.q.set_limits(-headroom, headroom);
+        let q = self.q.update(
+            self.setpoint.q as i32,
+            self.current.q as i32,
+            self.feed_forward.q as i32,
+        ) as i16;
+
+        self.voltage = Dq { d, q };
+        inverse_park(self.voltage, sin, cos)
+    }
+
+    /// Latest measured rotor frame currents.
+    pub fn current(&self) -> Dq<i16> {
+        self.current
+    }
+
+    /// Latest rotor frame voltage.
+    pub fn voltage(&self) -> Dq<i16> {
+        self.voltage
+    }
+
+    /// Take over from a voltage applied open loop, e.g. during alignment, without a step.
+    pub fn track(&mut self, voltage: Dq<i16>, currents: AlphaBeta<i16>, angle: u16) {
+        let (sin, cos) = sin_cos(angle);
+        self.current = park(currents, sin, cos);
+        self.voltage = voltage;
+        self.d.track(
+            voltage.d as i32,
+            self.setpoint.d as i32,
+            self.current.d as i32,
+            self.feed_forward.d as i32,
+        );
+        self.q.track(
+            voltage.q as i32,
+            self.setpoint.q as i32,
+            self.current.q as i32,
+            self.feed_forward.q as i32,
+        );
+    }
+
+    /// Clear both integrals, e.g. after the stage was switched off.
+    pub fn reset(&mut self) {
+        self.d.reset(self.current.d as i32);
+        self.q.reset(self.current.q as i32);
+        self.voltage = Dq::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svpwm::magnitude;
+    use crate::transform::{clarke3, inverse_clarke, Abc};
+
+    const FS: f32 = 16_000.0;
+    const BUS: f64 = 12.0;
+    const FULL_SCALE: f64 = 10.0;
+    const R: f64 = 0.5;
+    const L: f64 = 0.000_5;
+
+    /// Star connected RL load without back-EMF, stepped in amps and volts.
+    struct Winding {
+        i: [f64; 3],
+    }
+
+    impl Winding {
+        /// Apply phase voltages as bus fractions, return Q15 stationary currents.
+        fn step(&mut self, v: AlphaBeta<i16>) -> AlphaBeta<i16> {
+            let abc = inverse_clarke(v);
+            let phases = [abc.a, abc.b, abc.c].map(|x| x as f64 / 32_768.0 * BUS);
+            for (i, v) in self.i.iter_mut().zip(phases) {
+                *i += (v - R * *i) / L / FS as f64;
+            }
+            let q15 = |i: f64| (i / FULL_SCALE * 32_768.0).round() as i16;
+            clarke3(Abc {
+                a: q15(self.i[0]),
+                b: q15(self.i[1]),
+                c: q15(self.i[2]),
+            })
+        }
+    }
+
+    fn current_loop() -> CurrentLoop {
+        let gains = tuned_gains(R as f32, L as f32, 800.0, BUS as f32, FULL_SCALE as f32);
+        CurrentLoop::new(gains, FS)
+    }
+
+    #[test]
+    fn gains_cancel_the_winding_pole() {
+        let gains = tuned_gains(R as f32, L as f32, 800.0, BUS as f32, FULL_SCALE as f32);
+        assert!((gains.ki / gains.kp - (R / L) as f32).abs() < 1.0);
+    }
+
+    #[test]
+    fn tracks_dq_setpoints_while_rotating() {
+        let mut current_loop = current_loop();
+        let setpoint = Dq {
+            d: q15(-0.1),
+            q: q15(0.3),
+        };
+        current_loop.set_setpoint(setpoint);
+        let mut winding = Winding { i: [0.0; 3] };
+        let mut currents = AlphaBeta::default();
+        let mut angle = 0u16;
+        for n in 0..8_000 {
+            // 100 Hz electrical
+            angle = angle.wrapping_add((65_536 * 100 / FS as u32) as u16);
+            let v = current_loop.update(currents, angle);
+            currents = winding.step(v);
+            if n > 1_600 {
+                let i = current_loop.current();
+                assert!((i.d - setpoint.d).abs() < 200, "{n}: {i:?}");
+                assert!((i.q - setpoint.q).abs() < 200, "{n}: {i:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn voltage_stays_in_circle() {
+        let mut current_loop = current_loop();
+        current_loop.set_limit(q15(0.1));
+        // needs far more than 0.1 of the bus
+        current_loop.set_setpoint(Dq {
+            d: q15(0.2),
+            q: q15(0.9),
+        });
+        let mut winding = Winding { i: [0.0; 3] };
+        let mut currents = AlphaBeta::default();
+        for n in 0..4_000u32 {
+            let angle = (n * 300) as u16;
+            let v = current_loop.update(currents, angle);
+            currents = winding.step(v);
+            assert!(magnitude(current_loop.voltage()) <= q15(0.1) + 2);
+        }
+        // d was served first, q got what was left
+        let i = current_loop.current();
+        assert!((i.d - q15(0.2)).abs() < 200, "{i:?}");
+        assert!(i.q < q15(0.5), "{i:?}");
+
+        // no wind up, the currents follow a reachable setpoint right away
+        current_loop.set_setpoint(Dq::default());
+        for n in 0..800u32 {
+            let v = current_loop.update(currents, (n * 300) as u16);
+            currents = winding.step(v);
+        }
+        let i = current_loop.current();
+        assert!(i.d.abs() < 200 && i.q.abs() < 200, "{i:?}");
+    }
+
+    #[test]
+    fn takes_over_without_a_step() {
+        let mut current_loop = current_loop();
+        let applied = Dq { d: q15(0.05), q: 0 };
+        let currents = AlphaBeta {
+            alpha: q15(0.08),
+            beta: 0,
+        };
+        current_loop.set_setpoint(Dq { d: q15(0.08), q: 0 });
+        current_loop.track(applied, currents, 0);
+        current_loop.update(currents, 0);
+        let v = current_loop.voltage();
+        assert!((v.d - applied.d).abs() <= 2 && v.q.abs() <= 2, "{v:?}");
+    }
+}
@@ -0,0 +1,21 @@
+//! Common interface of the sensorless angle and velocity estimators.
+//!
+//! Every estimator runs once per PWM period on the measured stationary currents and the
+//! voltage applied while they developed, both Q15 per unit as in [`crate::current`], so
+//! the current control can switch between them, see [`crate::smo`] and [`crate::flux`].
+
+use crate::transform::AlphaBeta;
+
+pub trait Estimator {
+    /// Call every period with the measured currents and the applied voltage.
+    fn update(&mut self, currents: AlphaBeta<i16>, voltage: AlphaBeta<i16>);
+
+    /// Estimated electrical angle at the latest sample.
+    fn angle(&self) -> u16;
+
+    /// Estimated electrical velocity, counts/s.
+    fn velocity(&self) -> i32;
+
+    /// Forget the estimate, e.g. before a new start.
+    fn reset(&mut self);
+}
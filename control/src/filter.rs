@@ -0,0 +1,507 @@
+//! Filters for sampled signals such as phase currents, velocity estimates and the bus
+//! voltage.
+//!
+//! Fixed point filters take and return `i32` samples in the caller's units, e.g.
+//! milliamps, so they can sit directly behind `Isense`. Coefficients are designed in
+//! `f32` once at construction.
+
+use core::f32::consts::PI;
+
+/// Fractional bits of [`LowPass`] coefficients.
+const LOW_PASS_SHIFT: u32 = 16;
+/// Fractional bits of [`Biquad`] coefficients, leaves room for |a1| < 2.
+const BIQUAD_SHIFT: u32 = 28;
+
+/// Sine and cosine for `0 <= x <= PI`, only used for coefficient design.
+fn sin_cos(x: f32) -> (f32, f32) {
+    // shift into [-PI/2, PI/2] where the series converges quickly
+    let x = x - PI / 2.0;
+    let x2 = x * x;
+    let mut sin = 0.0;
+    let mut cos = 0.0;
+    let mut term = x;
+    for n in 1..=6 {
+        sin += term;
+        term *= -x2 / ((2 * n) * (2 * n + 1)) as f32;
+    }
+    let mut term = 1.0;
+    for n in 1..=7 {
+        cos += term;
+        term *= -x2 / ((2 * n - 1) * (2 * n)) as f32;
+    }
+    // sin(x + PI/2) = cos(x), cos(x + PI/2) = -sin(x)
+    (cos, -sin)
+}
+
+/// Smoothing factor of a first order low pass, from the backward Euler discretization.
+fn low_pass_alpha(cutoff_hz: f32, sample_hz: f32) -> f32 {
+    let w = 2.0 * PI * cutoff_hz / sample_hz;
+    w / (1.0 + w)
+}
+
+/// First order IIR low pass, `y += alpha * (x - y)`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LowPassF32 {
+    alpha: f32,
+    y: f32,
+}
+
+impl LowPassF32 {
+    pub fn new(alpha: f32) -> Self {
+        Self { alpha, y: 0.0 }
+    }
+
+    pub fn from_cutoff(cutoff_hz: f32, sample_hz: f32) -> Self {
+        Self::new(low_pass_alpha(cutoff_hz, sample_hz))
+    }
+
+    pub fn update(&mut self, x: f32) -> f32 {
+        self.y += self.alpha * (x - self.y);
+        self.y
+    }
+
+    pub fn value(&self) -> f32 {
+        self.y
+    }
+
+    /// Jump to `value`, e.g. the first sample, to skip the initial transient.
+    pub fn reset(&mut self, value: f32) {
+        self.y = value;
+    }
+}
+
+/// First order IIR low pass in fixed point.
+///
+/// The state keeps 16 fractional bits so small steps are not lost to rounding, a
+/// plain `i32` state would stall up to `1 / alpha` counts short of the input.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LowPass {
+    /// smoothing factor in Q16, at most 1.0
+    alpha: i64,
+    /// output in Q16
+    y: i64,
+}
+
+impl LowPass {
+    pub fn new(alpha_q16: u32) -> Self {
+        Self {
+            alpha: (alpha_q16 as i64).min(1 << LOW_PASS_SHIFT),
+            y: 0,
+        }
+    }
+
+    pub fn from_cutoff(cutoff_hz: f32, sample_hz: f32) -> Self {
+        let alpha = low_pass_alpha(cutoff_hz, sample_hz);
+        Self::new((alpha * (1 << LOW_PASS_SHIFT) as f32 + 0.5) as u32)
+    }
+
+    pub fn update(&mut self, x: i32) -> i32 {
+        let x = (x as i64) << LOW_PASS_SHIFT;
+        self.y += ((x - self.y) * self.alpha) >> LOW_PASS_SHIFT;
+        self.value()
+    }
+
+    pub fn value(&self) -> i32 {
+        ((self.y + (1 << (LOW_PASS_SHIFT - 1))) >> LOW_PASS_SHIFT) as i32
+    }
+
+    pub fn reset(&mut self, value: i32) {
+        self.y = (value as i64) << LOW_PASS_SHIFT;
+    }
+}
+
+/// Normalized second order section, `a0` is 1.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Coefficients {
+    pub b0: f32,
+    pub b1: f32,
+    pub b2: f32,
+    pub a1: f32,
+    pub a2: f32,
+}
+
+impl Coefficients {
+    /// Second order Butterworth-like low pass, `q` of 0.7071 for a flat passband.
+    pub fn low_pass(cutoff_hz: f32, sample_hz: f32, q: f32) -> Self {
+        let (sin, cos) = sin_cos(2.0 * PI * cutoff_hz / sample_hz);
+        let alpha = sin / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: (1.0 - cos) / 2.0 / a0,
+            b1: (1.0 - cos) / a0,
+            b2: (1.0 - cos) / 2.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+        }
+    }
+
+    /// Notch at `center_hz`, the -3 dB width is `center_hz / q`.
+    pub fn notch(center_hz: f32, sample_hz: f32, q: f32) -> Self {
+        let (sin, cos) = sin_cos(2.0 * PI * center_hz / sample_hz);
+        let alpha = sin / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        Self {
+            b0: 1.0 / a0,
+            b1: -2.0 * cos / a0,
+            b2: 1.0 / a0,
+            a1: -2.0 * cos / a0,
+            a2: (1.0 - alpha) / a0,
+        }
+    }
+}
+
+/// Biquad in direct form I.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BiquadF32 {
+    c: Coefficients,
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl BiquadF32 {
+    pub fn new(c: Coefficients) -> Self {
+        Self {
+            c,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    pub fn update(&mut self, x: f32) -> f32 {
+        let c = &self.c;
+        let y =
+            c.b0 * x + c.b1 * self.x[0] + c.b2 * self.x[1] - c.a1 * self.y[0] - c.a2 * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+
+    /// Settle on a constant input `value`, assuming unity gain at DC.
+    pub fn reset(&mut self, value: f32) {
+        self.x = [value; 2];
+        self.y = [value; 2];
+    }
+}
+
+/// Biquad in direct form I with Q4.28 coefficients and a 64 bit accumulator.
+///
+/// Direct form I only stores inputs and outputs, so the state cannot overflow as long
+/// as the output fits an `i32`. The rounding error of the accumulator is fed back into
+/// the next sample, which keeps low cutoff filters from drifting.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Biquad {
+    b: [i64; 3],
+    a: [i64; 2],
+    x: [i32; 2],
+    y: [i32; 2],
+    error: i64,
+}
+
+impl Biquad {
+    pub fn new(c: Coefficients) -> Self {
+        let q = |v: f32| {
+            let v = v as f64 * (1u64 << BIQUAD_SHIFT) as f64;
+            (if v < 0.0 { v - 0.5 } else { v + 0.5 }) as i64
+        };
+        Self {
+            b: [q(c.b0), q(c.b1), q(c.b2)],
+            a: [q(c.a1), q(c.a2)],
+            x: [0; 2],
+            y: [0; 2],
+            error: 0,
+        }
+    }
+
+    pub fn update(&mut self, x: i32) -> i32 {
+        let acc =
+            self.b[0] * x as i64 + self.b[1] * self.x[0] as i64 + self.b[2] * self.x[1] as i64
+                - self.a[0] * self.y[0] as i64
+                - self.a[1] * self.y[1] as i64
+                + self.error;
+        let y = (acc >> BIQUAD_SHIFT) as i32;
+        self.error = acc - ((y as i64) << BIQUAD_SHIFT);
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+
+    pub fn reset(&mut self, value: i32) {
+        self.x = [value; 2];
+        self.y = [value; 2];
+        self.error = 0;
+    }
+}
+
+/// Average of the last `N` samples.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MovingAverage<const N: usize> {
+    samples: [i32; N],
+    next: usize,
+    sum: i64,
+}
+
+impl<const N: usize> Default for MovingAverage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> MovingAverage<N> {
+    pub const fn new() -> Self {
+        Self {
+            samples: [0; N],
+            next: 0,
+            sum: 0,
+        }
+    }
+
+    pub fn update(&mut self, x: i32) -> i32 {
+        self.sum += x as i64 - self.samples[self.next] as i64;
+        self.samples[self.next] = x;
+        self.next = (self.next + 1) % N;
+        self.value()
+    }
+
+    pub fn value(&self) -> i32 {
+        (self.sum / N as i64) as i32
+    }
+
+    pub fn reset(&mut self, value: i32) {
+        self.samples = [value; N];
+        self.sum = value as i64 * N as i64;
+    }
+}
+
+/// Average of the last `N` samples in `f32`.
+///
+/// The running sum is rebuilt once per wrap so rounding errors do not accumulate.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MovingAverageF32<const N: usize> {
+    samples: [f32; N],
+    next: usize,
+    sum: f32,
+}
+
+impl<const N: usize> Default for MovingAverageF32<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> MovingAverageF32<N> {
+    pub const fn new() -> Self {
+        Self {
+            samples: [0.0; N],
+            next: 0,
+            sum: 0.0,
+        }
+    }
+
+    pub fn update(&mut self, x: f32) -> f32 {
+        self.sum += x - self.samples[self.next];
+        self.samples[self.next] = x;
+        self.next = (self.next + 1) % N;
+        if self.next == 0 {
+            self.sum = self.samples.iter().sum();
+        }
+        self.value()
+    }
+
+    pub fn value(&self) -> f32 {
+        self.sum / N as f32
+    }
+
+    pub fn reset(&mut self, value: f32) {
+        self.samples = [value; N];
+        self.sum = value * N as f32;
+    }
+}
+
+/// Median of the last `N` samples, removes isolated spikes without smearing edges.
+///
+/// Works for `i32` and `f32` alike. Each update sorts a copy of the window, keep `N`
+/// small, 3 or 5 is typical.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Median<T, const N: usize> {
+    samples: [T; N],
+    next: usize,
+}
+
+impl<T: Copy + PartialOrd, const N: usize> Median<T, N> {
+    pub fn new(value: T) -> Self {
+        Self {
+            samples: [value; N],
+            next: 0,
+        }
+    }
+
+    pub fn update(&mut self, x: T) -> T {
+        self.samples[self.next] = x;
+        self.next = (self.next + 1) % N;
+        self.value()
+    }
+
+    pub fn value(&self) -> T {
+        let mut sorted = self.samples;
+        // insertion sort, N is small
+        for i in 1..N {
+            let mut j = i;
+            while j > 0 && sorted[j - 1] > sorted[j] {
+                sorted.swap(j - 1, j);
+                j -= 1;
+            }
+        }
+        sorted[N / 2]
+    }
+
+    pub fn reset(&mut self, value: T) {
+        self.samples = [value; N];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FS: f32 = 16_000.0;
+
+    /// Direct form I in f64 as the reference.
+    fn reference(c: &Coefficients, input: &[f64]) -> Vec<f64> {
+        let (b0, b1, b2) = (c.b0 as f64, c.b1 as f64, c.b2 as f64);
+        let (a1, a2) = (c.a1 as f64, c.a2 as f64);
+        let (mut x1, mut x2, mut y1, mut y2) = (0.0, 0.0, 0.0, 0.0);
+        input
+            .iter()
+            .map(|&x| {
+                let y = b0 * x + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+                (x2, x1, y2, y1) = (x1, x, y1, y);
+                y
+            })
+            .collect()
+    }
+
+    /// Steady state amplitude of a sine at `hz` through `filter`.
+    fn gain(mut filter: impl FnMut(f64) -> f64, hz: f64) -> f64 {
+        let w = 2.0 * std::f64::consts::PI * hz / FS as f64;
+        (0..16_000)
+            .map(|n| filter((w * n as f64).sin()))
+            .skip(8_000)
+            .fold(0.0, |peak: f64, y| peak.max(y.abs()))
+    }
+
+    #[test]
+    fn sin_cos_matches_std() {
+        for i in 0..=100 {
+            let x = PI * i as f32 / 100.0;
+            let (sin, cos) = sin_cos(x);
+            assert!((sin - x.sin()).abs() < 1e-6, "sin({x})");
+            assert!((cos - x.cos()).abs() < 1e-6, "cos({x})");
+        }
+    }
+
+    #[test]
+    fn low_pass_step_response() {
+        let alpha = 0.1f32;
+        let mut filter = LowPassF32::new(alpha);
+        for n in 1..100 {
+            let expected = 1.0 - (1.0 - alpha as f64).powi(n);
+            assert!((filter.update(1.0) as f64 - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn low_pass_fixed_tracks_float_and_settles() {
+        let mut fixed = LowPass::from_cutoff(100.0, FS);
+        let mut float = LowPassF32::from_cutoff(100.0, FS);
+        for _ in 0..2_000 {
+            let y = fixed.update(1_000);
+            assert!((y as f32 - float.update(1_000.0)).abs() <= 1.0);
+        }
+        // no deadband, the output reaches the input
+        assert_eq!(fixed.value(), 1_000);
+        for _ in 0..2_000 {
+            fixed.update(-3);
+        }
+        assert_eq!(fixed.value(), -3);
+    }
+
+    #[test]
+    fn biquad_low_pass_matches_reference() {
+        let c = Coefficients::low_pass(500.0, FS, core::f32::consts::FRAC_1_SQRT_2);
+        let input: Vec<f64> = (0..500)
+            .map(|n| if n < 250 { 1_000.0 } else { -400.0 })
+            .collect();
+        let expected = reference(&c, &input);
+
+        let mut float = BiquadF32::new(c);
+        let mut fixed = Biquad::new(c);
+        for (x, y) in input.iter().zip(expected.iter()) {
+            assert!((float.update(*x as f32) as f64 - y).abs() < 1e-2);
+            // the integer output is fed back, allow a couple of counts
+            assert!((fixed.update(*x as i32) as f64 - y).abs() <= 2.0);
+        }
+    }
+
+    #[test]
+    fn biquad_low_pass_response() {
+        let c = Coefficients::low_pass(500.0, FS, core::f32::consts::FRAC_1_SQRT_2);
+        let mut filter = BiquadF32::new(c);
+        let dc = (0..1_000).map(|_| filter.update(1.0)).last().unwrap();
+        assert!((dc - 1.0).abs() < 1e-4);
+
+        let mut filter = BiquadF32::new(c);
+        let cutoff = gain(|x| filter.update(x as f32) as f64, 500.0);
+        assert!((cutoff - std::f64::consts::FRAC_1_SQRT_2).abs() < 0.01);
+
+        let mut filter = BiquadF32::new(c);
+        // 12 dB per octave well above the cutoff
+        assert!(gain(|x| filter.update(x as f32) as f64, 4_000.0) < 0.02);
+    }
+
+    #[test]
+    fn notch_rejects_center_only() {
+        let c = Coefficients::notch(1_000.0, FS, 5.0);
+        let mut filter = BiquadF32::new(c);
+        assert!(gain(|x| filter.update(x as f32) as f64, 1_000.0) < 0.01);
+        let mut filter = BiquadF32::new(c);
+        assert!(gain(|x| filter.update(x as f32) as f64, 100.0) > 0.99);
+
+        let mut fixed = Biquad::new(c);
+        let center = gain(|x| fixed.update((x * 10_000.0) as i32) as f64, 1_000.0);
+        assert!(center < 100.0);
+    }
+
+    #[test]
+    fn moving_average_of_ramp() {
+        let mut fixed = MovingAverage::<4>::new();
+        let mut float = MovingAverageF32::<4>::new();
+        let outputs: Vec<i32> = (1..=8).map(|x| fixed.update(x * 4)).collect();
+        assert_eq!(outputs, [1, 3, 6, 10, 14, 18, 22, 26]);
+        for x in 1..=8 {
+            float.update(x as f32);
+        }
+        assert_eq!(float.value(), 6.5);
+
+        fixed.reset(-7);
+        assert_eq!(fixed.update(-7), -7);
+    }
+
+    #[test]
+    fn median_removes_spikes() {
+        let mut filter = Median::<i32, 3>::new(0);
+        let input = [10, 10, 500, 10, 11, -300, 12, 12];
+        let output: Vec<i32> = input.iter().map(|x| filter.update(*x)).collect();
+        assert_eq!(output, [0, 10, 10, 10, 11, 10, 11, 12]);
+
+        let mut filter = Median::<f32, 5>::new(1.0);
+        assert_eq!(filter.update(f32::MAX), 1.0);
+    }
+}
@@ -0,0 +1,236 @@
+//! Nonlinear flux observer with a PLL for the angle and velocity.
+//!
+//! The stator flux `x = L i + ψ (cos θ, sin θ)` integrates `v - R i`. Open loop the
+//! integral drifts with any offset, so following Ortega et al. the magnet flux
+//! `η = x - L i` is pulled back onto its known magnitude,
+//!
+//! `dx/dt = v - R i + γ/2 η (ψ² - |η|²)`,
+//!
+//! which converges for any initial flux. Unlike a back EMF the flux does not shrink
+//! with the speed, so the estimate holds down to a few percent of the rated speed and
+//! has no sign ambiguity when reversing, given good values of `R`, `L` and `ψ`.
+//! A [`Pll`] locks onto the direction of `η`, so no arctangent is needed and the
+//! velocity comes filtered by the loop.
+//!
+//! The flux is normalized to the magnet flux and kept in Q24, one update is about 20
+//! `i64` multiplies and one [`crate::trig::sin_cos`] for the phase detector.
+
+use crate::estimator::Estimator;
+use crate::pll::{Pll, COUNTS_PER_RADIAN};
+use crate::transform::AlphaBeta;
+use crate::trig::sin_cos;
+
+/// Fractional bits of the flux and the nonlinear gain.
+const SHIFT: u32 = 24;
+const ONE: i64 = 1 << SHIFT;
+/// Extra fractional bits of the coefficients of the Q15 inputs.
+const INPUT_SHIFT: u32 = 16;
+
+/// Motor and tuning of a [`FluxObserver`].
+///
+/// `resistance` and `inductance` are phase values in ohm and henry and `flux_linkage`
+/// the magnet flux in Vs, the per unit bases are the bus voltage and the full scale
+/// current of the current sense, as for [`crate::current::tuned_gains`].
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FluxObserverConfig {
+    pub resistance: f32,
+    pub inductance: f32,
+    pub flux_linkage: f32,
+    pub bus_volts: f32,
+    pub full_scale_amps: f32,
+    /// `γ` for the flux normalized to the magnet flux, 1/s, a few thousand
+    pub gain: f32,
+    pub pll_bandwidth_hz: f32,
+}
+
+/// Ortega nonlinear flux observer, see the module documentation.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FluxObserver {
+    /// Q40 flux per sample of Q15 voltage and current and flux per Q15 current, Q24
+    /// `γ Ts / 2`
+    voltage: i64,
+    resistance: i64,
+    inductance: i64,
+    gain: i64,
+    /// Q24 stator flux
+    flux: AlphaBeta<i32>,
+    /// Q24 magnet flux
+    magnet: AlphaBeta<i32>,
+    pll: Pll,
+}
+
+impl FluxObserver {
+    /// Observer run every period of a `sample_hz` current loop.
+    pub fn new(config: FluxObserverConfig, sample_hz: u32) -> Self {
+        let ts = 1.0 / sample_hz as f64;
+        let flux = config.flux_linkage as f64;
+        // Q24 flux per Q15 input, with the extra bits
+        let fixed = |x: f64| (x / flux * (1u64 << (SHIFT - 15 + INPUT_SHIFT)) as f64 + 0.5) as i64;
+        let volts = config.bus_volts as f64;
+        let amps = config.full_scale_amps as f64;
+        Self {
+            voltage: fixed(volts * ts),
+            resistance: fixed(config.resistance as f64 * amps * ts),
+            inductance: fixed(config.inductance as f64 * amps),
+            gain: (config.gain as f64 * ts / 2.0 * ONE as f64 + 0.5) as i64,
+            flux: AlphaBeta::default(),
+            magnet: AlphaBeta::default(),
+            pll: Pll::new(config.pll_bandwidth_hz, sample_hz),
+        }
+    }
+
+    /// Estimated magnet flux, Q15 of the magnet flux linkage.
+    pub fn magnet_flux(&self) -> AlphaBeta<i16> {
+        let q15 = |x: i32| (x >> (SHIFT - 15)).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        AlphaBeta {
+            alpha: q15(self.magnet.alpha),
+            beta: q15(self.magnet.beta),
+        }
+    }
+}
+
+impl Estimator for FluxObserver {
+    fn update(&mut self, currents: AlphaBeta<i16>, voltage: AlphaBeta<i16>) {
+        let integrate = |flux: &mut i32, v: i16, i: i16| {
+            *flux += ((self.voltage * v as i64 - self.resistance * i as i64) >> INPUT_SHIFT) as i32;
+            *flux - ((self.inductance * i as i64) >> INPUT_SHIFT) as i32
+        };
+        let mut flux = self.flux;
+        let alpha = integrate(&mut flux.alpha, voltage.alpha, currents.alpha);
+        let beta = integrate(&mut flux.beta, voltage.beta, currents.beta);
+
+        // pull the magnet flux onto the unit circle
+        let (alpha, beta) = (alpha as i64, beta as i64);
+        let error = ONE - ((alpha * alpha + beta * beta) >> SHIFT);
+        let pull = (self.gain * error) >> SHIFT;
+        let correction = |x: i64| ((x * pull) >> SHIFT) as i32;
+        flux.alpha += correction(alpha);
+        flux.beta += correction(beta);
+        self.flux = flux;
+        self.magnet = AlphaBeta {
+            alpha: (alpha as i32) + correction(alpha),
+            beta: (beta as i32) + correction(beta),
+        };
+
+        // |η| sin(θη - θ), with |η| close to one
+        let (sin, cos) = sin_cos(self.pll.predict());
+        let cross =
+            (self.magnet.beta as i64 * cos as i64 - self.magnet.alpha as i64 * sin as i64) >> 15;
+        let radians = COUNTS_PER_RADIAN as i64;
+        self.pll.correct(((cross * radians) >> SHIFT) as i32);
+    }
+
+    fn angle(&self) -> u16 {
+        self.pll.angle()
+    }
+
+    fn velocity(&self) -> i32 {
+        self.pll.velocity()
+    }
+
+    fn reset(&mut self) {
+        self.flux = AlphaBeta::default();
+        self.magnet = AlphaBeta::default();
+        self.pll.reset(0, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{
+        current_loop, periods, Motor, BUS_VOLTS, FLUX, FULL_SCALE_AMPS, INDUCTANCE, PWM_HZ,
+        RESISTANCE,
+    };
+    use crate::smo::{SlidingModeConfig, SlidingModeObserver};
+    use crate::transform::{q15, Dq};
+
+    fn observer() -> FluxObserver {
+        FluxObserver::new(
+            FluxObserverConfig {
+                resistance: RESISTANCE as f32,
+                inductance: INDUCTANCE as f32,
+                flux_linkage: FLUX as f32,
+                bus_volts: BUS_VOLTS as f32,
+                full_scale_amps: FULL_SCALE_AMPS as f32,
+                gain: 2_000.0,
+                pll_bandwidth_hz: 100.0,
+            },
+            PWM_HZ,
+        )
+    }
+
+    /// Motor turning at a constant velocity under field oriented current control with
+    /// the true angle, returns the worst angle error in counts after `settle` seconds.
+    fn run(estimator: &mut dyn Estimator, hz: f64, settle: f64) -> i32 {
+        let mut current_loop = current_loop(INDUCTANCE, 1_000.0);
+        current_loop.set_setpoint(Dq { d: 0, q: q15(0.2) });
+        let mut motor = Motor::non_salient(2.0);
+        let mut voltage = AlphaBeta::default();
+        let mut worst = 0;
+        for n in 0..periods(settle + 0.2) {
+            let currents = motor.currents();
+            estimator.update(currents, voltage);
+            if n > periods(settle) {
+                let error = estimator.angle().wrapping_sub(motor.angle()) as i16 as i32;
+                worst = worst.max(error.abs());
+            }
+            voltage = current_loop.update(currents, motor.angle());
+            motor.step(voltage, hz);
+        }
+        worst
+    }
+    #[test]
+    fn tracks_angle_and_velocity() {
+        for hz in [100.0, -100.0, 300.0] {
+            let mut observer = observer();
+            let worst = run(&mut observer, hz, 0.3);
+            // a degree or two
+            assert!(worst < 350, "{hz} Hz: {worst}");
+            let velocity = hz * 65_536.0;
+            let error = (observer.velocity() as f64 - velocity).abs();
+            assert!(
+                error < 0.01 * velocity.abs(),
+                "{hz} Hz: {}",
+                observer.velocity()
+            );
+            let flux = observer.magnet_flux();
+            let magnitude = (flux.alpha as f64).hypot(flux.beta as f64) / 32_768.0;
+            assert!((magnitude - 1.0).abs() < 0.02, "{magnitude}");
+        }
+    }
+
+    #[test]
+    fn holds_at_low_speed() {
+        // a back EMF of 0.4% of the bus, converging from no flux takes a few turns
+        let mut observer = observer();
+        let worst = run(&mut observer, 4.0, 2.0);
+        assert!(worst < 700, "{worst}");
+    }
+
+    #[test]
+    fn shares_the_estimator_with_the_sliding_mode_observer() {
+        let config = SlidingModeConfig {
+            resistance: RESISTANCE as f32,
+            inductance: INDUCTANCE as f32,
+            bus_volts: BUS_VOLTS as f32,
+            full_scale_amps: FULL_SCALE_AMPS as f32,
+            gain: 0.5,
+            boundary: 0.125,
+            cutoff_ratio: 1.0,
+            min_cutoff_hz: 20.0,
+            velocity_cutoff_hz: 50.0,
+        };
+        let estimators: [&mut dyn Estimator; 2] = [
+            &mut observer(),
+            &mut SlidingModeObserver::new(config, PWM_HZ),
+        ];
+        for estimator in estimators {
+            assert!(run(estimator, 100.0, 0.3) < 700);
+            estimator.reset();
+            assert_eq!((estimator.angle(), estimator.velocity()), (0, 0));
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! High frequency injection for sensorless operation down to standstill.
+//!
+//! A square wave voltage of alternating sign every period is added to the d axis
+//! command, e.g. as the d feed-forward of the [`crate::current::CurrentLoop`]. On an
+//! interior PM motor `Ld < Lq`, so unless the estimated d axis is on the rotor's the
+//! current response has a component on the estimated q axis,
+//!
+//! `Δiq = Ts Vh / 2 (1/Ld - 1/Lq) sin 2Δθ`,
+//!
+//! which a [`Pll`] drives to zero. The response is taken from the second difference of
+//! three samples, so the slope of the fundamental current cancels, and the average of
+//! the last two samples is the fundamental current for the current loop and the
+//! observer.
+//!
+//! The saliency repeats every half turn, so after the loop has locked a positive and a
+//! negative `Id` are held in turn: the one along the magnet saturates the iron and
+//! gives the larger d axis response, if it is the negative one the estimate is turned
+//! by half a turn. Above a speed band the angle and velocity are blended into those of
+//! a back EMF [`Estimator`] and the injection stops, below it the loop is restarted
+//! from the observer's estimate.
+
+use crate::estimator::Estimator;
+use crate::pll::{Pll, COUNTS_PER_RADIAN};
+use crate::transform::{inverse_park, AlphaBeta, Dq};
+use crate::trig::sin_cos;
+
+/// Q16 weight of the observer once blended in.
+const ONE: i32 = 1 << 16;
+
+/// Motor and tuning of an [`Hfi`], the per unit bases are the bus voltage and the full
+/// scale current of the current sense, velocities electrical angle counts per second.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HfiConfig {
+    /// henry, the smaller `Ld` is, the larger the saliency signal
+    pub d_inductance: f32,
+    pub q_inductance: f32,
+    pub bus_volts: f32,
+    pub full_scale_amps: f32,
+    /// amplitude of the injected voltage, Q15 of the bus voltage
+    pub injection: i16,
+    pub pll_bandwidth_hz: f32,
+    /// seconds to lock before the polarity is detected
+    pub lock_time: f32,
+    /// `Id` held either way to detect the polarity, Q15 of the full scale current
+    pub polarity_current: i16,
+    /// seconds each polarity current is held
+    pub polarity_time: f32,
+    /// speed band over which the observer is blended in
+    pub blend_start: i32,
+    pub blend_end: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    /// injecting without current until the loop has locked
+    Lock,
+    /// holding the positive polarity current
+    Positive,
+    /// holding the negative polarity current
+    Negative,
+    /// polarity known, bringing `Id` back to zero
+    Release,
+    Running,
+}
+
+/// Injection, demodulation and blend, see the module documentation.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Hfi<E> {
+    observer: E,
+    pll: Pll,
+    amplitude: i16,
+    /// Q16 counts per Q15 of the demodulated q response
+    gain: i64,
+    polarity_current: i16,
+    lock_periods: u32,
+    polarity_periods: u32,
+    blend_start: i32,
+    blend_end: i32,
+    state: State,
+    /// periods in the current state
+    count: u32,
+    /// d axis responses of the positive and negative current
+    response: [i64; 2],
+    /// the latest two samples
+    samples: [AlphaBeta<i16>; 2],
+    fundamental: AlphaBeta<i16>,
+    /// demodulated q response of the previous period
+    previous_q: i32,
+    /// sign of the latest injection, 0 while not injecting
+    sign: i16,
+    /// injected voltage to add to the d axis and its stationary vector
+    injection: i16,
+    injected: AlphaBeta<i16>,
+    /// angles of the latest two injections
+    injected_angles: [u16; 2],
+    /// Q16 weight of the observer
+    weight: i32,
+    angle: u16,
+    velocity: i32,
+}
+
+impl<E: Estimator> Hfi<E> {
+    /// Injection run every period of a `sample_hz` current loop, blending into
+    /// `observer`, starting with the polarity detection.
+    pub fn new(config: HfiConfig, observer: E, sample_hz: u32) -> Self {
+        let fs = sample_hz as f64;
+        let periods = |seconds: f32| (seconds as f64 * fs) as u32;
+        // q response per radian of error in Q15, twice from the second difference
+        let volts = config.injection as f64 / 32_768.0 * config.bus_volts as f64;
+        let saliency = 1.0 / config.d_inductance as f64 - 1.0 / config.q_inductance as f64;
+        let response = 2.0 * volts / fs * saliency / config.full_scale_amps as f64 * 32_768.0;
+        Self {
+            observer,
+            pll: Pll::new(config.pll_bandwidth_hz, sample_hz),
+            amplitude: config.injection,
+            gain: (COUNTS_PER_RADIAN as f64 / response * 65_536.0 + 0.5) as i64,
+            polarity_current: config.polarity_current,
+            lock_periods: periods(config.lock_time),
+            polarity_periods: periods(config.polarity_time),
+            blend_start: config.blend_start,
+            blend_end: config.blend_end.max(config.blend_start + 1),
+            state: State::Lock,
+            count: 0,
+            response: [0; 2],
+            samples: [AlphaBeta::default(); 2],
+            fundamental: AlphaBeta::default(),
+            previous_q: 0,
+            sign: 0,
+            injection: 0,
+            injected: AlphaBeta::default(),
+            injected_angles: [0; 2],
+            weight: 0,
+            angle: 0,
+            velocity: 0,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// `Id` setpoint to hold while the polarity is detected, `None` once running, when
+    /// the setpoint is free again.
+    pub fn probe_current(&self) -> Option<i16> {
+        match self.state {
+            State::Positive => Some(self.polarity_current),
+            State::Negative => Some(-self.polarity_current),
+            State::Lock | State::Release => Some(0),
+            State::Running => None,
+        }
+    }
+
+    /// Voltage to add to the d axis command this period, Q15 of the bus voltage.
+    pub fn injection(&self) -> i16 {
+        self.injection
+    }
+
+    /// Latest currents without the injection response, for the current loop.
+    pub fn fundamental(&self) -> AlphaBeta<i16> {
+        self.fundamental
+    }
+
+    /// Q16 weight of the observer in the estimate, 65536 above the blend band.
+    pub fn weight(&self) -> i32 {
+        self.weight
+    }
+
+    pub fn observer(&mut self) -> &mut E {
+        &mut self.observer
+    }
+
+    /// Demodulate the q response of the injection, correct the loop and detect the
+    /// polarity from the d response.
+    fn demodulate(
+        &mut self,
+        currents: AlphaBeta<i16>,
+        previous: AlphaBeta<i16>,
+        older: AlphaBeta<i16>,
+    ) {
+        let angle = self.pll.predict();
+        if self.sign == 0 {
+            return;
+        }
+        let sign = self.sign as i32;
+        let second = |i: i16, j: i16, k: i16| sign * (i as i32 - 2 * j as i32 + k as i32);
+        let alpha = second(currents.alpha, previous.alpha, older.alpha);
+        let beta = second(currents.beta, previous.beta, older.beta);
+        // the response of the last two injections, between their angles
+        let [latest, earlier] = self.injected_angles;
+        let midpoint = earlier.wrapping_add((latest.wrapping_sub(earlier) as i16 / 2) as u16);
+        let (sin, cos) = sin_cos(midpoint);
+        let (sin, cos) = (sin as i32, cos as i32);
+        let d = (alpha * cos + beta * sin) >> 15;
+        let q = (beta * cos - alpha * sin) >> 15;
+        // what is left of the fundamental changes sign with the injection
+        let error = (q + self.previous_q) as i64 >> 1;
+        self.previous_q = q;
+        let offset = midpoint.wrapping_sub(angle) as i16 as i32;
+        let correction = offset + ((error * self.gain) >> 16) as i32;
+        self.pll.correct(correction);
+
+        self.count += 1;
+        let settled = self.count > self.polarity_periods / 2;
+        match self.state {
+            State::Lock if self.count >= self.lock_periods => self.enter(State::Positive),
+            State::Positive | State::Negative => {
+                let index = (self.state == State::Negative) as usize;
+                if settled {
+                    self.response[index] += d as i64;
+                }
+                if self.count < self.polarity_periods {
+                    return;
+                }
+                if self.state == State::Positive {
+                    self.enter(State::Negative);
+                    return;
+                }
+                // the larger response was along the magnet
+                if self.response[1] > self.response[0] {
+                    let angle = self.pll.angle().wrapping_add(32_768);
+                    self.pll.reset(angle, self.pll.velocity());
+                }
+                self.enter(State::Release);
+            }
+            State::Release if settled => self.enter(State::Running),
+            _ => {}
+        }
+    }
+
+    fn enter(&mut self, state: State) {
+        self.state = state;
+        self.count = 0;
+    }
+}
+
+impl<E: Estimator> Estimator for Hfi<E> {
+    /// Call every period with the measured currents and the applied voltage including
+    /// the injection, then add [`Hfi::injection`] to the d axis and regulate the
+    /// [`Hfi::fundamental`] currents at [`Estimator::angle`].
+    fn update(&mut self, currents: AlphaBeta<i16>, voltage: AlphaBeta<i16>) {
+        let [previous, older] = self.samples;
+        self.samples = [currents, previous];
+        let average = |i: i16, j: i16| ((i as i32 + j as i32) >> 1) as i16;
+        self.fundamental = AlphaBeta {
+            alpha: average(currents.alpha, previous.alpha),
+            beta: average(currents.beta, previous.beta),
+        };
+        let voltage = voltage - self.injected;
+        self.observer.update(self.fundamental, voltage);
+
+        if self.weight < ONE {
+            self.demodulate(currents, previous, older);
+        } else {
+            let (angle, velocity) = (self.observer.angle(), self.observer.velocity());
+            self.pll.reset(angle, velocity);
+        }
+
+        // blend on the speed of the loop, which follows the observer once blended in
+        let speed = (self.pll.velocity().abs() - self.blend_start).max(0) as i64;
+        let band = (self.blend_end - self.blend_start) as i64;
+        self.weight = if self.state == State::Running {
+            ((speed << 16) / band).min(ONE as i64) as i32
+        } else {
+            0
+        };
+        let blend =
+            |hfi: i32, observer: i32| hfi + ((observer as i64 * self.weight as i64) >> 16) as i32;
+        let angle = self.pll.angle();
+        let offset = self.observer.angle().wrapping_sub(angle) as i16 as i32;
+        self.angle = blend(angle as i32, offset) as u16;
+        let velocity = self.pll.velocity();
+        self.velocity = blend(velocity, self.observer.velocity() - velocity);
+
+        self.sign = match self.weight {
+            ONE => 0,
+            _ if self.sign > 0 => -1,
+            _ => 1,
+        };
+        self.injection = self.sign * self.amplitude;
+        let (sin, cos) = sin_cos(self.angle);
+        self.injected = inverse_park(
+            Dq {
+                d: self.injection,
+                q: 0,
+            },
+            sin,
+            cos,
+        );
+        self.injected_angles = [self.angle, self.injected_angles[0]];
+    }
+
+    fn angle(&self) -> u16 {
+        self.angle
+    }
+
+    fn velocity(&self) -> i32 {
+        self.velocity
+    }
+
+    /// Start over with the polarity detection.
+    fn reset(&mut self) {
+        self.observer.reset();
+        self.pll.reset(0, 0);
+        self.enter(State::Lock);
+        self.response = [0; 2];
+        self.samples = [AlphaBeta::default(); 2];
+        self.fundamental = AlphaBeta::default();
+        self.previous_q = 0;
+        self.sign = 0;
+        self.injection = 0;
+        self.injected = AlphaBeta::default();
+        self.injected_angles = [0; 2];
+        self.weight = 0;
+        self.angle = 0;
+        self.velocity = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{
+        current_loop, periods, Motor, BUS_VOLTS, D_INDUCTANCE, FULL_SCALE_AMPS, PWM_HZ,
+        Q_INDUCTANCE, RESISTANCE,
+    };
+    use crate::smo::{SlidingModeConfig, SlidingModeObserver};
+    use crate::transform::q15;
+
+    fn hfi() -> Hfi<SlidingModeObserver> {
+        // the extended back EMF of a salient motor is on the q axis
+        let observer = SlidingModeObserver::new(
+            SlidingModeConfig {
+                resistance: RESISTANCE as f32,
+                inductance: Q_INDUCTANCE as f32,
+                bus_volts: BUS_VOLTS as f32,
+                full_scale_amps: FULL_SCALE_AMPS as f32,
+                gain: 0.5,
+                boundary: 0.125,
+                cutoff_ratio: 1.0,
+                min_cutoff_hz: 20.0,
+                velocity_cutoff_hz: 50.0,
+            },
+            PWM_HZ,
+        );
+        let config = HfiConfig {
+            d_inductance: D_INDUCTANCE as f32,
+            q_inductance: Q_INDUCTANCE as f32,
+            bus_volts: BUS_VOLTS as f32,
+            full_scale_amps: FULL_SCALE_AMPS as f32,
+            injection: q15(0.05),
+            pll_bandwidth_hz: 50.0,
+            lock_time: 0.05,
+            polarity_current: q15(0.2),
+            polarity_time: 0.02,
+            blend_start: 20 * 65_536,
+            blend_end: 40 * 65_536,
+        };
+        Hfi::new(config, observer, PWM_HZ)
+    }
+
+    /// Salient motor turned at `hz(t)` under field oriented current control on the
+    /// estimate, with `Iq` once running, returns the worst angle error in counts from
+    /// 20 ms after the polarity was detected, when the step in `Iq` has settled.
+    fn run(
+        hfi: &mut Hfi<SlidingModeObserver>,
+        theta: f64,
+        hz: impl Fn(f64) -> f64,
+        seconds: f64,
+    ) -> i32 {
+        // the d axis saturates along the magnet
+        let mut motor = Motor::salient(theta).saturating(0.2);
+        let mut current_loop = current_loop(D_INDUCTANCE, 500.0);
+        let mut voltage = AlphaBeta::default();
+        let (mut worst, mut running) = (0, 0);
+        for n in 0..periods(seconds) {
+            hfi.update(motor.currents(), voltage);
+            if hfi.state() == State::Running {
+                running += 1;
+            }
+            if running > PWM_HZ / 50 {
+                let error = hfi.angle().wrapping_sub(motor.angle()) as i16 as i32;
+                worst = worst.max(error.abs());
+            }
+            let setpoint = match hfi.probe_current() {
+                Some(d) => Dq { d, q: 0 },
+                None => Dq { d: 0, q: q15(0.2) },
+            };
+            current_loop.set_setpoint(setpoint);
+            current_loop.set_feed_forward(Dq {
+                d: hfi.injection(),
+                q: 0,
+            });
+            voltage = current_loop.update(hfi.fundamental(), hfi.angle());
+            motor.step(voltage, hz(n as f64 / PWM_HZ as f64));
+        }
+        worst
+    }
+
+    #[test]
+    fn finds_the_rotor_at_standstill() {
+        // the second start locks half a turn off and has to turn the estimate around
+        for theta in [1.0, 4.0] {
+            let mut hfi = hfi();
+            let worst = run(&mut hfi, theta, |_| 0.0, 0.3);
+            assert_eq!(hfi.state(), State::Running);
+            // about a degree under the torque current
+            assert!(worst < 200, "{theta}: {worst}");
+            assert_eq!(hfi.weight(), 0);
+        }
+    }
+
+    #[test]
+    fn tracks_a_slow_rotation() {
+        let mut hfi = hfi();
+        let worst = run(&mut hfi, 2.5, |t| if t < 0.15 { 0.0 } else { -5.0 }, 0.6);
+        assert!(worst < 500, "{worst}");
+        assert!(
+            (hfi.velocity() + 5 * 65_536).abs() < 65_536 / 10,
+            "{}",
+            hfi.velocity()
+        );
+    }
+
+    #[test]
+    fn blends_into_the_observer_and_back() {
+        let mut hfi = hfi();
+        // up to 80 Hz and back to standstill
+        let hz = |t: f64| 80.0 * (1.0 - (t - 1.2).abs() / 1.0).max(0.0);
+        let worst = run(&mut hfi, 2.5, hz, 1.8);
+        assert!(worst < 1_000, "{worst}");
+
+        let mut hfi = self::hfi();
+        run(&mut hfi, 2.5, |t| (100.0 * (t - 0.2)).clamp(0.0, 80.0), 1.2);
+        assert_eq!((hfi.weight(), hfi.injection()), (ONE, 0));
+        hfi.reset();
+        assert_eq!((hfi.state(), hfi.probe_current()), (State::Lock, Some(0)));
+    }
+}
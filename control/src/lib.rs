@@ -0,0 +1,29 @@
+//! Target independent motor control building blocks.
+//!
+//! Fixed point types are the default, the STM32F103 has no FPU. The `F32` variants
+//! are meant for the host and chips with an FPU. Unit tests run on the host with
+//! `cargo test -p control --target x86_64-unknown-linux-gnu`.
+#![cfg_attr(not(test), no_std)]
+
+pub mod current;
+pub mod estimator;
+pub mod filter;
+pub mod flux;
+pub mod hfi;
+pub mod math;
+pub mod mtpa;
+pub mod phase_current;
+pub mod pid;
+pub mod pll;
+pub mod position;
+#[cfg(test)]
+mod sim;
+pub mod single_shunt;
+pub mod smo;
+pub mod startup;
+pub mod svpwm;
+pub mod trajectory;
+pub mod transform;
+pub mod trig;
+pub mod velocity;
+pub mod weakening;
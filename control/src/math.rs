@@ -0,0 +1,71 @@
+//! Square roots for `no_std`.
+
+/// Integer square root, rounded down.
+pub fn isqrt(x: u64) -> u32 {
+    // bit by bit, no division
+    let mut x = x;
+    let mut root = 0u64;
+    let mut bit = 1u64 << 62;
+    while bit > x {
+        bit >>= 2;
+    }
+    while bit != 0 {
+        if x >= root + bit {
+            x -= root + bit;
+            root = (root >> 1) + bit;
+        } else {
+            root >>= 1;
+        }
+        bit >>= 2;
+    }
+    root as u32
+}
+
+/// Square root, `core` has none on stable.
+pub fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    // halve the exponent for a first guess within a few percent, then Newton
+    let mut y = f32::from_bits((x.to_bits() >> 1) + 0x1fbd_1df5);
+    for _ in 0..3 {
+        y = 0.5 * (y + x / y);
+    }
+    y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_square_root() {
+        for x in [
+            0u64,
+            1,
+            2,
+            3,
+            4,
+            15,
+            16,
+            17,
+            1 << 30,
+            u32::MAX as u64,
+            u64::MAX,
+        ] {
+            let (root, x) = (isqrt(x) as u128, x as u128);
+            assert!(root * root <= x && (root + 1) * (root + 1) > x, "{x}");
+        }
+    }
+
+    #[test]
+    fn square_root() {
+        for x in [1e-6f32, 0.5, 1.0, 2.0, 1e3, 4.2e12] {
+            assert!(
+                (sqrt(x) / (x as f64).sqrt() as f32 - 1.0).abs() < 1e-6,
+                "{x}"
+            );
+        }
+        assert_eq!(sqrt(0.0), 0.0);
+    }
+}
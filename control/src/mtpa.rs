@@ -0,0 +1,239 @@
+//! Maximum torque per ampere.
+//!
+//! An interior magnet motor has more inductance on the q axis than on the d axis, so a
+//! negative `Id` adds reluctance torque to the magnet torque:
+//!
+//! `T = 3/2 p iq (ψ + (Ld - Lq) id)`
+//!
+//! Each torque has one split between `Id` and `Iq` with the smallest current.
+//! [`Mtpa`] tabulates these once from `Ld`, `Lq` and `ψ` with the closed form of the
+//! optimum for a current magnitude `I`:
+//!
+//! `id = (ψ - sqrt(ψ² + 8 (Lq - Ld)² I²)) / (4 (Lq - Ld))`
+//!
+//! Then it interpolates the table every period. The torque command is the `Iq` that
+//! makes the same torque with `Id` zero, so it stays in the units of
+//! [`crate::current::CurrentLoop::set_setpoint`], and with `Ld` equal to `Lq` the
+//! reference is that `Iq`. The reference then goes through
+//! [`crate::weakening::FieldWeakening::update`], which adds its `Id` to the one from
+//! the table above base speed.
+
+use crate::math::sqrt;
+use crate::transform::Dq;
+
+/// Segments of the table, evenly spaced in torque.
+const SEGMENTS: usize = 32;
+
+/// Motor of a [`Mtpa`].
+///
+/// The inductances are phase values in henry and `flux_linkage` the magnet flux in Vs.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MtpaConfig {
+    pub d_inductance: f32,
+    pub q_inductance: f32,
+    pub flux_linkage: f32,
+    pub full_scale_amps: f32,
+    /// radius of the current circle, Q15 of the full scale current
+    pub current_limit: i16,
+}
+
+/// Torque to current reference, see the module documentation.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Mtpa {
+    /// references at `k / SEGMENTS` of the largest torque
+    table: [Dq<i16>; SEGMENTS + 1],
+    /// torque on the current circle, Q15
+    max_torque: i16,
+}
+
+impl Mtpa {
+    /// Tabulate the optimum of the motor in `config` up to the current limit.
+    pub fn new(config: MtpaConfig) -> Self {
+        let amps = config.full_scale_amps;
+        let flux = config.flux_linkage;
+        let saliency = config.q_inductance - config.d_inductance;
+        // Id of the optimum for an Iq, in amps
+        let d = |iq: f32| {
+            if saliency <= 0.0 {
+                return 0.0;
+            }
+            let a = flux / (2.0 * saliency);
+            a - sqrt(a * a + iq * iq)
+        };
+        // torque as the Iq alone that makes it
+        let torque = |id: f32, iq: f32| iq * (1.0 - saliency * id / flux);
+
+        let radius = config.current_limit as f32 / 32_768.0 * amps;
+        let id = if saliency > 0.0 {
+            (flux - sqrt(flux * flux + 8.0 * saliency * saliency * radius * radius))
+                / (4.0 * saliency)
+        } else {
+            0.0
+        };
+        let iq = sqrt(radius * radius - id * id);
+        let max = torque(id, iq).min(32_767.0 / 32_768.0 * amps);
+
+        let q15 = |x: f32| {
+            let x = x / amps * 32_768.0;
+            (if x < 0.0 { x - 0.5 } else { x + 0.5 }) as i16
+        };
+        let mut table = [Dq { d: 0, q: 0 }; SEGMENTS + 1];
+        for (k, point) in table.iter_mut().enumerate() {
+            let target = max * k as f32 / SEGMENTS as f32;
+            // the torque grows with Iq along the optimum and is never below it
+            let (mut low, mut high) = (0.0f32, target);
+            for _ in 0..24 {
+                let iq = 0.5 * (low + high);
+                if torque(d(iq), iq) < target {
+                    low = iq;
+                } else {
+                    high = iq;
+                }
+            }
+            let iq = 0.5 * (low + high);
+            *point = Dq {
+                d: q15(d(iq)),
+                q: q15(iq),
+            };
+        }
+        Self {
+            table,
+            max_torque: q15(max),
+        }
+    }
+
+    /// `Id` and `Iq` reference with the least current for a `torque`, given as the `Iq`
+    /// making it with `Id` zero, Q15 of the full scale current. Saturates at
+    /// [`Self::max_torque`].
+    pub fn reference(&self, torque: i16) -> Dq<i16> {
+        let max = self.max_torque as i32;
+        if max <= 0 {
+            return Dq { d: 0, q: 0 };
+        }
+        let scaled = (torque as i32).abs().min(max) * SEGMENTS as i32;
+        let index = (scaled / max) as usize;
+        let point = self.table[index];
+        let Some(next) = self.table.get(index + 1) else {
+            return Dq {
+                d: point.d,
+                q: if torque < 0 { -point.q } else { point.q },
+            };
+        };
+        let fraction = ((scaled % max) << 15) / max;
+        let lerp = |a: i16, b: i16| a + ((fraction * (b as i32 - a as i32)) >> 15) as i16;
+        let q = lerp(point.q, next.q);
+        Dq {
+            d: lerp(point.d, next.d),
+            q: if torque < 0 { -q } else { q },
+        }
+    }
+
+    /// Largest torque within the current circle, Q15 as the equivalent `Iq`.
+    pub fn max_torque(&self) -> i16 {
+        self.max_torque
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::q15;
+
+    const D_INDUCTANCE: f64 = 0.000_6;
+    const Q_INDUCTANCE: f64 = 0.001_2;
+    const FLUX: f64 = 0.004;
+    const FULL_SCALE_AMPS: f64 = 10.0;
+
+    fn mtpa(d_inductance: f64) -> Mtpa {
+        Mtpa::new(MtpaConfig {
+            d_inductance: d_inductance as f32,
+            q_inductance: Q_INDUCTANCE as f32,
+            flux_linkage: FLUX as f32,
+            full_scale_amps: FULL_SCALE_AMPS as f32,
+            current_limit: q15(0.6),
+        })
+    }
+
+    fn per_unit(x: i16) -> f64 {
+        x as f64 / 32_768.0
+    }
+
+    /// Torque as the equivalent per unit `Iq`.
+    fn torque(reference: Dq<i16>) -> f64 {
+        let saliency = (Q_INDUCTANCE - D_INDUCTANCE) * FULL_SCALE_AMPS / FLUX;
+        per_unit(reference.q) * (1.0 - saliency * per_unit(reference.d))
+    }
+
+    fn current(reference: Dq<i16>) -> f64 {
+        per_unit(reference.d).hypot(per_unit(reference.q))
+    }
+
+    #[test]
+    fn makes_the_commanded_torque() {
+        let mtpa = mtpa(D_INDUCTANCE);
+        for torque_command in (0..=20).map(|k| 0.04 * k as f64) {
+            let command = q15(torque_command as f32);
+            let reference = mtpa.reference(command);
+            let expected = per_unit(command.min(mtpa.max_torque()));
+            let error = torque(reference) - expected;
+            assert!(
+                error.abs() < 0.002,
+                "{torque_command}: {reference:?} {error}"
+            );
+            assert!(reference.d <= 0);
+        }
+    }
+
+    #[test]
+    fn uses_the_least_current() {
+        let mtpa = mtpa(D_INDUCTANCE);
+        let saliency = (Q_INDUCTANCE - D_INDUCTANCE) * FULL_SCALE_AMPS / FLUX;
+        for torque_command in [0.1, 0.3, 0.5, 0.7] {
+            let reference = mtpa.reference(q15(torque_command as f32));
+            // every split of the same torque along Id
+            let least = (0..=600)
+                .map(|k| {
+                    let id = -0.001 * k as f64;
+                    let iq = torque_command / (1.0 - saliency * id);
+                    id.hypot(iq)
+                })
+                .fold(f64::MAX, f64::min);
+            let used = current(reference);
+            assert!(used < least + 0.002, "{torque_command}: {used} {least}");
+            // the reluctance torque pays off more the more current there is
+            let saving = 1.0 - used / torque_command;
+            assert!(saving > torque_command / 10.0, "{torque_command}: {used}");
+        }
+    }
+
+    #[test]
+    fn saturates_on_the_current_circle() {
+        let mtpa = mtpa(D_INDUCTANCE);
+        assert!((0.74..0.77).contains(&per_unit(mtpa.max_torque())));
+        for command in [i16::MAX, mtpa.max_torque()] {
+            let reference = mtpa.reference(command);
+            assert!((current(reference) - 0.6).abs() < 0.001, "{reference:?}");
+        }
+        let forward = mtpa.reference(q15(0.4));
+        let reverse = mtpa.reference(-q15(0.4));
+        assert_eq!(reverse.d, forward.d);
+        assert_eq!(reverse.q, -forward.q);
+        assert_eq!(mtpa.reference(0), Dq { d: 0, q: 0 });
+    }
+
+    #[test]
+    fn leaves_a_surface_motor_alone() {
+        let mtpa = mtpa(Q_INDUCTANCE);
+        assert_eq!(mtpa.max_torque(), q15(0.6));
+        for command in [0, 1_000, q15(0.3), -q15(0.5)] {
+            let reference = mtpa.reference(command);
+            assert_eq!(reference.d, 0);
+            assert!(
+                (reference.q as i32 - command as i32).abs() <= 1,
+                "{reference:?}"
+            );
+        }
+    }
+}
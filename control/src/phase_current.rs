@@ -0,0 +1,253 @@
+//! Phase current reconstruction from low side shunts.
+//!
+//! Low side shunts only carry the phase current while the low side switch conducts.
+//! With two shunts the third phase follows from `Ia + Ib + Ic = 0`. With three, the
+//! phase with the highest duty has the shortest low side window, so it is dropped
+//! every period and rebuilt from the other two.
+
+use crate::transform::{Abc, Scalar};
+
+/// Phase of a three phase bridge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Phase {
+    A,
+    B,
+    C,
+}
+
+/// Phase currents in milliamps, positive into the motor.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PhaseCurrents {
+    pub a: i32,
+    pub b: i32,
+    pub c: i32,
+}
+
+impl PhaseCurrents {
+    /// Complete a pair of measured phases with the third, since Ia + Ib + Ic = 0.
+    pub fn from_pair(first: (Phase, i32), second: (Phase, i32)) -> Self {
+        let third = -(first.1 + second.1);
+        let mut currents = Self {
+            a: third,
+            b: third,
+            c: third,
+        };
+        currents.set(first.0, first.1);
+        currents.set(second.0, second.1);
+        currents
+    }
+
+    pub fn get(&self, phase: Phase) -> i32 {
+        match phase {
+            Phase::A => self.a,
+            Phase::B => self.b,
+            Phase::C => self.c,
+        }
+    }
+
+    /// Currents as fractions of `full_scale_ma`, the input of the Clarke transform.
+    pub fn per_unit<T: Scalar>(&self, full_scale_ma: i32) -> Abc<T> {
+        Abc {
+            a: T::from_ratio(self.a, full_scale_ma),
+            b: T::from_ratio(self.b, full_scale_ma),
+            c: T::from_ratio(self.c, full_scale_ma),
+        }
+    }
+
+    fn set(&mut self, phase: Phase, current: i32) {
+        match phase {
+            Phase::A => self.a = current,
+            Phase::B => self.b = current,
+            Phase::C => self.c = current,
+        }
+    }
+}
+
+/// Space vector sector (1 to 6) of a set of applied duties in phase order a, b, c.
+///
+/// Sector 1 spans 0 to 60 electrical degrees, where a >= b >= c.
+pub fn sector(duties: [u16; 3]) -> u8 {
+    let [a, b, c] = duties;
+    match (a >= b, b >= c, a >= c) {
+        (true, true, _) => 1,
+        (false, _, true) => 2,
+        (false, true, false) => 3,
+        (false, false, _) => 4,
+        (true, false, false) => 5,
+        (true, false, true) => 6,
+    }
+}
+
+/// Phase with the highest duty in a sector, its low side conducts the shortest time.
+pub fn highest_phase(sector: u8) -> Phase {
+    match sector {
+        1 | 6 => Phase::A,
+        2 | 3 => Phase::B,
+        _ => Phase::C,
+    }
+}
+
+/// Injected ranks carrying the low side shunts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Shunts {
+    /// Two phases are measured and the third is derived.
+    Two {
+        first: (Phase, usize),
+        second: (Phase, usize),
+    },
+    /// All three phases are measured, ranks of a, b and c.
+    Three { a: usize, b: usize, c: usize },
+}
+
+/// Turns injected results into three phase currents.
+pub struct Reconstruction {
+    shunts: Shunts,
+}
+
+impl Reconstruction {
+    pub fn new(shunts: Shunts) -> Self {
+        Self { shunts }
+    }
+
+    /// Two shunt layout of the FOC8313 board, phase B and C at the given ranks.
+    pub fn two_shunt(b: usize, c: usize) -> Self {
+        Self::new(Shunts::Two {
+            first: (Phase::B, b),
+            second: (Phase::C, c),
+        })
+    }
+
+    pub fn three_shunt(a: usize, b: usize, c: usize) -> Self {
+        Self::new(Shunts::Three { a, b, c })
+    }
+
+    /// Phase currents from injected results in milliamps and the duties applied while
+    /// they were sampled.
+    pub fn reconstruct(&self, milliamps: &[i32], duties: [u16; 3]) -> PhaseCurrents {
+        let (first, second) = match self.shunts {
+            Shunts::Two { first, second } => (first, second),
+            Shunts::Three { a, b, c } => match highest_phase(sector(duties)) {
+                Phase::A => ((Phase::B, b), (Phase::C, c)),
+                Phase::B => ((Phase::A, a), (Phase::C, c)),
+                Phase::C => ((Phase::A, a), (Phase::B, b)),
+            },
+        };
+        PhaseCurrents::from_pair(
+            (first.0, milliamps[first.1]),
+            (second.0, milliamps[second.1]),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svpwm::duties;
+    use crate::transform::AlphaBeta;
+
+    const MAX_DUTY: u16 = 1_000;
+    const PHASES: [Phase; 3] = [Phase::A, Phase::B, Phase::C];
+
+    /// Duties of a vector at `degrees` electrical.
+    fn duties_at(degrees: f32) -> [u16; 3] {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        duties(
+            AlphaBeta {
+                alpha: 0.5 * cos,
+                beta: 0.5 * sin,
+            },
+            MAX_DUTY,
+        )
+    }
+
+    #[test]
+    fn sectors_follow_the_vector() {
+        for sector in 1..=6u8 {
+            for offset in [5.0, 30.0, 55.0] {
+                let duties = duties_at(60.0 * (sector - 1) as f32 + offset);
+                assert_eq!(super::sector(duties), sector, "{duties:?}");
+                let highest = (0..3).max_by_key(|phase| duties[*phase]).unwrap();
+                assert_eq!(highest_phase(sector), PHASES[highest], "{duties:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn sector_ties_at_zero_modulation() {
+        assert_eq!(sector([500; 3]), 1);
+    }
+
+    #[test]
+    fn pair_is_completed_with_the_third() {
+        let currents = PhaseCurrents::from_pair((Phase::C, 700), (Phase::A, -200));
+        assert_eq!(
+            currents,
+            PhaseCurrents {
+                a: -200,
+                b: -500,
+                c: 700
+            }
+        );
+        assert_eq!(currents.get(Phase::B), -500);
+    }
+
+    #[test]
+    fn two_shunts_derive_phase_a() {
+        let reconstruction = Reconstruction::two_shunt(1, 0);
+        // duties do not matter with two shunts
+        for degrees in [0.0, 100.0, 200.0, 300.0] {
+            let currents = reconstruction.reconstruct(&[-300, 1_200], duties_at(degrees));
+            assert_eq!(
+                currents,
+                PhaseCurrents {
+                    a: -900,
+                    b: 1_200,
+                    c: -300
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn three_shunts_rebuild_the_highest_duty() {
+        let reconstruction = Reconstruction::three_shunt(2, 0, 1);
+        let actual = PhaseCurrents {
+            a: 1_000,
+            b: -400,
+            c: -600,
+        };
+        // the highest duty phase reads garbage, its low side window is too short
+        let garbage = 30_000;
+        for sector in 1..=6u8 {
+            let duties = duties_at(60.0 * (sector - 1) as f32 + 30.0);
+            let highest = highest_phase(sector);
+            let reading = |phase| {
+                if phase == highest {
+                    garbage
+                } else {
+                    actual.get(phase)
+                }
+            };
+            let milliamps = [reading(Phase::B), reading(Phase::C), reading(Phase::A)];
+            assert_eq!(
+                reconstruction.reconstruct(&milliamps, duties),
+                actual,
+                "sector {sector}"
+            );
+        }
+    }
+
+    #[test]
+    fn per_unit_of_full_scale() {
+        let currents = PhaseCurrents {
+            a: 5_000,
+            b: -2_500,
+            c: -2_500,
+        };
+        let abc = currents.per_unit::<f32>(10_000);
+        assert_eq!((abc.a, abc.b, abc.c), (0.5, -0.25, -0.25));
+    }
+}
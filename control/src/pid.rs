@@ -0,0 +1,535 @@
+//! PI(D) controller for the current, velocity and position loops.
+//!
+//! Both controllers run the same parallel form:
+//!
+//! `output = clamp(kp * e + ki * ∫e dt - kd * d(measurement)/dt + feed_forward)`
+//!
+//! The derivative acts on the measurement, so setpoint steps do not kick the output;
+//! filter noisy measurements first, e.g. with [`crate::filter::LowPass`]. The integral
+//! is kept as its contribution to the output, so changing `ki` does not move the output
+//! and the anti-windup and tracking below act on it directly.
+//!
+//! In manual mode, or with [`Pid::track`], the integral follows an externally applied
+//! output so that switching back to automatic is bumpless.
+
+/// Fractional bits of the fixed point gains and integral.
+const SHIFT: u32 = 24;
+
+/// Gains of the parallel form, `ki` in 1/s and `kd` in s.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Gains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+}
+
+impl Gains {
+    pub const fn pi(kp: f32, ki: f32) -> Self {
+        Self { kp, ki, kd: 0.0 }
+    }
+}
+
+/// How the integral is kept from winding up while the output saturates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AntiWindup {
+    /// Integrate only up to the output limits, stop while the error pushes further out.
+    Clamping,
+    /// Bleed the saturation excess back into the integral with a tracking gain in 1/s,
+    /// `ki` is a common choice.
+    BackCalculation(f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Mode {
+    /// The controller computes the output.
+    Automatic,
+    /// The output is set with [`Pid::set_output`], the integral tracks it.
+    Manual,
+}
+
+/// Gains interpolated over an operating point, e.g. speed or bus voltage.
+///
+/// `points` must be sorted by operating point, outside the range the end gains hold.
+#[derive(Clone, Copy, Debug)]
+pub struct GainSchedule<const N: usize> {
+    pub points: [(f32, Gains); N],
+}
+
+impl<const N: usize> GainSchedule<N> {
+    pub const fn new(points: [(f32, Gains); N]) -> Self {
+        Self { points }
+    }
+
+    pub fn gains(&self, x: f32) -> Gains {
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let mut gains = self.points[0].1;
+        for pair in self.points.windows(2) {
+            let ((x0, g0), (x1, g1)) = (pair[0], pair[1]);
+            if x >= x1 {
+                gains = g1;
+            } else if x > x0 {
+                let t = (x - x0) / (x1 - x0);
+                gains = Gains {
+                    kp: lerp(g0.kp, g1.kp, t),
+                    ki: lerp(g0.ki, g1.ki, t),
+                    kd: lerp(g0.kd, g1.kd, t),
+                };
+                break;
+            }
+        }
+        gains
+    }
+}
+
+/// PI(D) controller in `f32`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PidF32 {
+    sample_hz: f32,
+    kp: f32,
+    /// ki / sample_hz
+    ki: f32,
+    /// kd * sample_hz
+    kd: f32,
+    /// tracking gain / sample_hz, 0 for clamping
+    kt: f32,
+    min: f32,
+    max: f32,
+    mode: Mode,
+    integral: f32,
+    measurement: f32,
+    error: f32,
+    output: f32,
+}
+
+impl PidF32 {
+    /// Controller run at `sample_hz`, unlimited and with clamping anti-windup.
+    pub fn new(gains: Gains, sample_hz: f32) -> Self {
+        let mut pid = Self {
+            sample_hz,
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.0,
+            kt: 0.0,
+            min: f32::MIN,
+            max: f32::MAX,
+            mode: Mode::Automatic,
+            integral: 0.0,
+            measurement: 0.0,
+            error: 0.0,
+            output: 0.0,
+        };
+        pid.set_gains(gains);
+        pid
+    }
+
+    /// Change the gains without a step in the output.
+    pub fn set_gains(&mut self, gains: Gains) {
+        // move the proportional change into the integral
+        self.integral += (self.kp - gains.kp) * self.error;
+        self.kp = gains.kp;
+        self.ki = gains.ki / self.sample_hz;
+        self.kd = gains.kd * self.sample_hz;
+    }
+
+    pub fn set_limits(&mut self, min: f32, max: f32) {
+        self.min = min;
+        self.max = max;
+        self.integral = self.integral.clamp(min, max);
+    }
+
+    pub fn set_anti_windup(&mut self, anti_windup: AntiWindup) {
+        self.kt = match anti_windup {
+            AntiWindup::Clamping => 0.0,
+            AntiWindup::BackCalculation(kt) => kt / self.sample_hz,
+        };
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Hold `output` in manual mode until [`PidF32::set_automatic`].
+    pub fn set_output(&mut self, output: f32) {
+        self.mode = Mode::Manual;
+        self.output = output.clamp(self.min, self.max);
+    }
+
+    /// Resume control from the current output.
+    pub fn set_automatic(&mut self) {
+        self.mode = Mode::Automatic;
+    }
+
+    /// Run one sample period and return the output.
+    pub fn update(&mut self, setpoint: f32, measurement: f32, feed_forward: f32) -> f32 {
+        let error = setpoint - measurement;
+        let p = self.kp * error;
+        let d = -self.kd * (measurement - self.measurement);
+        self.measurement = measurement;
+        self.error = error;
+
+        if self.mode == Mode::Manual {
+            self.track_output(p + d + feed_forward);
+            return self.output;
+        }
+
+        let rest = p + d + feed_forward;
+        let integral = self.integral + self.ki * error;
+        let unlimited = rest + integral;
+        let output = unlimited.clamp(self.min, self.max);
+        self.integral = if self.kt != 0.0 {
+            integral + self.kt * (output - unlimited)
+        } else {
+            // integrate up to the headroom left by the other terms, never further out
+            // than before
+            integral
+                .min((self.max - rest).max(self.integral))
+                .max((self.min - rest).min(self.integral))
+        };
+        self.integral = self.integral.clamp(self.min, self.max);
+        self.output = output;
+        output
+    }
+
+    /// Follow an output applied by someone else, e.g. an open-loop ramp, so control
+    /// can take over without a step. Call once per period with the latest samples.
+    pub fn track(&mut self, output: f32, setpoint: f32, measurement: f32, feed_forward: f32) {
+        let error = setpoint - measurement;
+        let d = -self.kd * (measurement - self.measurement);
+        self.measurement = measurement;
+        self.error = error;
+        self.output = output.clamp(self.min, self.max);
+        self.track_output(self.kp * error + d + feed_forward);
+    }
+
+    fn track_output(&mut self, rest: f32) {
+        self.integral = (self.output - rest).clamp(self.min, self.max);
+    }
+
+    pub fn output(&self) -> f32 {
+        self.output
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    /// Clear the state, e.g. after a fault, `measurement` avoids a derivative kick.
+    pub fn reset(&mut self, measurement: f32) {
+        self.integral = 0.0;
+        self.error = 0.0;
+        self.output = 0.0;
+        self.measurement = measurement;
+    }
+}
+
+/// PI(D) controller in fixed point.
+///
+/// Takes and returns `i32` in the caller's units, e.g. milliamps in and a Q15 voltage
+/// out. Gains are kept with 24 fractional bits and must stay below 128 in the units of
+/// the loop; keep errors and outputs within ±2^30 so the 64 bit sums do not overflow.
+/// [`Pid::set_gains`] converts from `f32`, which is done in software on the F103, so
+/// schedule gains at the rate of an outer loop rather than every PWM period.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Pid {
+    sample_hz: f32,
+    kp: i64,
+    ki: i64,
+    kd: i64,
+    kt: i64,
+    min: i32,
+    max: i32,
+    mode: Mode,
+    /// Q24
+    integral: i64,
+    measurement: i32,
+    error: i32,
+    output: i32,
+}
+
+fn fixed(x: f32) -> i64 {
+    let x = x as f64 * (1u64 << SHIFT) as f64;
+    (if x < 0.0 { x - 0.5 } else { x + 0.5 }) as i64
+}
+
+#[inline(always)]
+fn round(x: i64) -> i64 {
+    (x + (1 << (SHIFT - 1))) >> SHIFT
+}
+
+impl Pid {
+    /// Controller run at `sample_hz`, unlimited and with clamping anti-windup.
+    pub fn new(gains: Gains, sample_hz: f32) -> Self {
+        let mut pid = Self {
+            sample_hz,
+            kp: 0,
+            ki: 0,
+            kd: 0,
+            kt: 0,
+            min: i32::MIN,
+            max: i32::MAX,
+            mode: Mode::Automatic,
+            integral: 0,
+            measurement: 0,
+            error: 0,
+            output: 0,
+        };
+        pid.set_gains(gains);
+        pid
+    }
+
+    /// Change the gains without a step in the output.
+    pub fn set_gains(&mut self, gains: Gains) {
+        let kp = fixed(gains.kp);
+        self.integral += (self.kp - kp) * self.error as i64;
+        self.kp = kp;
+        self.ki = fixed(gains.ki / self.sample_hz);
+        self.kd = fixed(gains.kd * self.sample_hz);
+    }
+
+    pub fn set_limits(&mut self, min: i32, max: i32) {
+        self.min = min;
+        self.max = max;
+        self.integral = self.clamp_integral(self.integral);
+    }
+
+    pub fn set_anti_windup(&mut self, anti_windup: AntiWindup) {
+        self.kt = match anti_windup {
+            AntiWindup::Clamping => 0,
+            AntiWindup::BackCalculation(kt) => fixed(kt / self.sample_hz),
+        };
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Hold `output` in manual mode until [`Pid::set_automatic`].
+    pub fn set_output(&mut self, output: i32) {
+        self.mode = Mode::Manual;
+        self.output = output.clamp(self.min, self.max);
+    }
+
+    /// Resume control from the current output.
+    pub fn set_automatic(&mut self) {
+        self.mode = Mode::Automatic;
+    }
+
+    /// Run one sample period and return the output.
+    #[inline]
+    pub fn update(&mut self, setpoint: i32, measurement: i32, feed_forward: i32) -> i32 {
+        let error = setpoint.saturating_sub(measurement);
+        let p = self.kp * error as i64;
+        let d = -self.kd * (measurement as i64 - self.measurement as i64);
+        let ff = (feed_forward as i64) << SHIFT;
+        self.measurement = measurement;
+        self.error = error;
+
+        if self.mode == Mode::Manual {
+            self.track_output(p + d + ff);
+            return self.output;
+        }
+
+        let rest = p + d + ff;
+        let integral = self.integral + self.ki * error as i64;
+        let unlimited = round(rest + integral);
+        let output = unlimited.clamp(self.min as i64, self.max as i64);
+        self.integral = if self.kt != 0 {
+            integral + self.kt * (output - unlimited)
+        } else {
+            integral
+                .min((((self.max as i64) << SHIFT) - rest).max(self.integral))
+                .max((((self.min as i64) << SHIFT) - rest).min(self.integral))
+        };
+        self.integral = self.clamp_integral(self.integral);
+        self.output = output as i32;
+        self.output
+    }
+
+    /// Follow an output applied by someone else, e.g. an open-loop ramp, so control
+    /// can take over without a step. Call once per period with the latest samples.
+    pub fn track(&mut self, output: i32, setpoint: i32, measurement: i32, feed_forward: i32) {
+        let error = setpoint.saturating_sub(measurement);
+        let d = -self.kd * (measurement as i64 - self.measurement as i64);
+        self.measurement = measurement;
+        self.error = error;
+        self.output = output.clamp(self.min, self.max);
+        self.track_output(self.kp * error as i64 + d + ((feed_forward as i64) << SHIFT));
+    }
+
+    fn track_output(&mut self, rest: i64) {
+        self.integral = self.clamp_integral(((self.output as i64) << SHIFT) - rest);
+    }
+
+    fn clamp_integral(&self, integral: i64) -> i64 {
+        integral.clamp((self.min as i64) << SHIFT, (self.max as i64) << SHIFT)
+    }
+
+    pub fn output(&self) -> i32 {
+        self.output
+    }
+
+    /// Integral contribution to the output.
+    pub fn integral(&self) -> i32 {
+        round(self.integral) as i32
+    }
+
+    /// Clear the state, e.g. after a fault, `measurement` avoids a derivative kick.
+    pub fn reset(&mut self, measurement: i32) {
+        self.integral = 0;
+        self.error = 0;
+        self.output = 0;
+        self.measurement = measurement;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FS: f32 = 10_000.0;
+
+    /// First order plant, e.g. a winding: `dx/dt = (u - x) / tau`.
+    struct Plant {
+        x: f64,
+        tau: f64,
+    }
+
+    impl Plant {
+        fn step(&mut self, u: f64) -> f64 {
+            self.x += (u - self.x) / (self.tau * FS as f64);
+            self.x
+        }
+    }
+
+    #[test]
+    fn pi_reaches_setpoint() {
+        let gains = Gains::pi(2.0, 400.0);
+        let mut float = PidF32::new(gains, FS);
+        let mut fixed = Pid::new(gains, FS);
+        let mut plant_f = Plant { x: 0.0, tau: 0.005 };
+        let mut plant_i = Plant { x: 0.0, tau: 0.005 };
+        for _ in 0..2_000 {
+            let u = float.update(1_000.0, plant_f.x as f32, 0.0);
+            plant_f.step(u as f64);
+            let u = fixed.update(1_000, plant_i.x.round() as i32, 0);
+            plant_i.step(u as f64);
+        }
+        assert!((plant_f.x - 1_000.0).abs() < 1.0);
+        assert!((plant_i.x - 1_000.0).abs() < 2.0);
+        // at rest the integral carries the whole output
+        assert!((float.integral() - float.output()).abs() < 2.0);
+    }
+
+    #[test]
+    fn fixed_tracks_float() {
+        let gains = Gains {
+            kp: 0.5,
+            ki: 150.0,
+            kd: 0.0001,
+        };
+        let mut float = PidF32::new(gains, FS);
+        let mut fixed = Pid::new(gains, FS);
+        float.set_limits(-5_000.0, 5_000.0);
+        fixed.set_limits(-5_000, 5_000);
+        for n in 0..1_000 {
+            let measurement = (n * 7 % 300) - 150;
+            let setpoint = if n < 500 { 2_000 } else { -800 };
+            let a = float.update(setpoint as f32, measurement as f32, 100.0);
+            let b = fixed.update(setpoint, measurement, 100);
+            assert!((a - b as f32).abs() <= 1.0, "{n}: {a} {b}");
+        }
+    }
+
+    #[test]
+    fn feed_forward_adds_to_output() {
+        let mut pid = Pid::new(Gains::pi(1.0, 0.0), FS);
+        assert_eq!(pid.update(10, 0, 0), 10);
+        assert_eq!(pid.update(10, 0, 250), 260);
+        let mut pid = PidF32::new(Gains::pi(1.0, 0.0), FS);
+        assert_eq!(pid.update(10.0, 0.0, -5.0), 5.0);
+    }
+
+    /// Periods the output stays saturated after the setpoint drops back into reach.
+    fn recovery(anti_windup: AntiWindup) -> usize {
+        let mut pid = PidF32::new(Gains::pi(1.0, 200.0), FS);
+        pid.set_limits(-100.0, 100.0);
+        pid.set_anti_windup(anti_windup);
+        let mut plant = Plant { x: 0.0, tau: 0.01 };
+        // unreachable setpoint
+        for _ in 0..5_000 {
+            let u = pid.update(500.0, plant.x as f32, 0.0);
+            plant.step(u as f64);
+        }
+        assert_eq!(pid.output(), 100.0);
+        assert!(pid.integral() <= 100.0);
+        (0..5_000)
+            .take_while(|_| {
+                let u = pid.update(50.0, plant.x as f32, 0.0);
+                plant.step(u as f64);
+                u >= 100.0
+            })
+            .count()
+    }
+
+    #[test]
+    fn anti_windup_recovers_quickly() {
+        assert!(recovery(AntiWindup::Clamping) < 10);
+        assert!(recovery(AntiWindup::BackCalculation(200.0)) < 10);
+    }
+
+    #[test]
+    fn clamping_holds_integral_in_saturation() {
+        let mut pid = Pid::new(Gains::pi(0.0, 1_000.0), FS);
+        pid.set_limits(-1_000, 1_000);
+        for _ in 0..100_000 {
+            pid.update(100_000, 0, 0);
+        }
+        assert_eq!(pid.output(), 1_000);
+        assert_eq!(pid.integral(), 1_000);
+        // a reversed error unwinds immediately
+        assert!(pid.update(-100_000, 0, 0) < 1_000);
+    }
+
+    #[test]
+    fn manual_to_automatic_is_bumpless() {
+        let mut pid = Pid::new(Gains::pi(3.0, 500.0), FS);
+        pid.set_output(1_234);
+        for _ in 0..10 {
+            assert_eq!(pid.update(500, 200, 20), 1_234);
+        }
+        pid.set_automatic();
+        let output = pid.update(500, 200, 20);
+        // only the integral of one period is added
+        assert!((output - 1_234).abs() <= 16, "{output}");
+
+        let mut pid = PidF32::new(Gains::pi(3.0, 500.0), FS);
+        pid.track(-40.0, 10.0, 12.0, 0.0);
+        assert!((pid.update(10.0, 12.0, 0.0) + 40.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn gain_change_is_bumpless() {
+        let mut pid = Pid::new(Gains::pi(1.0, 100.0), FS);
+        for _ in 0..100 {
+            pid.update(300, 100, 0);
+        }
+        let before = pid.output();
+        pid.set_gains(Gains::pi(4.0, 100.0));
+        let after = pid.update(300, 100, 0);
+        assert!((after - before).abs() <= 3, "{before} {after}");
+    }
+
+    #[test]
+    fn schedule_interpolates() {
+        let schedule =
+            GainSchedule::new([(0.0, Gains::pi(1.0, 10.0)), (100.0, Gains::pi(3.0, 30.0))]);
+        assert_eq!(schedule.gains(-5.0), Gains::pi(1.0, 10.0));
+        assert_eq!(schedule.gains(50.0), Gains::pi(2.0, 20.0));
+        assert_eq!(schedule.gains(500.0), Gains::pi(3.0, 30.0));
+    }
+}
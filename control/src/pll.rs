@@ -0,0 +1,125 @@
+//! Phase locked loop tracking a wrapping angle.
+//!
+//! A PI controller on the angle error drives the velocity, which integrates to the
+//! angle, so a constant velocity is followed without error. The gains place both poles
+//! at the bandwidth, critically damped. Each sample [`Pll::predict`] advances the angle
+//! and [`Pll::correct`] takes the error of the prediction from a phase detector, e.g.
+//! the cross product of a flux vector with the unit vector at the predicted angle, or
+//! [`Pll::track`] does both for a measured angle.
+//!
+//! Angles are `u16` counts as in [`crate::trig`], the state keeps 16 more fractional
+//! bits so slow velocities still advance it.
+
+/// Counts of the angle in a radian.
+pub const COUNTS_PER_RADIAN: f32 = 10_430.378;
+
+/// Type 2 PLL, see the module documentation.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Pll {
+    sample_hz: u32,
+    /// Q16 and Q32 counts per period per count of error
+    kp: i64,
+    ki: i64,
+    /// Q16 angle
+    phase: u32,
+    /// Q32 counts per period
+    step: i64,
+}
+
+impl Pll {
+    /// Loop of `bandwidth_hz` run at `sample_hz`.
+    pub fn new(bandwidth_hz: f32, sample_hz: u32) -> Self {
+        let w = core::f64::consts::TAU * bandwidth_hz as f64 / sample_hz as f64;
+        Self {
+            sample_hz,
+            kp: (2.0 * w * 65_536.0) as i64,
+            ki: (w * w * 4_294_967_296.0) as i64,
+            phase: 0,
+            step: 0,
+        }
+    }
+
+    /// Advance to the next sample, returns the predicted angle.
+    #[inline]
+    pub fn predict(&mut self) -> u16 {
+        self.phase = self.phase.wrapping_add((self.step >> 16) as u32);
+        self.angle()
+    }
+
+    /// Correct the prediction by its error in counts, the tracked angle less the
+    /// predicted one.
+    #[inline]
+    pub fn correct(&mut self, error: i32) {
+        let error = error as i64;
+        self.step += self.ki * error;
+        self.phase = self.phase.wrapping_add((self.kp * error) as u32);
+    }
+
+    /// Advance one sample towards a measured angle.
+    #[inline]
+    pub fn track(&mut self, angle: u16) {
+        let predicted = self.predict();
+        self.correct(angle.wrapping_sub(predicted) as i16 as i32);
+    }
+
+    pub fn angle(&self) -> u16 {
+        (self.phase >> 16) as u16
+    }
+
+    /// Velocity in counts/s.
+    pub fn velocity(&self) -> i32 {
+        (((self.step >> 16) * self.sample_hz as i64 + (1 << 15)) >> 16) as i32
+    }
+
+    /// Start over at `angle` and `velocity`.
+    pub fn reset(&mut self, angle: u16, velocity: i32) {
+        self.phase = (angle as u32) << 16;
+        self.step = ((velocity as i64) << 32) / self.sample_hz as i64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FS: u32 = 16_000;
+
+    #[test]
+    fn locks_onto_a_constant_velocity() {
+        let mut pll = Pll::new(50.0, FS);
+        let velocity = 123_456;
+        let mut angle = 20_000.0f64;
+        for _ in 0..FS / 2 {
+            angle = (angle + velocity as f64 / FS as f64).rem_euclid(65_536.0);
+            pll.track(angle as u16);
+        }
+        let error = (angle as u16).wrapping_sub(pll.angle()) as i16;
+        assert!(error.abs() <= 1, "{error}");
+        assert!((pll.velocity() - velocity).abs() <= 2, "{}", pll.velocity());
+    }
+
+    #[test]
+    fn critically_damped_step() {
+        let mut pll = Pll::new(50.0, FS);
+        let target = 10_000u16;
+        let mut overshoot = 0i32;
+        for _ in 0..FS / 10 {
+            pll.track(target);
+            overshoot = overshoot.max(pll.angle() as i16 as i32 - target as i32);
+        }
+        // the zero of the PI adds some 14% to the overshoot of two real poles
+        assert!(overshoot < 1_500, "{overshoot}");
+        assert_eq!(pll.angle(), target);
+        assert_eq!(pll.velocity(), 0);
+    }
+
+    #[test]
+    fn restarts_at_a_velocity() {
+        let mut pll = Pll::new(50.0, FS);
+        pll.reset(u16::MAX, -65_536);
+        assert_eq!(pll.velocity(), -65_536);
+        // 4.096 counts back
+        assert_eq!(pll.predict(), u16::MAX - 5);
+    }
+}
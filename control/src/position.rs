@@ -0,0 +1,191 @@
+//! Position loop cascaded on the velocity loop.
+//!
+//! A proportional controller on the position error adds to the velocity of the
+//! [`Setpoint`], so the velocity loop does the work of following a trajectory and the
+//! position loop only corrects the error. Setpoints usually come from a
+//! [`crate::trajectory::Trajectory`] run in a task; they can arrive at any rate, but
+//! at the rate of the loop the reference steps carry the acceleration into the
+//! inertia feed-forward of the [`VelocityLoop`].
+//!
+//! Positions are `i32` angle counts and wrap, see [`crate::trajectory`]. Everything here
+//! is fixed point, cheap enough for the control interrupt.
+
+use crate::trajectory::Setpoint;
+use crate::velocity::VelocityLoop;
+
+/// Fractional bits of the position gain.
+const SHIFT: u32 = 16;
+
+/// Position P controller producing an `Iq` command through a velocity loop.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PositionLoop {
+    /// 1/s, Q16
+    kp: i64,
+    velocity: VelocityLoop,
+    max_velocity: i32,
+    setpoint: Setpoint,
+    error: i32,
+}
+
+impl PositionLoop {
+    /// Loop with a gain of `kp` in 1/s, run at the rate of `velocity`, whose
+    /// acceleration limit should be off since the trajectory already limits it.
+    pub fn new(kp: f32, velocity: VelocityLoop) -> Self {
+        let mut position_loop = Self {
+            kp: 0,
+            velocity,
+            max_velocity: i32::MAX,
+            setpoint: Setpoint::default(),
+            error: 0,
+        };
+        position_loop.set_gain(kp);
+        position_loop
+    }
+
+    pub fn set_gain(&mut self, kp: f32) {
+        self.kp = (kp * (1 << SHIFT) as f32) as i64;
+    }
+
+    /// Largest velocity command in either direction, counts/s.
+    pub fn set_velocity_limit(&mut self, velocity: u32) {
+        self.max_velocity = velocity.min(i32::MAX as u32) as i32;
+    }
+
+    pub fn set_setpoint(&mut self, setpoint: Setpoint) {
+        self.setpoint = setpoint;
+    }
+
+    pub fn setpoint(&self) -> Setpoint {
+        self.setpoint
+    }
+
+    /// The inner loop, for gains and limits.
+    pub fn velocity_loop(&mut self) -> &mut VelocityLoop {
+        &mut self.velocity
+    }
+
+    /// Call every PWM period with the measured position and velocity, returns a new
+    /// `Iq` command on the periods the loop runs.
+    #[inline]
+    pub fn update(&mut self, position: i32, velocity: i32) -> Option<i16> {
+        self.velocity.tick().then(|| self.run(position, velocity))
+    }
+
+    /// Run the loop now, regardless of the decimation.
+    pub fn run(&mut self, position: i32, velocity: i32) -> i16 {
+        self.error = self.setpoint.position.wrapping_sub(position);
+        let correction = (self.kp * self.error as i64) >> SHIFT;
+        let command = (self.setpoint.velocity as i64 + correction)
+            .clamp(-self.max_velocity as i64, self.max_velocity as i64);
+        self.velocity.set_setpoint(command as i32);
+        self.velocity.run(velocity)
+    }
+
+    /// Latest position error, counts.
+    pub fn error(&self) -> i32 {
+        self.error
+    }
+
+    /// Hold `position` at rest, e.g. when the loop is switched on.
+    pub fn reset(&mut self, position: i32) {
+        self.setpoint = Setpoint {
+            position,
+            ..Setpoint::default()
+        };
+        self.error = 0;
+        self.velocity.reset(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pid::Gains;
+    use crate::trajectory::{Limits, Profile, Trajectory};
+
+    const PWM_HZ: u32 = 16_000;
+    const DECIMATION: u16 = 16;
+
+    /// Frictionless rotor, `Iq` straight into torque.
+    struct Rotor {
+        position: f64,
+        velocity: f64,
+        /// counts/s² per Q15 unit of current
+        torque: f64,
+    }
+
+    impl Rotor {
+        fn step(&mut self, iq: i16) {
+            let dt = 1.0 / PWM_HZ as f64;
+            self.velocity += iq as f64 * self.torque * dt;
+            self.position += self.velocity * dt;
+        }
+    }
+
+    fn cascade() -> PositionLoop {
+        let mut velocity = VelocityLoop::new(Gains::pi(0.5, 10.0), PWM_HZ, DECIMATION);
+        velocity.set_torque_limit(i16::MAX / 2);
+        PositionLoop::new(30.0, velocity)
+    }
+
+    #[test]
+    fn follows_an_s_curve() {
+        let limits = Limits {
+            velocity: 327_680.0,
+            acceleration: 1_638_400.0,
+            jerk: 32_768_000.0,
+        };
+        let mut trajectory = Trajectory::new(Profile::SCurve, limits, 1_000.0, 0);
+        let mut position_loop = cascade();
+        position_loop.reset(0);
+        let mut rotor = Rotor {
+            position: 0.0,
+            velocity: 0.0,
+            torque: 400.0,
+        };
+        trajectory.set_target(10 * 65_536);
+        let mut iq = 0;
+        let mut worst = 0;
+        for n in 0..4 * PWM_HZ {
+            // the planner in a task at the loop rate
+            if n % DECIMATION as u32 == 0 {
+                position_loop.set_setpoint(trajectory.update());
+            }
+            if n == 16_000 {
+                // change of plan mid-move
+                trajectory.set_target(2 * 65_536);
+            }
+            let (position, velocity) = (rotor.position as i32, rotor.velocity as i32);
+            if let Some(output) = position_loop.update(position, velocity) {
+                iq = output;
+                worst = worst.max(position_loop.error().abs());
+            }
+            rotor.step(iq);
+        }
+        assert!(trajectory.is_done());
+        // a few degrees while moving, within a count at rest
+        assert!(worst < 1_000, "{worst}");
+        assert!(
+            (rotor.position - 2.0 * 65_536.0).abs() <= 1.0,
+            "{}",
+            rotor.position
+        );
+    }
+
+    #[test]
+    fn velocity_command_is_limited() {
+        let mut position_loop = cascade();
+        position_loop.set_velocity_limit(1_000);
+        position_loop.set_setpoint(Setpoint {
+            position: i32::MIN,
+            ..Setpoint::default()
+        });
+        // the error wraps the short way round
+        position_loop.run(i32::MAX, 0);
+        assert_eq!(position_loop.error(), 1);
+        position_loop.set_setpoint(Setpoint::default());
+        position_loop.run(1 << 20, 0);
+        assert_eq!(position_loop.velocity_loop().setpoint(), -1_000);
+    }
+}
@@ -0,0 +1,112 @@
+//! Motor model for the closed loop tests of the estimators and the field weakening.
+//!
+//! A surface magnet motor, or a salient one with `Lq = 2 Ld`, is integrated in the
+//! rotor frame over ten Euler steps per PWM period, with the currents and voltages
+//! in Q15 of the full scale current and the bus.
+
+use crate::current::{tuned_gains, CurrentLoop};
+use crate::transform::AlphaBeta;
+use core::f64::consts::TAU;
+
+pub const PWM_HZ: u32 = 16_000;
+const SUBSTEPS: u32 = 10;
+pub const RESISTANCE: f64 = 0.5;
+/// Inductance of the non salient motor.
+pub const INDUCTANCE: f64 = 0.001;
+pub const D_INDUCTANCE: f64 = 0.000_6;
+pub const Q_INDUCTANCE: f64 = 0.001_2;
+/// Vs of the magnets
+pub const FLUX: f64 = 0.004;
+pub const BUS_VOLTS: f64 = 24.0;
+pub const FULL_SCALE_AMPS: f64 = 10.0;
+
+/// Current loop tuned for `inductance` at `bandwidth_hz`.
+pub fn current_loop(inductance: f64, bandwidth_hz: f32) -> CurrentLoop {
+    let gains = tuned_gains(
+        RESISTANCE as f32,
+        inductance as f32,
+        bandwidth_hz,
+        BUS_VOLTS as f32,
+        FULL_SCALE_AMPS as f32,
+    );
+    CurrentLoop::new(gains, PWM_HZ as f32)
+}
+
+/// Seconds to PWM periods.
+pub fn periods(seconds: f64) -> u32 {
+    (PWM_HZ as f64 * seconds) as u32
+}
+
+pub struct Motor {
+    d_inductance: f64,
+    q_inductance: f64,
+    /// drop of the d inductance along the magnet at large `Id`
+    saturation: f64,
+    /// electrical angle in radians
+    pub theta: f64,
+    pub id: f64,
+    pub iq: f64,
+}
+
+impl Motor {
+    pub fn non_salient(theta: f64) -> Self {
+        Self {
+            d_inductance: INDUCTANCE,
+            q_inductance: INDUCTANCE,
+            saturation: 0.0,
+            theta,
+            id: 0.0,
+            iq: 0.0,
+        }
+    }
+
+    pub fn salient(theta: f64) -> Self {
+        Self {
+            d_inductance: D_INDUCTANCE,
+            q_inductance: Q_INDUCTANCE,
+            ..Self::non_salient(theta)
+        }
+    }
+
+    /// Let the d inductance fall by up to `fraction` as `Id` saturates the magnet path.
+    pub fn saturating(self, fraction: f64) -> Self {
+        Self {
+            saturation: fraction,
+            ..self
+        }
+    }
+
+    /// Measured stationary currents.
+    pub fn currents(&self) -> AlphaBeta<i16> {
+        let (sin, cos) = self.theta.sin_cos();
+        let amps = |x: f64| (x / FULL_SCALE_AMPS * 32_768.0) as i16;
+        AlphaBeta {
+            alpha: amps(self.id * cos - self.iq * sin),
+            beta: amps(self.id * sin + self.iq * cos),
+        }
+    }
+
+    /// True electrical angle in counts.
+    pub fn angle(&self) -> u16 {
+        (self.theta / TAU * 65_536.0).rem_euclid(65_536.0) as u16
+    }
+
+    /// Apply `voltage` for one PWM period while turning at `hz` electrical.
+    pub fn step(&mut self, voltage: AlphaBeta<i16>, hz: f64) {
+        let v_alpha = voltage.alpha as f64 / 32_768.0 * BUS_VOLTS;
+        let v_beta = voltage.beta as f64 / 32_768.0 * BUS_VOLTS;
+        let omega = TAU * hz;
+        let dt = 1.0 / (PWM_HZ * SUBSTEPS) as f64;
+        for _ in 0..SUBSTEPS {
+            let (sin, cos) = self.theta.sin_cos();
+            let vd = v_alpha * cos + v_beta * sin;
+            let vq = v_beta * cos - v_alpha * sin;
+            let d_inductance = self.d_inductance * (1.0 - self.saturation * (self.id / 2.0).tanh());
+            let d = vd - RESISTANCE * self.id + omega * self.q_inductance * self.iq;
+            let q = vq - RESISTANCE * self.iq - omega * (self.d_inductance * self.id + FLUX);
+            self.id += d / d_inductance * dt;
+            self.iq += q / self.q_inductance * dt;
+            self.theta += omega * dt;
+        }
+    }
+}
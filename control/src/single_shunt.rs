@@ -0,0 +1,189 @@
+//! Edge placement for phase current reconstruction from a single DC link shunt.
+//!
+//! The DC link only carries a phase current during the active vectors. Counting up
+//! through a center aligned period the phases switch off in order of rising duty,
+//! passing both active vectors:
+//!
+//! ```not_rust
+//!            up half                    down half
+//! max  ‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾|___|‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾
+//! mid  ‾‾‾‾‾‾‾‾‾‾‾‾|_______________|‾‾‾‾‾‾‾‾‾‾‾‾‾‾‾
+//! min  ‾‾‾|_______________________________________|‾‾
+//!             [-Imin]  [+Imax]
+//! ```
+//!
+//! Both samples are taken in the up half, at the end of the two-phases-high vector
+//! (-Imin) and at the end of the one-phase-high vector (+Imax). When a vector is too
+//! short to sample, the edges of the up half are spread apart and moved back by the
+//! same amount in the down half, which keeps the average duty of every phase. The
+//! down half is not sampled, so its phase order does not matter.
+//!
+//! Spreading both vectors in one half is the only placement that works down to zero
+//! modulation: sampling one vector in each half needs the max and min duties to be a
+//! full window apart in both halves, which mirrored edges can not provide.
+
+/// Compare values and triggers for one PWM period.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Plan {
+    /// Phase indices (0 = a, 1 = b, 2 = c) ordered by duty, highest first.
+    pub order: [usize; 3],
+    /// Phase compare values (a, b, c) during the up counting half.
+    pub up: [u16; 3],
+    /// Phase compare values (a, b, c) during the down counting half.
+    pub down: [u16; 3],
+    /// Trigger compare values of the -Imin and the +Imax sample, both counting up.
+    pub triggers: [u16; 2],
+}
+
+impl Plan {
+    /// Trigger in the down half after this period, where the compare values of `next`
+    /// are loaded.
+    ///
+    /// It is at or below both the last trigger of this period, so it is not passed
+    /// again counting up, and the first trigger of `next`, so moving the trigger there
+    /// does not cause another match counting down.
+    pub fn reload_trigger(&self, next: &Plan) -> u16 {
+        self.triggers[1].min(next.triggers[0])
+    }
+}
+
+/// Phase indices ordered (max, mid, min) by duty, ties in phase order.
+pub fn order(duties: [u16; 3]) -> [usize; 3] {
+    let mut order = [0, 1, 2];
+    // insertion sort, only moves a phase past a strictly lower duty
+    for i in 1..3 {
+        let mut j = i;
+        while j > 0 && duties[order[j]] > duties[order[j - 1]] {
+            order.swap(j, j - 1);
+            j -= 1;
+        }
+    }
+    order
+}
+
+/// Compare value for the other half that keeps the average at `duty`.
+fn mirror(duty: u16, shifted: u16, top: u16) -> u16 {
+    (2 * duty as i32 - shifted as i32).clamp(0, top as i32) as u16
+}
+
+/// Plan the compare values of one period for the duties (a, b, c).
+///
+/// Outputs are high while the counter is below the compare value. `min_window` is the
+/// shortest sampleable vector in timer ticks, `sample_offset` the ticks between a
+/// trigger and the end of its vector and `top` the highest compare value. `top` must
+/// leave room for two windows.
+pub fn plan(duties: [u16; 3], min_window: u16, sample_offset: u16, top: u16) -> Plan {
+    debug_assert!(2 * min_window <= top && sample_offset < min_window);
+    let order = order(duties);
+    let [max, mid, min] = order;
+
+    let mut up = duties;
+    // keep mid where it is unless the outer edges have no room left
+    up[mid] = duties[mid].clamp(min_window, top - min_window);
+    up[min] = duties[min].min(up[mid] - min_window);
+    up[max] = duties[max].max(up[mid] + min_window);
+
+    let mut down = duties;
+    for phase in order {
+        down[phase] = mirror(duties[phase], up[phase], top);
+    }
+
+    Plan {
+        order,
+        up,
+        down,
+        triggers: [up[mid] - sample_offset, up[max] - sample_offset],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: u16 = 100;
+    const OFFSET: u16 = 20;
+    const TOP: u16 = 999;
+
+    /// Phases that are high while counting up through `tick`.
+    fn high(compare: [u16; 3], tick: u16) -> [bool; 3] {
+        compare.map(|c| tick < c)
+    }
+
+    /// Every tick from settling to the end of sampling sees the same phases high.
+    fn steady(compare: [u16; 3], trigger: u16) -> [bool; 3] {
+        let start = trigger + OFFSET - WINDOW;
+        let state = high(compare, start);
+        for tick in start..=trigger + OFFSET - 1 {
+            assert_eq!(high(compare, tick), state, "{compare:?} at {tick}");
+        }
+        state
+    }
+
+    fn duties() -> impl Iterator<Item = [u16; 3]> {
+        let steps = [
+            0, 1, 50, 99, 100, 101, 450, 499, 500, 501, 550, 900, 998, 999,
+        ];
+        steps
+            .into_iter()
+            .flat_map(move |a| steps.into_iter().map(move |b| (a, b)))
+            .flat_map(move |(a, b)| steps.into_iter().map(move |c| [a, b, c]))
+    }
+
+    #[test]
+    fn every_sample_sees_its_active_vector() {
+        for duties in duties() {
+            let plan = plan(duties, WINDOW, OFFSET, TOP);
+            let [max, mid, min] = plan.order;
+
+            // -Imin: max and mid high, min low
+            let state = steady(plan.up, plan.triggers[0]);
+            assert!(state[max] && state[mid] && !state[min], "{duties:?}");
+            // +Imax: only max high
+            let state = steady(plan.up, plan.triggers[1]);
+            assert!(state[max] && !state[mid] && !state[min], "{duties:?}");
+        }
+    }
+
+    #[test]
+    fn zero_modulation_is_spread_in_the_up_half() {
+        let plan = plan([500; 3], WINDOW, OFFSET, TOP);
+        assert_eq!(plan.order, [0, 1, 2]);
+        assert_eq!(plan.up, [600, 500, 400]);
+        assert_eq!(plan.down, [400, 500, 600]);
+        assert_eq!(plan.triggers, [480, 580]);
+    }
+
+    #[test]
+    fn average_duty_is_kept() {
+        for duties in duties() {
+            let plan = plan(duties, WINDOW, OFFSET, TOP);
+            for phase in 0..3 {
+                let average = (plan.up[phase] as i32 + plan.down[phase] as i32) / 2;
+                // only edges pushed against the rails lose duty
+                if plan.down[phase] != 0 && plan.down[phase] != TOP {
+                    assert_eq!(average, duties[phase] as i32, "{duties:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wide_vectors_are_left_alone() {
+        let duties = [800, 500, 200];
+        let plan = plan(duties, WINDOW, OFFSET, TOP);
+        assert_eq!((plan.up, plan.down), (duties, duties));
+    }
+
+    #[test]
+    fn reload_trigger_does_not_match_twice() {
+        for (this, next) in duties().zip(duties().skip(7)) {
+            let this = plan(this, WINDOW, OFFSET, TOP);
+            let next = plan(next, WINDOW, OFFSET, TOP);
+            let reload = this.reload_trigger(&next);
+            assert!(reload <= this.triggers[1] && reload <= next.triggers[0]);
+            // the reload handler has the rest of the down half to load `next`
+            assert!(reload >= WINDOW - OFFSET);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Sliding mode observer of the rotor angle and velocity.
+//!
+//! A model of the winding, `L di/dt = v - R i - e`, is driven by the applied voltage
+//! and a switching term `z` that forces its currents onto the measured ones. On the
+//! sliding surface `z` averages to the back EMF `e = ω ψ (-sin θ, cos θ)`, so two low
+//! pass stages extract it and the angle follows from its direction:
+//!
+//! - the switching function is a saturation, linear within a boundary layer of current
+//!   error, which trades a little accuracy for far less chattering than a sign function
+//! - the cutoff of both stages follows the estimated velocity, as a multiple of it and
+//!   no lower than a floor, and the lag of the stages, `2 atan(ω / ωc)`, is added back
+//!   to the angle, so the estimate does not fall behind as the speed rises
+//! - the cutoff follows the speed through a filter at a quarter of the floor: a rising
+//!   cutoff shortens the lag, which reads as more velocity, and adapting faster than
+//!   the floor frequency would run away
+//!
+//! The back EMF vanishes at standstill, so the estimate is only usable above a few
+//! percent of the rated speed, see [`crate::startup`] for getting there.
+//!
+//! Currents and voltages are Q15 per unit as in [`crate::current`], the state keeps 12
+//! more fractional bits. One [`SlidingModeObserver::update`] is fixed point only: on
+//! the Cortex-M3 at opt-level 3 it compiles to about 250 instructions, including the
+//! speed filter of the cutoff, with no loops or divisions, mostly `umull` and `mla` for
+//! the `i64` products, plus two calls of [`crate::trig::atan2`], one for the angle and
+//! one for the compensation. Budget about 600 cycles, 8.3 µs at 72 MHz, about an eighth
+//! of a 16 kHz period.
+
+use crate::estimator::Estimator;
+use crate::filter::LowPass;
+use crate::transform::AlphaBeta;
+use crate::trig::atan2;
+
+/// Extra fractional bits of the per unit state.
+const STATE_SHIFT: u32 = 12;
+/// Fractional bits of the model and filter coefficients.
+const SHIFT: u32 = 24;
+/// Fractional bits of the cutoff per count/s.
+const CUTOFF_SHIFT: u32 = 16;
+/// Largest filter coefficient, the forward Euler stages stay well damped below it.
+const MAX_ALPHA: i64 = 1 << (SHIFT - 1);
+
+/// Motor and tuning of a [`SlidingModeObserver`].
+///
+/// `resistance` and `inductance` are phase values in ohm and henry, the per unit bases
+/// are the bus voltage and the full scale current of the current sense, as for
+/// [`crate::current::tuned_gains`].
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SlidingModeConfig {
+    pub resistance: f32,
+    pub inductance: f32,
+    pub bus_volts: f32,
+    pub full_scale_amps: f32,
+    /// switching gain, per unit voltage, above the largest back EMF
+    pub gain: f32,
+    /// per unit current error within which the switching is linear
+    pub boundary: f32,
+    /// cutoff of the back EMF filters as a multiple of the electrical frequency
+    pub cutoff_ratio: f32,
+    /// lowest cutoff of the back EMF filters, Hz
+    pub min_cutoff_hz: f32,
+    /// cutoff of the velocity filter, Hz
+    pub velocity_cutoff_hz: f32,
+}
+
+/// Back EMF sliding mode observer, see the module documentation.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SlidingModeObserver {
+    sample_hz: u32,
+    /// Q24, `1 - R Ts / L` and `Ts Vb / (L Ib)`
+    decay: i64,
+    input: i64,
+    /// Q27 switching gain and Q24 slope within the boundary layer
+    gain: i64,
+    slope: i64,
+    /// Q16 filter cutoff, counts/s per count/s of velocity, and its floor in counts/s
+    cutoff_ratio: i64,
+    min_cutoff: i64,
+    /// Q24 filter coefficient per count/s of cutoff
+    alpha_per_count: i64,
+    /// Q27 per unit
+    current: AlphaBeta<i32>,
+    switching: AlphaBeta<i32>,
+    back_emf: AlphaBeta<i32>,
+    smoothed: AlphaBeta<i32>,
+    /// angle of the smoothed back EMF, before the compensation
+    raw_angle: u16,
+    angle: u16,
+    velocity: LowPass,
+    /// speed the cutoff follows
+    speed: LowPass,
+}
+
+impl SlidingModeObserver {
+    /// Observer run every period of a `sample_hz` current loop.
+    pub fn new(config: SlidingModeConfig, sample_hz: u32) -> Self {
+        let ts = 1.0 / sample_hz as f64;
+        let fixed = |x: f64, shift: u32| (x * (1u64 << shift) as f64 + 0.5) as i64;
+        let inductance = config.inductance as f64;
+        let input = ts * config.bus_volts as f64 / (inductance * config.full_scale_amps as f64);
+        Self {
+            sample_hz,
+            decay: fixed(1.0 - config.resistance as f64 * ts / inductance, SHIFT),
+            input: fixed(input, SHIFT),
+            gain: fixed(config.gain as f64, 15 + STATE_SHIFT),
+            slope: fixed(config.gain as f64 / config.boundary as f64, SHIFT),
+            cutoff_ratio: fixed(config.cutoff_ratio as f64, CUTOFF_SHIFT),
+            min_cutoff: (config.min_cutoff_hz as f64 * 65_536.0) as i64,
+            alpha_per_count: fixed(core::f64::consts::TAU * ts / 65_536.0, SHIFT + CUTOFF_SHIFT),
+            current: AlphaBeta::default(),
+            switching: AlphaBeta::default(),
+            back_emf: AlphaBeta::default(),
+            smoothed: AlphaBeta::default(),
+            raw_angle: 0,
+            angle: 0,
+            velocity: LowPass::from_cutoff(config.velocity_cutoff_hz, sample_hz as f32),
+            speed: LowPass::from_cutoff(config.min_cutoff_hz / 4.0, sample_hz as f32),
+        }
+    }
+
+    /// Model current of this sample on one axis from the last one, all Q27.
+    fn predict(&self, current: i32, voltage: i16, switching: i32) -> i32 {
+        let drive = ((voltage as i64) << STATE_SHIFT) - switching as i64;
+        ((self.decay * current as i64 + self.input * drive) >> SHIFT) as i32
+    }
+
+    /// Saturating switching function of a Q27 current error.
+    fn switch(&self, error: i32) -> i32 {
+        // unlike clamp, max and min leave no panic for a negative gain
+        ((self.slope * error as i64) >> SHIFT)
+            .max(-self.gain)
+            .min(self.gain) as i32
+    }
+
+    /// Estimated back EMF, Q15 per unit, ahead of the angle filter.
+    pub fn back_emf(&self) -> AlphaBeta<i16> {
+        AlphaBeta {
+            alpha: (self.back_emf.alpha >> STATE_SHIFT) as i16,
+            beta: (self.back_emf.beta >> STATE_SHIFT) as i16,
+        }
+    }
+}
+
+impl Estimator for SlidingModeObserver {
+    fn update(&mut self, currents: AlphaBeta<i16>, voltage: AlphaBeta<i16>) {
+        let speed = self.speed.update(self.velocity.value().abs()) as i64;
+        let cutoff = ((speed * self.cutoff_ratio) >> CUTOFF_SHIFT).max(self.min_cutoff);
+        let alpha = ((cutoff * self.alpha_per_count) >> CUTOFF_SHIFT).min(MAX_ALPHA);
+
+        self.current = AlphaBeta {
+            alpha: self.predict(self.current.alpha, voltage.alpha, self.switching.alpha),
+            beta: self.predict(self.current.beta, voltage.beta, self.switching.beta),
+        };
+        let error = AlphaBeta {
+            alpha: self.current.alpha - ((currents.alpha as i32) << STATE_SHIFT),
+            beta: self.current.beta - ((currents.beta as i32) << STATE_SHIFT),
+        };
+        self.switching = AlphaBeta {
+            alpha: self.switch(error.alpha),
+            beta: self.switch(error.beta),
+        };
+        let low_pass = |y: &mut i32, x: i32| {
+            *y += (((x as i64 - *y as i64) * alpha) >> SHIFT) as i32;
+        };
+        low_pass(&mut self.back_emf.alpha, self.switching.alpha);
+        low_pass(&mut self.back_emf.beta, self.switching.beta);
+        low_pass(&mut self.smoothed.alpha, self.back_emf.alpha);
+        low_pass(&mut self.smoothed.beta, self.back_emf.beta);
+
+        // e = ω ψ (-sin θ, cos θ), turned half a turn when running backwards
+        let angle = atan2(-self.smoothed.alpha, self.smoothed.beta);
+        let step = angle.wrapping_sub(self.raw_angle) as i16 as i32;
+        self.raw_angle = angle;
+        let velocity = self.velocity.update(step * self.sample_hz as i32);
+        let lag = 2 * atan2(speed as i32, cutoff.min(i32::MAX as i64) as i32) as i32;
+        self.angle = if velocity < 0 {
+            angle.wrapping_add(0x8000).wrapping_sub(lag as u16)
+        } else {
+            angle.wrapping_add(lag as u16)
+        };
+    }
+
+    fn angle(&self) -> u16 {
+        self.angle
+    }
+
+    fn velocity(&self) -> i32 {
+        self.velocity.value()
+    }
+
+    fn reset(&mut self) {
+        self.current = AlphaBeta::default();
+        self.switching = AlphaBeta::default();
+        self.back_emf = AlphaBeta::default();
+        self.smoothed = AlphaBeta::default();
+        self.raw_angle = 0;
+        self.angle = 0;
+        self.velocity.reset(0);
+        self.speed.reset(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{
+        current_loop, periods, Motor, BUS_VOLTS, FULL_SCALE_AMPS, INDUCTANCE, PWM_HZ, RESISTANCE,
+    };
+    use crate::transform::{q15, Dq};
+
+    fn config() -> SlidingModeConfig {
+        SlidingModeConfig {
+            resistance: RESISTANCE as f32,
+            inductance: INDUCTANCE as f32,
+            bus_volts: BUS_VOLTS as f32,
+            full_scale_amps: FULL_SCALE_AMPS as f32,
+            gain: 0.5,
+            boundary: 0.125,
+            cutoff_ratio: 1.0,
+            min_cutoff_hz: 20.0,
+            velocity_cutoff_hz: 50.0,
+        }
+    }
+
+    /// Motor turning at a constant velocity under field oriented current control with
+    /// the true angle, returns the worst angle error in counts after `settle` seconds.
+    fn run(config: SlidingModeConfig, hz: f64, settle: f64) -> (i32, SlidingModeObserver) {
+        let mut observer = SlidingModeObserver::new(config, PWM_HZ);
+        let mut current_loop = current_loop(INDUCTANCE, 1_000.0);
+        current_loop.set_setpoint(Dq { d: 0, q: q15(0.2) });
+        let mut motor = Motor::non_salient(0.3);
+        let mut voltage = AlphaBeta::default();
+        let mut worst = 0;
+        for n in 0..periods(settle + 0.2) {
+            let currents = motor.currents();
+            observer.update(currents, voltage);
+            if n > periods(settle) {
+                let error = observer.angle().wrapping_sub(motor.angle()) as i16 as i32;
+                worst = worst.max(error.abs());
+            }
+            voltage = current_loop.update(currents, motor.angle());
+            motor.step(voltage, hz);
+        }
+        (worst, observer)
+    }
+    #[test]
+    fn tracks_angle_and_velocity() {
+        for hz in [100.0, -100.0, 300.0] {
+            let (worst, observer) = run(config(), hz, 0.3);
+            // a few degrees
+            assert!(worst < 700, "{hz} Hz: {worst}");
+            let velocity = hz * 65_536.0;
+            let error = (observer.velocity() as f64 - velocity).abs();
+            assert!(
+                error < 0.02 * velocity.abs(),
+                "{hz} Hz: {}",
+                observer.velocity()
+            );
+        }
+    }
+
+    #[test]
+    fn adapts_the_cutoff_without_running_away() {
+        // just above the floor the adapting cutoff used to oscillate the estimate
+        for hz in [10.0, 30.0, -40.0] {
+            let (worst, observer) = run(config(), hz, 0.3);
+            assert!(worst < 700, "{hz} Hz: {worst}");
+            let velocity = hz * 65_536.0;
+            assert!((observer.velocity() as f64 - velocity).abs() < 0.02 * velocity.abs());
+        }
+    }
+
+    #[test]
+    fn compensates_the_filter_lag() {
+        // a fixed cutoff at the electrical frequency lags the angle by 90 degrees
+        let fixed = SlidingModeConfig {
+            cutoff_ratio: 0.0,
+            min_cutoff_hz: 100.0,
+            ..config()
+        };
+        let (worst, _) = run(fixed, 100.0, 0.3);
+        assert!(worst < 700, "{worst}");
+    }
+
+    #[test]
+    fn switching_is_linear_within_the_boundary() {
+        let observer = SlidingModeObserver::new(config(), PWM_HZ);
+        let q27 = |x: f64| (x * (1 << 27) as f64) as i32;
+        // gain 0.5 over a boundary of 0.125
+        assert_eq!(observer.switch(q27(0.0625)), q27(0.25));
+        assert_eq!(observer.switch(-q27(0.0625)), -q27(0.25));
+        assert_eq!(observer.switch(q27(0.2)), q27(0.5));
+        assert_eq!(observer.switch(-q27(0.9)), -q27(0.5));
+    }
+}
@@ -0,0 +1,507 @@
+//! Open loop I/f startup of a sensorless motor.
+//!
+//! The rotor is first aligned by a current on the d axis, then a current vector of
+//! constant magnitude is turned at a rising speed and drags the rotor along until the
+//! back EMF is large enough for an observer. Once the observer's velocity agrees with
+//! the open loop one, the angle is handed over: the output switches to the estimated
+//! frame with the same current vector, its `Iq` being the torque the load needed, and the
+//! `Id` is released over the handover time. Afterwards the `Iq` can be taken over by a
+//! [`crate::velocity::VelocityLoop`] with `track`, without a step in torque.
+//!
+//! [`Startup::update`] runs every PWM period, its output is the angle and the d/q
+//! setpoint of the [`crate::current::CurrentLoop`], so the current is regulated rather
+//! than the voltage. If the estimate does not converge in time, or the estimated
+//! velocity falls below the stall velocity after the handover, the phases rest for a
+//! while and the sequence is retried up to the configured number of times.
+//!
+//! In I/f the rotor settles ahead of the open loop angle by up to 90 degrees in the
+//! direction of rotation, where the torque matches the load, so an estimate within 90
+//! degrees counts as converged.
+
+use crate::transform::Dq;
+use crate::trig::sin_cos;
+
+/// A quarter turn of the electrical angle.
+const QUARTER: i32 = 16_384;
+
+/// Parameters of the startup, currents are Q15 fractions of the full scale current and
+/// velocities electrical angle counts per second, the sign of `handover_velocity`
+/// giving the direction.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StartupConfig {
+    /// `Id` holding the rotor at `align_angle`
+    pub align_current: i16,
+    pub align_angle: u16,
+    /// seconds
+    pub align_time: f32,
+    /// magnitude of the rotating current vector
+    pub ramp_current: i16,
+    /// counts/s²
+    pub acceleration: f32,
+    /// open loop velocity held until the observer has converged
+    pub handover_velocity: i32,
+    /// largest difference of the estimated and the open loop velocity
+    pub velocity_tolerance: i32,
+    /// seconds the estimate has to agree before the handover
+    pub converge_time: f32,
+    /// seconds at the handover velocity before the attempt fails
+    pub converge_timeout: f32,
+    /// seconds the `Id` left after the switch to the estimate is released over
+    pub handover_time: f32,
+    /// estimated velocity in the direction of rotation below which the rotor stalls
+    pub stall_velocity: i32,
+    /// seconds below the stall velocity before the attempt fails
+    pub stall_time: f32,
+    /// attempts after the first one
+    pub retries: u8,
+    /// seconds without current between attempts
+    pub retry_delay: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum State {
+    Idle,
+    Align,
+    /// open loop acceleration to the handover velocity
+    Ramp,
+    /// open loop at the handover velocity, waiting for the estimate
+    Converge,
+    /// closing the load angle onto the estimate
+    Handover,
+    /// following the estimate
+    Running,
+    /// resting before the next attempt
+    Retry,
+    /// out of retries
+    Failed,
+}
+
+/// Why an attempt failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Failure {
+    NotConverged,
+    Stalled,
+}
+
+/// Angle and d/q current setpoint for the current loop.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Output {
+    pub angle: u16,
+    pub current: Dq<i16>,
+}
+
+/// I/f startup sequence, see the module documentation.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Startup {
+    config: StartupConfig,
+    pwm_hz: u32,
+    /// 1 or -1
+    direction: i16,
+    align_periods: u32,
+    converge_periods: u32,
+    timeout_periods: u32,
+    stall_periods: u32,
+    retry_periods: u32,
+    /// Q32 counts per period², Q32 counts per period
+    acceleration: i64,
+    handover_step: i64,
+    handover_periods: u32,
+    state: State,
+    /// periods in the current state
+    count: u32,
+    /// consecutive periods the estimate agreed, or was stalled
+    streak: u32,
+    attempts: u8,
+    failure: Option<Failure>,
+    /// Q16 open loop angle, Q32 counts per period
+    phase: u32,
+    step: i64,
+    /// current in the estimated frame from the handover on, and the `Id` released per
+    /// period
+    current: Dq<i16>,
+    release: i16,
+}
+
+impl Startup {
+    /// Sequence run every period of a `pwm_hz` current loop, idle until started.
+    pub fn new(config: StartupConfig, pwm_hz: u32) -> Self {
+        let fs = pwm_hz as f64;
+        let periods = |seconds: f32| (seconds as f64 * fs) as u32;
+        let direction = if config.handover_velocity < 0 { -1 } else { 1 };
+        Self {
+            config,
+            pwm_hz,
+            direction,
+            align_periods: periods(config.align_time),
+            converge_periods: periods(config.converge_time).max(1),
+            timeout_periods: periods(config.converge_timeout),
+            stall_periods: periods(config.stall_time).max(1),
+            retry_periods: periods(config.retry_delay),
+            acceleration: (config.acceleration as f64 * direction as f64 / (fs * fs)
+                * (1u64 << 32) as f64) as i64,
+            handover_step: (config.handover_velocity as f64 / fs * (1u64 << 32) as f64) as i64,
+            handover_periods: periods(config.handover_time).max(1),
+            state: State::Idle,
+            count: 0,
+            streak: 0,
+            attempts: 0,
+            failure: None,
+            phase: 0,
+            step: 0,
+            current: Dq::default(),
+            release: 0,
+        }
+    }
+
+    /// Begin with the alignment, clearing the attempts.
+    pub fn start(&mut self) {
+        self.attempts = 0;
+        self.failure = None;
+        self.enter(State::Align);
+    }
+
+    /// Return to idle, the output current is zero.
+    pub fn stop(&mut self) {
+        self.enter(State::Idle);
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Attempts made since the start, including the current one.
+    pub fn attempts(&self) -> u8 {
+        self.attempts
+    }
+
+    /// Why the latest attempt failed.
+    pub fn failure(&self) -> Option<Failure> {
+        self.failure
+    }
+
+    /// Velocity of the open loop angle, counts/s.
+    pub fn open_loop_velocity(&self) -> i32 {
+        (((self.step >> 16) * self.pwm_hz as i64) >> 16) as i32
+    }
+
+    /// Call every PWM period with the observer's latest angle and velocity.
+    pub fn update(&mut self, angle: u16, velocity: i32) -> Output {
+        self.count = self.count.saturating_add(1);
+        match self.state {
+            State::Idle | State::Failed => {}
+            State::Align => {
+                if self.count >= self.align_periods {
+                    self.enter(State::Ramp);
+                }
+            }
+            State::Ramp => {
+                self.step += self.acceleration;
+                if (self.step - self.handover_step) * self.direction as i64 >= 0 {
+                    self.step = self.handover_step;
+                    self.enter(State::Converge);
+                }
+                self.advance();
+            }
+            State::Converge => {
+                self.advance();
+                let error = self.open_loop_angle().wrapping_sub(angle) as i16 as i32;
+                let slip = velocity.saturating_sub(self.open_loop_velocity());
+                if error.abs() <= QUARTER && slip.abs() <= self.config.velocity_tolerance {
+                    self.streak += 1;
+                } else {
+                    self.streak = 0;
+                }
+                if self.streak >= self.converge_periods {
+                    self.hand_over(error);
+                } else if self.count >= self.timeout_periods {
+                    self.fail(Failure::NotConverged);
+                }
+            }
+            State::Handover => {
+                let d = self.current.d;
+                self.current.d -= d.clamp(-self.release, self.release);
+                if self.current.d == 0 {
+                    self.enter(State::Running);
+                }
+                self.check_stall(velocity);
+            }
+            State::Running => self.check_stall(velocity),
+            State::Retry => {
+                if self.count >= self.retry_periods {
+                    self.enter(State::Align);
+                }
+            }
+        }
+        self.output(angle)
+    }
+
+    fn output(&self, estimate: u16) -> Output {
+        match self.state {
+            State::Idle | State::Retry | State::Failed => Output::default(),
+            State::Align => Output {
+                angle: self.config.align_angle,
+                current: Dq {
+                    d: self.config.align_current,
+                    q: 0,
+                },
+            },
+            State::Ramp | State::Converge => Output {
+                angle: self.open_loop_angle(),
+                current: Dq {
+                    d: 0,
+                    q: self.config.ramp_current * self.direction,
+                },
+            },
+            State::Handover | State::Running => Output {
+                angle: estimate,
+                current: self.current,
+            },
+        }
+    }
+
+    fn enter(&mut self, state: State) {
+        match state {
+            State::Align => self.attempts = self.attempts.saturating_add(1),
+            State::Ramp => {
+                // a quarter turn behind, the current vector stays where it aligned
+                let angle = self.config.align_angle as i32 - QUARTER * self.direction as i32;
+                self.phase = (angle as u16 as u32) << 16;
+                self.step = 0;
+            }
+            _ => {}
+        }
+        self.state = state;
+        self.count = 0;
+        self.streak = 0;
+    }
+
+    /// Switch to the estimated frame, `error` is the open loop angle less the estimate.
+    fn hand_over(&mut self, error: i32) {
+        // the vector is a quarter turn ahead of the open loop angle
+        let angle = error + QUARTER * self.direction as i32;
+        let (sin, cos) = sin_cos(angle as u16);
+        let current = self.config.ramp_current as i32;
+        self.current = Dq {
+            d: ((current * cos as i32) >> 15) as i16,
+            q: ((current * sin as i32) >> 15) as i16,
+        };
+        self.release = (self.current.d.unsigned_abs() as u32)
+            .div_ceil(self.handover_periods)
+            .max(1) as i16;
+        self.enter(State::Handover);
+    }
+
+    fn fail(&mut self, failure: Failure) {
+        self.failure = Some(failure);
+        if self.attempts > self.config.retries {
+            self.enter(State::Failed);
+        } else {
+            self.enter(State::Retry);
+        }
+    }
+
+    fn check_stall(&mut self, velocity: i32) {
+        if velocity.saturating_mul(self.direction as i32) < self.config.stall_velocity {
+            self.streak += 1;
+            if self.streak >= self.stall_periods {
+                self.fail(Failure::Stalled);
+            }
+        } else {
+            self.streak = 0;
+        }
+    }
+
+    fn advance(&mut self) {
+        self.phase = self.phase.wrapping_add((self.step >> 16) as u32);
+    }
+
+    fn open_loop_angle(&self) -> u16 {
+        (self.phase >> 16) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::q15;
+    use core::f64::consts::TAU;
+
+    const PWM_HZ: u32 = 16_000;
+    const COUNTS: f64 = 65_536.0;
+
+    fn config(handover_velocity: i32) -> StartupConfig {
+        StartupConfig {
+            align_current: q15(0.3),
+            align_angle: 10_000,
+            align_time: 0.2,
+            ramp_current: q15(0.3),
+            acceleration: 200_000.0,
+            handover_velocity,
+            velocity_tolerance: 10_000,
+            converge_time: 0.05,
+            converge_timeout: 0.5,
+            handover_time: 0.1,
+            stall_velocity: 20_000,
+            stall_time: 0.05,
+            retries: 2,
+            retry_delay: 0.1,
+        }
+    }
+
+    /// Rotor with damping and an ideal current loop, the observer reads it exactly.
+    struct Rotor {
+        angle: f64,
+        velocity: f64,
+        /// counts/s² per Q15 unit of `Iq`
+        torque: f64,
+        /// 1/s
+        damping: f64,
+        locked: bool,
+    }
+
+    impl Rotor {
+        fn new() -> Self {
+            Self {
+                angle: 0.0,
+                velocity: 0.0,
+                torque: 500.0,
+                damping: 30.0,
+                locked: false,
+            }
+        }
+
+        fn step(&mut self, output: Output) {
+            // the current vector seen from the rotor
+            let current = output.current;
+            let vector =
+                output.angle as f64 / COUNTS * TAU + (current.q as f64).atan2(current.d as f64);
+            let magnitude = (current.d as f64).hypot(current.q as f64);
+            let iq = magnitude * (vector - self.angle / COUNTS * TAU).sin();
+            if self.locked {
+                self.velocity = 0.0;
+                return;
+            }
+            let dt = 1.0 / PWM_HZ as f64;
+            self.velocity += (iq * self.torque - self.damping * self.velocity) * dt;
+            self.angle += self.velocity * dt;
+        }
+
+        fn estimate(&self) -> (u16, i32) {
+            (self.angle.rem_euclid(COUNTS) as u16, self.velocity as i32)
+        }
+    }
+
+    /// Stationary frame angle of the current vector.
+    fn vector(output: Output) -> f64 {
+        let current = output.current;
+        let angle = output.angle as f64 + (current.q as f64).atan2(current.d as f64) / TAU * COUNTS;
+        angle.rem_euclid(COUNTS)
+    }
+
+    fn hand_over(handover_velocity: i32) {
+        let mut startup = Startup::new(config(handover_velocity), PWM_HZ);
+        let mut rotor = Rotor::new();
+        startup.start();
+        let mut previous: Option<f64> = None;
+        let mut states = Vec::new();
+        let mut handed_over = 0.0;
+        for _ in 0..2 * PWM_HZ {
+            let (angle, velocity) = rotor.estimate();
+            let output = startup.update(angle, velocity);
+            if states.last() != Some(&startup.state()) {
+                states.push(startup.state());
+                handed_over = rotor.velocity;
+            }
+            let running = output.current != Dq::default();
+            if let (Some(previous), true) = (previous, running) {
+                // the vector turns without a jump, a few counts per period
+                let turn = (vector(output) - previous).rem_euclid(COUNTS);
+                let turn = if turn > COUNTS / 2.0 {
+                    turn - COUNTS
+                } else {
+                    turn
+                };
+                assert!(turn.abs() < 16.0, "{turn} in {:?}", startup.state());
+            }
+            previous = running.then(|| vector(output));
+            rotor.step(output);
+        }
+        use State::*;
+        assert_eq!(states, [Align, Ramp, Converge, Handover, Running]);
+        assert_eq!(startup.attempts(), 1);
+        // still close to the open loop velocity when the handover completed
+        let slip = handed_over - handover_velocity as f64;
+        assert!(slip.abs() < 10_000.0, "{handed_over}");
+        // and holds it, the `Iq` is the torque the load needs
+        let slip = rotor.velocity - handover_velocity as f64;
+        assert!(slip.abs() < 10_000.0, "{}", rotor.velocity);
+    }
+
+    #[test]
+    fn aligns_ramps_and_hands_over() {
+        hand_over(131_072);
+        hand_over(-131_072);
+    }
+
+    #[test]
+    fn ramp_starts_where_the_rotor_aligned() {
+        let mut startup = Startup::new(config(131_072), PWM_HZ);
+        startup.start();
+        let aligned = startup.update(0, 0);
+        assert_eq!(aligned.current, Dq { d: q15(0.3), q: 0 });
+        while startup.state() == State::Align {
+            startup.update(0, 0);
+        }
+        let ramp = startup.update(0, 0);
+        assert!((vector(ramp) - vector(aligned)).abs() < 1.0);
+        assert!(startup.open_loop_velocity() > 0);
+    }
+
+    #[test]
+    fn retries_a_locked_rotor_then_fails() {
+        let mut startup = Startup::new(config(131_072), PWM_HZ);
+        let mut rotor = Rotor::new();
+        rotor.locked = true;
+        startup.start();
+        let mut rested = 0;
+        for _ in 0..10 * PWM_HZ {
+            let (angle, velocity) = rotor.estimate();
+            let output = startup.update(angle, velocity);
+            if startup.state() == State::Retry {
+                assert_eq!(output, Output::default());
+                rested += 1;
+            }
+            rotor.step(output);
+        }
+        assert_eq!(startup.state(), State::Failed);
+        assert_eq!(startup.failure(), Some(Failure::NotConverged));
+        assert_eq!(startup.attempts(), 3);
+        assert_eq!(rested, 2 * 1_600);
+        assert_eq!(startup.update(0, 0), Output::default());
+    }
+
+    #[test]
+    fn detects_a_stall_after_the_handover() {
+        let mut startup = Startup::new(config(131_072), PWM_HZ);
+        let mut rotor = Rotor::new();
+        startup.start();
+        while startup.state() != State::Running {
+            let (angle, velocity) = rotor.estimate();
+            rotor.step(startup.update(angle, velocity));
+        }
+        rotor.locked = true;
+        rotor.velocity = 0.0;
+        for _ in 0..800 {
+            let (angle, velocity) = rotor.estimate();
+            rotor.step(startup.update(angle, velocity));
+        }
+        assert_eq!(startup.state(), State::Retry);
+        assert_eq!(startup.failure(), Some(Failure::Stalled));
+        assert_eq!(startup.attempts(), 1);
+    }
+}
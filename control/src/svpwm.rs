@@ -0,0 +1,176 @@
+//! Space vector modulation.
+//!
+//! Voltages are fractions of the bus voltage. Min-max zero sequence injection centres
+//! the three phase voltages in the available range, which is equivalent to classic
+//! space vector PWM and reaches a vector length of `1 / sqrt(3)` before clipping, 15%
+//! more than plain sine modulation.
+
+use crate::math::isqrt;
+use crate::transform::{inverse_clarke, Abc, AlphaBeta, Dq, Scalar};
+
+/// Longest stationary vector that is modulated without distortion.
+pub const LINEAR_LIMIT: f32 = 0.577_350_26;
+
+/// Length of a Q15 vector.
+pub fn magnitude(v: Dq<i16>) -> i16 {
+    let square = v.d as i64 * v.d as i64 + v.q as i64 * v.q as i64;
+    isqrt(square as u64).min(i16::MAX as u32) as i16
+}
+
+/// Largest `q` that keeps a vector with the given `d` inside a circle of `radius`.
+pub fn circle_headroom(d: i16, radius: i16) -> i16 {
+    let square = radius as i64 * radius as i64 - d as i64 * d as i64;
+    isqrt(square.max(0) as u64) as i16
+}
+
+/// Shorten a Q15 vector to at most `radius`, giving `d` priority over `q`.
+///
+/// Keeping `d` preserves the flux or field weakening current while torque gives way.
+pub fn limit_circle(v: Dq<i16>, radius: i16) -> Dq<i16> {
+    let d = v.d.clamp(-radius, radius);
+    let headroom = circle_headroom(d, radius);
+    Dq {
+        d,
+        q: v.q.clamp(-headroom, headroom),
+    }
+}
+
+/// Phase voltages in `-0.5..=0.5` of the bus for a stationary voltage vector.
+#[inline]
+pub fn modulate<T: Scalar>(v: AlphaBeta<T>) -> Abc<T> {
+    let abc = inverse_clarke(v);
+    let (mut min, mut max) = (abc.a, abc.a);
+    for x in [abc.b, abc.c] {
+        if x < min {
+            min = x;
+        }
+        if x > max {
+            max = x;
+        }
+    }
+    let offset = min.half().add_sat(max.half()).neg_sat();
+    Abc {
+        a: abc.a.add_sat(offset),
+        b: abc.b.add_sat(offset),
+        c: abc.c.add_sat(offset),
+    }
+}
+
+/// Compare values of phase a, b and c for a stationary voltage vector, e.g. for
+/// `Pwm3::set_duties` with its `get_max_duty`.
+///
+/// Outside the linear range the phases clip at the rails.
+#[inline]
+pub fn duties<T: Scalar>(v: AlphaBeta<T>, max_duty: u16) -> [u16; 3] {
+    let abc = modulate(v);
+    [
+        abc.a.duty(max_duty),
+        abc.b.duty(max_duty),
+        abc.c.duty(max_duty),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::q15;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn vector_magnitude() {
+        assert_eq!(
+            magnitude(Dq {
+                d: 3_000,
+                q: -4_000
+            }),
+            5_000
+        );
+    }
+
+    #[test]
+    fn circle_limit_keeps_d() {
+        let radius = q15(0.5);
+        let v = limit_circle(
+            Dq {
+                d: q15(0.3),
+                q: q15(0.9),
+            },
+            radius,
+        );
+        assert_eq!(v.d, q15(0.3));
+        assert!((v.q - q15(0.4)).abs() <= 1);
+        let v = limit_circle(
+            Dq {
+                d: q15(-0.9),
+                q: q15(0.1),
+            },
+            radius,
+        );
+        assert_eq!(v, Dq { d: -radius, q: 0 });
+        // inside the circle nothing changes
+        let v = Dq {
+            d: q15(0.2),
+            q: q15(-0.3),
+        };
+        assert_eq!(limit_circle(v, radius), v);
+    }
+
+    #[test]
+    fn modulation_is_centred_and_linear() {
+        for step in 0..360 {
+            let theta = 2.0 * PI * step as f64 / 360.0;
+            let m = 0.57;
+            let v = AlphaBeta {
+                alpha: (m * theta.cos()) as f32,
+                beta: (m * theta.sin()) as f32,
+            };
+            let abc = modulate(v);
+            let phases = [abc.a, abc.b, abc.c];
+            let max = phases.iter().cloned().fold(f32::MIN, f32::max);
+            let min = phases.iter().cloned().fold(f32::MAX, f32::min);
+            assert!((max + min).abs() < 1e-6);
+            assert!(max <= 0.5 && min >= -0.5);
+
+            // line to line voltages are those of the requested vector
+            let ab = (abc.a - abc.b) as f64;
+            let expected = m * (theta.cos() - (theta - 2.0 * PI / 3.0).cos());
+            assert!((ab - expected).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn duties_follow_the_vector() {
+        let max_duty = 1_000;
+        assert_eq!(duties(AlphaBeta::<i16>::default(), max_duty), [500; 3]);
+        assert_eq!(duties(AlphaBeta::<f32>::default(), max_duty), [500; 3]);
+
+        let v = AlphaBeta {
+            alpha: q15(0.5),
+            beta: 0,
+        };
+        let [a, b, c] = duties(v, max_duty);
+        // phase voltages 0.5, -0.25, -0.25 shifted down by 0.125
+        assert_eq!([a, b, c], [875, 125, 125]);
+        assert_eq!(
+            duties(
+                AlphaBeta {
+                    alpha: 0.5f32,
+                    beta: 0.0
+                },
+                max_duty
+            ),
+            [a, b, c]
+        );
+    }
+
+    #[test]
+    fn overmodulation_clips_below_max_duty() {
+        let v = AlphaBeta {
+            alpha: i32::MAX,
+            beta: 0,
+        };
+        let [a, b, c] = duties(v, 1_000);
+        assert_eq!(a, 999);
+        assert_eq!((b, c), (0, 0));
+    }
+}
@@ -0,0 +1,459 @@
+//! Point to point trajectories.
+//!
+//! [`Trajectory`] is an online planner: every sample it steps towards the target from
+//! its current position, velocity and acceleration, so a new target can be set at any
+//! time, also mid-move, without a jump in any of them. Positions are `i32` angle counts,
+//! 65536 to a turn as in [`crate::trig`], and wrap like the angles; velocities and
+//! accelerations are counts/s and counts/s².
+//!
+//! Each sample takes the most forward step the limits allow from which the trajectory
+//! can still come to rest on the target: the trapezoid picks its velocity from the
+//! stopping distance at full deceleration, the S-curve its acceleration from the
+//! stopping distance of a jerk limited deceleration. A move ends when it is within one
+//! sample of rest on the target.
+//!
+//! The planner runs in `f32` relative to the start of the current move, so precision
+//! does not depend on the absolute position. Without an FPU that is expensive: on the
+//! F103 a trapezoid sample takes some 10 µs and an S-curve sample up to about 300 µs
+//! while braking, so run the planner in a task, e.g. at 1 kHz, and hand its setpoints
+//! to the [`crate::position::PositionLoop`] in the control interrupt. The target itself
+//! is kept in whole counts, so moves longer than the 2^24 counts `f32` resolves still
+//! end exactly on it.
+
+use crate::math::sqrt;
+
+/// Halvings of the acceleration step when braking, to 1/64 of a step.
+const BISECTIONS: u32 = 6;
+
+/// Shape of the velocity profile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Profile {
+    /// Constant acceleration phases, the acceleration steps.
+    Trapezoidal,
+    /// Jerk limited, the acceleration ramps and the move takes about `acceleration /
+    /// jerk` longer.
+    SCurve,
+}
+
+/// Limits of a move, all positive.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Limits {
+    /// counts/s
+    pub velocity: f32,
+    /// counts/s²
+    pub acceleration: f32,
+    /// counts/s³, only used by [`Profile::SCurve`]
+    pub jerk: f32,
+}
+
+/// Sample of a trajectory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Setpoint {
+    pub position: i32,
+    pub velocity: i32,
+    pub acceleration: i32,
+}
+
+/// State after `t` seconds of constant `jerk`.
+fn advance((x, v, a): (f32, f32, f32), jerk: f32, t: f32) -> (f32, f32, f32) {
+    (
+        x + v * t + a * t * t / 2.0 + jerk * t * t * t / 6.0,
+        v + a * t + jerk * t * t / 2.0,
+        a + jerk * t,
+    )
+}
+
+/// Distance covered while coming to rest as fast as the limits allow, starting at
+/// velocity `v` and acceleration `a` in the direction of travel.
+fn braking_distance(v: f32, a: f32, max_acceleration: f32, jerk: f32) -> f32 {
+    if v <= 0.0 && a <= 0.0 {
+        return 0.0;
+    }
+    // ramping the acceleration from `a` down to `peak` and back to 0 changes the
+    // velocity by (a² - 2 peak²) / 2 jerk, the full profile holds -max_acceleration
+    let hold =
+        (v + a * a / (2.0 * jerk) - max_acceleration * max_acceleration / jerk) / max_acceleration;
+    let (peak, hold) = if hold >= 0.0 {
+        (-max_acceleration, hold)
+    } else {
+        (-sqrt(v * jerk + a * a / 2.0), 0.0)
+    };
+    // already braking harder than needed, only ease out
+    let peak = peak.min(a);
+    let state = advance((0.0, v, a), -jerk, (a - peak) / jerk);
+    let state = advance(state, 0.0, hold);
+    advance(state, jerk, -peak / jerk).0
+}
+
+/// Online trajectory generator.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Trajectory {
+    profile: Profile,
+    limits: Limits,
+    dt: f32,
+    /// position the move is planned relative to
+    origin: i32,
+    /// target relative to `origin`, kept whole so long moves end exactly on it
+    target: i32,
+    position: f32,
+    velocity: f32,
+    acceleration: f32,
+    done: bool,
+}
+
+impl Trajectory {
+    /// Planner run at `sample_hz`, at rest at `position`.
+    pub fn new(profile: Profile, limits: Limits, sample_hz: f32, position: i32) -> Self {
+        Self {
+            profile,
+            limits,
+            dt: 1.0 / sample_hz,
+            origin: position,
+            target: 0,
+            position: 0.0,
+            velocity: 0.0,
+            acceleration: 0.0,
+            done: true,
+        }
+    }
+
+    /// New limits take effect from the next sample, a lower velocity is approached at
+    /// the acceleration limit.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.done = false;
+    }
+
+    /// Switch the profile, also mid-move.
+    pub fn set_profile(&mut self, profile: Profile) {
+        self.profile = profile;
+        self.done = false;
+    }
+
+    /// Move to `target`, from wherever the trajectory currently is.
+    pub fn set_target(&mut self, target: i32) {
+        // re-plan relative to the current position
+        let whole = self.position as i32;
+        self.origin = self.origin.wrapping_add(whole);
+        self.position -= whole as f32;
+        self.target = target.wrapping_sub(self.origin);
+        self.done = false;
+    }
+
+    pub fn target(&self) -> i32 {
+        self.origin.wrapping_add(self.target)
+    }
+
+    /// At rest on the target.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Stop at `position` at once, e.g. after a fault or to follow a measured position.
+    pub fn reset(&mut self, position: i32) {
+        self.origin = position;
+        self.target = 0;
+        self.position = 0.0;
+        self.velocity = 0.0;
+        self.acceleration = 0.0;
+        self.done = true;
+    }
+
+    /// Step one sample towards the target.
+    pub fn update(&mut self) -> Setpoint {
+        if !self.done {
+            match self.profile {
+                Profile::Trapezoidal => self.step_trapezoidal(),
+                Profile::SCurve => self.step_s_curve(),
+            }
+        }
+        self.setpoint()
+    }
+
+    /// Latest sample.
+    pub fn setpoint(&self) -> Setpoint {
+        Setpoint {
+            position: self.origin.wrapping_add(self.position as i32),
+            velocity: self.velocity as i32,
+            acceleration: self.acceleration as i32,
+        }
+    }
+
+    /// Direction to the target and the distance, velocity and acceleration along it.
+    fn towards_target(&self) -> (f32, f32, f32, f32) {
+        let distance = self.target as f32 - self.position;
+        let direction = if distance > 0.0 || (distance == 0.0 && self.velocity < 0.0) {
+            1.0
+        } else {
+            -1.0
+        };
+        (
+            direction,
+            distance * direction,
+            self.velocity * direction,
+            self.acceleration * direction,
+        )
+    }
+
+    fn step_trapezoidal(&mut self) {
+        let Limits {
+            velocity: max_velocity,
+            acceleration: max_acceleration,
+            ..
+        } = self.limits;
+        let dt = self.dt;
+        let (direction, distance, velocity, _) = self.towards_target();
+
+        // fastest velocity that still stops on the target after this step, from
+        // next² = 2 max_acceleration (distance - (velocity + next) dt / 2)
+        let half_step = max_acceleration * dt / 2.0;
+        let stopping = sqrt(
+            half_step * half_step + 2.0 * max_acceleration * distance - 2.0 * half_step * velocity,
+        ) - half_step;
+        let change = max_acceleration * dt;
+        let next = velocity + (stopping.min(max_velocity) - velocity).clamp(-change, change);
+        let travel = (velocity + next) / 2.0 * dt;
+
+        if velocity <= change && (travel >= distance || distance <= change * dt) {
+            self.arrive();
+            return;
+        }
+        self.position += travel * direction;
+        self.velocity = next * direction;
+        self.acceleration = (next - velocity) / dt * direction;
+    }
+
+    fn step_s_curve(&mut self) {
+        let Limits {
+            velocity: max_velocity,
+            acceleration: max_acceleration,
+            jerk,
+        } = self.limits;
+        let dt = self.dt;
+        let (direction, distance, velocity, acceleration) = self.towards_target();
+        let change = jerk * dt;
+
+        // the state after a step that ends at `next`
+        let step = |next: f32| {
+            let next_velocity = velocity + (acceleration + next) / 2.0 * dt;
+            (next_velocity, (velocity + next_velocity) / 2.0 * dt)
+        };
+        let safe = |next: f32| {
+            let (next_velocity, travel) = step(next);
+            // the velocity left when the acceleration is ramped out
+            let settled = next_velocity + next * next.abs() / (2.0 * jerk);
+            settled <= max_velocity.max(velocity)
+                && braking_distance(next_velocity, next, max_acceleration, jerk)
+                    <= distance - travel
+        };
+        let upper = (acceleration + change).min(max_acceleration);
+        let mut lower = (acceleration - change).max(-max_acceleration);
+        let next = if safe(upper) {
+            upper
+        } else if !safe(lower) {
+            // too late already, brake as hard as possible
+            lower
+        } else {
+            // the most forward safe acceleration lies within this step
+            let mut upper = upper;
+            for _ in 0..BISECTIONS {
+                let middle = (lower + upper) / 2.0;
+                if safe(middle) {
+                    lower = middle;
+                } else {
+                    upper = middle;
+                }
+            }
+            lower
+        };
+        let (next_velocity, travel) = step(next);
+
+        // within one sample of rest on the target
+        if (distance - travel).abs() <= max_acceleration * dt * dt
+            && next_velocity.abs() <= change * dt
+            && next.abs() <= change
+        {
+            self.arrive();
+            return;
+        }
+        self.position += travel * direction;
+        self.velocity = next_velocity * direction;
+        self.acceleration = next * direction;
+    }
+
+    fn arrive(&mut self) {
+        // past 2^24 counts `f32` can not hold the target, so rest on the whole count
+        self.origin = self.origin.wrapping_add(self.target);
+        self.target = 0;
+        self.position = 0.0;
+        self.velocity = 0.0;
+        self.acceleration = 0.0;
+        self.done = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FS: f32 = 1_000.0;
+    const LIMITS: Limits = Limits {
+        velocity: 655_360.0,
+        acceleration: 3_276_800.0,
+        jerk: 65_536_000.0,
+    };
+
+    /// Run to the end, checking the limits on the way, and return the samples taken.
+    fn run(trajectory: &mut Trajectory, limits: Limits) -> Vec<Setpoint> {
+        let mut samples = vec![trajectory.setpoint()];
+        while !trajectory.is_done() {
+            let previous = *samples.last().unwrap();
+            let setpoint = trajectory.update();
+            let dt = 1.0 / FS;
+            let slack = 1.001;
+            assert!(setpoint.velocity.abs() as f32 <= limits.velocity * slack);
+            assert!(setpoint.acceleration.abs() as f32 <= limits.acceleration * slack);
+            let acceleration = (setpoint.velocity - previous.velocity) as f32 / dt;
+            assert!(
+                acceleration.abs() <= limits.acceleration * slack + 1.0 / dt,
+                "{acceleration} {previous:?} {setpoint:?}"
+            );
+            if trajectory.profile == Profile::SCurve && !trajectory.is_done() {
+                let jerk = (setpoint.acceleration - previous.acceleration) as f32 / dt;
+                assert!(jerk.abs() <= limits.jerk * slack, "{jerk}");
+            }
+            samples.push(setpoint);
+            assert!(samples.len() < 100_000);
+        }
+        samples
+    }
+
+    #[test]
+    fn trapezoid_cruises_and_arrives() {
+        // 20 turns: 0.2 s accelerating, 1.8 s cruising, 0.2 s braking
+        let target = 20 * 65_536;
+        let mut trajectory = Trajectory::new(Profile::Trapezoidal, LIMITS, FS, 0);
+        trajectory.set_target(target);
+        let samples = run(&mut trajectory, LIMITS);
+        let duration = samples.len() as f32 / FS;
+        assert!((duration - 2.2).abs() < 0.01, "{duration}");
+        assert_eq!(samples.last().unwrap().position, target);
+        assert!(samples.windows(2).all(|w| w[1].position >= w[0].position));
+        let peak = samples.iter().map(|s| s.velocity).max().unwrap();
+        assert_eq!(peak, LIMITS.velocity as i32);
+    }
+
+    #[test]
+    fn short_trapezoid_is_a_triangle() {
+        // a quarter turn never reaches the velocity limit
+        let mut trajectory = Trajectory::new(Profile::Trapezoidal, LIMITS, FS, -100);
+        trajectory.set_target(-100 - 16_384);
+        let samples = run(&mut trajectory, LIMITS);
+        let peak = samples.iter().map(|s| -s.velocity).max().unwrap() as f32;
+        let expected = (16_384.0 * LIMITS.acceleration).sqrt();
+        assert!((peak / expected - 1.0).abs() < 0.05, "{peak} {expected}");
+        assert_eq!(samples.last().unwrap().position, -100 - 16_384);
+    }
+
+    #[test]
+    fn s_curve_is_jerk_limited_and_arrives() {
+        let target = 20 * 65_536;
+        let mut trajectory = Trajectory::new(Profile::SCurve, LIMITS, FS, 0);
+        trajectory.set_target(target);
+        let samples = run(&mut trajectory, LIMITS);
+        // the trapezoid plus acceleration / jerk
+        let duration = samples.len() as f32 / FS;
+        assert!((duration - 2.25).abs() < 0.02, "{duration}");
+        assert_eq!(samples.last().unwrap().position, target);
+        // no overshoot, not even by a count
+        assert!(samples.iter().all(|s| s.position <= target));
+        let peak = samples.iter().map(|s| s.acceleration).max().unwrap() as f32;
+        assert!((peak / LIMITS.acceleration - 1.0).abs() < 1e-3, "{peak}");
+    }
+
+    #[test]
+    fn short_and_tiny_s_curves() {
+        for distance in [1, 7, 100, 1_000, 16_384, 100_000] {
+            let mut trajectory = Trajectory::new(Profile::SCurve, LIMITS, FS, 0);
+            trajectory.set_target(-distance);
+            let samples = run(&mut trajectory, LIMITS);
+            assert_eq!(samples.last().unwrap().position, -distance);
+            let overshoot = samples
+                .iter()
+                .map(|s| -distance - s.position)
+                .max()
+                .unwrap();
+            assert!(overshoot <= 0, "{distance}: {overshoot}");
+        }
+    }
+
+    #[test]
+    fn new_target_mid_move() {
+        for profile in [Profile::Trapezoidal, Profile::SCurve] {
+            let mut trajectory = Trajectory::new(profile, LIMITS, FS, 1 << 30);
+            trajectory.set_target((1 << 30) + 10 * 65_536);
+            for _ in 0..500 {
+                trajectory.update();
+            }
+            // at full speed, turn back past the start
+            let before = trajectory.setpoint();
+            assert!((before.velocity as f32 - LIMITS.velocity).abs() <= 1.0);
+            trajectory.set_target((1 << 30) - 65_536);
+            let samples = run(&mut trajectory, LIMITS);
+            // no step in position or velocity at the switch
+            let first = samples[1];
+            assert!(first.position - before.position <= before.velocity / 1_000 + 1);
+            assert!((first.velocity - before.velocity) as f32 <= LIMITS.acceleration / FS + 1.0);
+            assert_eq!(trajectory.setpoint().position, (1 << 30) - 65_536);
+        }
+    }
+
+    #[test]
+    fn reversal_while_accelerating_keeps_the_jerk_limit() {
+        let mut trajectory = Trajectory::new(Profile::SCurve, LIMITS, FS, 0);
+        trajectory.set_target(10 * 65_536);
+        for _ in 0..60 {
+            trajectory.update();
+        }
+        assert!(trajectory.setpoint().acceleration as f32 > 0.9 * LIMITS.acceleration);
+        trajectory.set_target(-65_536);
+        // run checks the jerk on the way back
+        let samples = run(&mut trajectory, LIMITS);
+        assert_eq!(samples.last().unwrap().position, -65_536);
+    }
+
+    #[test]
+    fn long_moves_end_on_the_whole_count() {
+        let limits = Limits {
+            velocity: 65_536_000.0,
+            acceleration: 655_360_000.0,
+            jerk: 65_536_000_000.0,
+        };
+        // 256 turns and a count, more than f32 resolves
+        let target = (1 << 24) + 1;
+        for profile in [Profile::Trapezoidal, Profile::SCurve] {
+            let mut trajectory = Trajectory::new(profile, limits, FS, 0);
+            trajectory.set_target(target);
+            assert_eq!(trajectory.target(), target);
+            let samples = run(&mut trajectory, limits);
+            assert_eq!(samples.last().unwrap().position, target);
+            assert_eq!(trajectory.target(), target);
+        }
+    }
+
+    #[test]
+    fn wraps_like_the_angles() {
+        let mut trajectory = Trajectory::new(Profile::SCurve, LIMITS, FS, i32::MAX - 1_000);
+        let target = (i32::MAX - 1_000).wrapping_add(3 * 65_536);
+        trajectory.set_target(target);
+        let samples = run(&mut trajectory, LIMITS);
+        assert_eq!(samples.last().unwrap().position, target);
+        assert!(samples
+            .windows(2)
+            .all(|w| w[1].position.wrapping_sub(w[0].position) >= 0));
+    }
+}
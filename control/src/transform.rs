@@ -0,0 +1,539 @@
+//! Clarke and Park transforms between phase, stationary and rotating frames.
+//!
+//! The transforms are amplitude invariant: a balanced set of phase currents with peak
+//! `I` becomes a vector of length `I`. They are generic over [`Scalar`], which is
+//! implemented for Q15 (`i16`), Q31 (`i32`) and `f32`. Fixed point values are fractions
+//! of a full scale, e.g. the largest current `Isense` can measure, and every operation
+//! saturates instead of wrapping. Keep vectors inside the unit circle, a `d` or `q`
+//! component beyond full scale is clipped.
+
+use core::ops::{Add, Neg, Sub};
+
+/// Q15 from a real number, rounded and saturated.
+pub const fn q15(x: f32) -> i16 {
+    let x = x as f64 * 32_768.0;
+    let x = if x < 0.0 { x - 0.5 } else { x + 0.5 };
+    // float to int casts saturate
+    x as i16
+}
+
+/// Q31 from a real number, rounded and saturated.
+pub const fn q31(x: f32) -> i32 {
+    let x = x as f64 * 2_147_483_648.0;
+    let x = if x < 0.0 { x - 0.5 } else { x + 0.5 };
+    x as i32
+}
+
+/// Number type of the transforms.
+pub trait Scalar: Copy + PartialOrd + Default {
+    const ZERO: Self;
+    /// 1 / sqrt(3)
+    const FRAC_1_SQRT_3: Self;
+    /// sqrt(3) / 2
+    const FRAC_SQRT_3_2: Self;
+    /// 1 / 3
+    const FRAC_1_3: Self;
+
+    /// Saturating sum.
+    fn add_sat(self, rhs: Self) -> Self;
+    /// Saturating difference.
+    fn sub_sat(self, rhs: Self) -> Self;
+    /// Fractional product, rounded and saturated.
+    fn mul_frac(self, rhs: Self) -> Self;
+    fn half(self) -> Self;
+    fn neg_sat(self) -> Self;
+    /// `num / den` as a fraction, e.g. milliamps over the full scale current.
+    fn from_ratio(num: i32, den: i32) -> Self;
+    /// Map `-0.5..=0.5` onto a compare value `0..max_duty`.
+    fn duty(self, max_duty: u16) -> u16;
+}
+
+/// Q15, 1.0 is 32768 and just out of range.
+impl Scalar for i16 {
+    const ZERO: Self = 0;
+    const FRAC_1_SQRT_3: Self = q15(0.577_350_26);
+    const FRAC_SQRT_3_2: Self = q15(0.866_025_4);
+    const FRAC_1_3: Self = q15(1.0 / 3.0);
+
+    #[inline]
+    fn add_sat(self, rhs: Self) -> Self {
+        self.saturating_add(rhs)
+    }
+
+    #[inline]
+    fn sub_sat(self, rhs: Self) -> Self {
+        self.saturating_sub(rhs)
+    }
+
+    #[inline]
+    fn mul_frac(self, rhs: Self) -> Self {
+        let p = (self as i32 * rhs as i32 + (1 << 14)) >> 15;
+        p.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+    }
+
+    #[inline]
+    fn half(self) -> Self {
+        self >> 1
+    }
+
+    #[inline]
+    fn neg_sat(self) -> Self {
+        self.saturating_neg()
+    }
+
+    fn from_ratio(num: i32, den: i32) -> Self {
+        (((num as i64) << 15) / den as i64).clamp(i16::MIN as i64, i16::MAX as i64) as i16
+    }
+
+    #[inline]
+    fn duty(self, max_duty: u16) -> u16 {
+        let x = (self as i32 + (1 << 14)).clamp(0, 1 << 15);
+        ((x * max_duty as i32 + (1 << 14)) >> 15).min(max_duty as i32 - 1) as u16
+    }
+}
+
+/// Q31, 1.0 is 2^31 and just out of range.
+impl Scalar for i32 {
+    const ZERO: Self = 0;
+    const FRAC_1_SQRT_3: Self = q31(0.577_350_26);
+    const FRAC_SQRT_3_2: Self = q31(0.866_025_4);
+    const FRAC_1_3: Self = q31(1.0 / 3.0);
+
+    #[inline]
+    fn add_sat(self, rhs: Self) -> Self {
+        self.saturating_add(rhs)
+    }
+
+    #[inline]
+    fn sub_sat(self, rhs: Self) -> Self {
+        self.saturating_sub(rhs)
+    }
+
+    #[inline]
+    fn mul_frac(self, rhs: Self) -> Self {
+        let p = (self as i64 * rhs as i64 + (1 << 30)) >> 31;
+        p.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+
+    #[inline]
+    fn half(self) -> Self {
+        self >> 1
+    }
+
+    #[inline]
+    fn neg_sat(self) -> Self {
+        self.saturating_neg()
+    }
+
+    fn from_ratio(num: i32, den: i32) -> Self {
+        (((num as i64) << 31) / den as i64).clamp(i32::MIN as i64, i32::MAX as i64) as i32
+    }
+
+    #[inline]
+    fn duty(self, max_duty: u16) -> u16 {
+        let x = (self as i64 + (1 << 30)).clamp(0, 1 << 31);
+        ((x * max_duty as i64 + (1 << 30)) >> 31).min(max_duty as i64 - 1) as u16
+    }
+}
+
+impl Scalar for f32 {
+    const ZERO: Self = 0.0;
+    const FRAC_1_SQRT_3: Self = 0.577_350_26;
+    const FRAC_SQRT_3_2: Self = 0.866_025_4;
+    const FRAC_1_3: Self = 1.0 / 3.0;
+
+    #[inline]
+    fn add_sat(self, rhs: Self) -> Self {
+        self + rhs
+    }
+
+    #[inline]
+    fn sub_sat(self, rhs: Self) -> Self {
+        self - rhs
+    }
+
+    #[inline]
+    fn mul_frac(self, rhs: Self) -> Self {
+        self * rhs
+    }
+
+    #[inline]
+    fn half(self) -> Self {
+        self * 0.5
+    }
+
+    #[inline]
+    fn neg_sat(self) -> Self {
+        -self
+    }
+
+    fn from_ratio(num: i32, den: i32) -> Self {
+        num as f32 / den as f32
+    }
+
+    #[inline]
+    fn duty(self, max_duty: u16) -> u16 {
+        let x = (self + 0.5).clamp(0.0, 1.0);
+        ((x * max_duty as f32 + 0.5) as u16).min(max_duty - 1)
+    }
+}
+
+/// Quantities of phase a, b and c.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Abc<T> {
+    pub a: T,
+    pub b: T,
+    pub c: T,
+}
+
+/// Vector in the stationary frame, alpha along phase a.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct AlphaBeta<T> {
+    pub alpha: T,
+    pub beta: T,
+}
+
+/// Vector in the rotor frame, d along the rotor flux.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Dq<T> {
+    pub d: T,
+    pub q: T,
+}
+
+impl<T: Scalar> Add for AlphaBeta<T> {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            alpha: self.alpha.add_sat(rhs.alpha),
+            beta: self.beta.add_sat(rhs.beta),
+        }
+    }
+}
+
+impl<T: Scalar> Sub for AlphaBeta<T> {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            alpha: self.alpha.sub_sat(rhs.alpha),
+            beta: self.beta.sub_sat(rhs.beta),
+        }
+    }
+}
+
+impl<T: Scalar> Neg for AlphaBeta<T> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self {
+            alpha: self.alpha.neg_sat(),
+            beta: self.beta.neg_sat(),
+        }
+    }
+}
+
+impl<T: Scalar> Add for Dq<T> {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            d: self.d.add_sat(rhs.d),
+            q: self.q.add_sat(rhs.q),
+        }
+    }
+}
+
+impl<T: Scalar> Sub for Dq<T> {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            d: self.d.sub_sat(rhs.d),
+            q: self.q.sub_sat(rhs.q),
+        }
+    }
+}
+
+impl<T: Scalar> Neg for Dq<T> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self {
+            d: self.d.neg_sat(),
+            q: self.q.neg_sat(),
+        }
+    }
+}
+
+/// Clarke transform of two phases, assuming `a + b + c = 0`.
+#[inline]
+pub fn clarke<T: Scalar>(a: T, b: T) -> AlphaBeta<T> {
+    // (a + 2b) / sqrt(3), scaled first so no intermediate exceeds full scale
+    let b_scaled = b.mul_frac(T::FRAC_1_SQRT_3);
+    AlphaBeta {
+        alpha: a,
+        beta: a
+            .mul_frac(T::FRAC_1_SQRT_3)
+            .add_sat(b_scaled)
+            .add_sat(b_scaled),
+    }
+}
+
+/// Clarke transform of all three phases.
+///
+/// Common mode, e.g. an offset shared by all shunts, drops out.
+#[inline]
+pub fn clarke3<T: Scalar>(abc: Abc<T>) -> AlphaBeta<T> {
+    let a = abc.a.mul_frac(T::FRAC_1_3);
+    let b = abc.b.mul_frac(T::FRAC_1_3);
+    let c = abc.c.mul_frac(T::FRAC_1_3);
+    AlphaBeta {
+        // (2a - b - c) / 3
+        alpha: a.add_sat(a).sub_sat(b).sub_sat(c),
+        beta: abc
+            .b
+            .mul_frac(T::FRAC_1_SQRT_3)
+            .sub_sat(abc.c.mul_frac(T::FRAC_1_SQRT_3)),
+    }
+}
+
+/// Inverse Clarke transform, the phase quantities of a stationary vector.
+#[inline]
+pub fn inverse_clarke<T: Scalar>(v: AlphaBeta<T>) -> Abc<T> {
+    let alpha = v.alpha.half();
+    let beta = v.beta.mul_frac(T::FRAC_SQRT_3_2);
+    Abc {
+        a: v.alpha,
+        b: beta.sub_sat(alpha),
+        c: beta.neg_sat().sub_sat(alpha),
+    }
+}
+
+/// Park transform into the frame at the angle given by `sin` and `cos`.
+#[inline]
+pub fn park<T: Scalar>(v: AlphaBeta<T>, sin: T, cos: T) -> Dq<T> {
+    Dq {
+        d: v.alpha.mul_frac(cos).add_sat(v.beta.mul_frac(sin)),
+        q: v.beta.mul_frac(cos).sub_sat(v.alpha.mul_frac(sin)),
+    }
+}
+
+/// Inverse Park transform out of the frame at the angle given by `sin` and `cos`.
+#[inline]
+pub fn inverse_park<T: Scalar>(v: Dq<T>, sin: T, cos: T) -> AlphaBeta<T> {
+    AlphaBeta {
+        alpha: v.d.mul_frac(cos).sub_sat(v.q.mul_frac(sin)),
+        beta: v.d.mul_frac(sin).add_sat(v.q.mul_frac(cos)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    fn to_f64(x: impl Into<f64>, full_scale: f64) -> f64 {
+        x.into() / full_scale
+    }
+
+    /// Balanced currents with peak `amplitude` at electrical angle `theta`.
+    fn balanced(amplitude: f64, theta: f64) -> [f64; 3] {
+        [
+            amplitude * theta.cos(),
+            amplitude * (theta - 2.0 * PI / 3.0).cos(),
+            amplitude * (theta + 2.0 * PI / 3.0).cos(),
+        ]
+    }
+
+    fn reference_clarke(a: f64, b: f64, c: f64) -> (f64, f64) {
+        ((2.0 * a - b - c) / 3.0, (b - c) / 3f64.sqrt())
+    }
+
+    fn reference_park(alpha: f64, beta: f64, theta: f64) -> (f64, f64) {
+        (
+            alpha * theta.cos() + beta * theta.sin(),
+            -alpha * theta.sin() + beta * theta.cos(),
+        )
+    }
+
+    /// Runs a check over a grid of amplitudes and angles.
+    fn grid(mut check: impl FnMut(f64, f64)) {
+        for amplitude in [0.0, 0.1, 0.5, 0.9] {
+            for step in 0..72 {
+                check(amplitude, 2.0 * PI * step as f64 / 72.0);
+            }
+        }
+    }
+
+    #[test]
+    fn constants_round() {
+        assert_eq!(q15(0.5), 16_384);
+        assert_eq!(q15(-1.0), i16::MIN);
+        assert_eq!(q15(1.0), i16::MAX);
+        assert_eq!(q31(-0.25), -(1 << 29));
+        assert_eq!(i16::from_ratio(1_500, 3_000), 16_384);
+        assert_eq!(i32::from_ratio(-4_000, 3_000), i32::MIN);
+    }
+
+    #[test]
+    fn q15_multiply_saturates() {
+        assert_eq!(i16::MIN.mul_frac(i16::MIN), i16::MAX);
+        assert_eq!(q15(0.5).mul_frac(q15(-0.5)), q15(-0.25));
+        assert_eq!(i32::MIN.mul_frac(i32::MIN), i32::MAX);
+        assert_eq!(i16::MAX.add_sat(1), i16::MAX);
+    }
+
+    #[test]
+    fn clarke_matches_reference() {
+        grid(|amplitude, theta| {
+            let [a, b, c] = balanced(amplitude, theta);
+            let (alpha, beta) = reference_clarke(a, b, c);
+
+            let v = clarke(q15(a as f32), q15(b as f32));
+            assert!((to_f64(v.alpha, 32_768.0) - alpha).abs() < 1e-4);
+            assert!((to_f64(v.beta, 32_768.0) - beta).abs() < 2e-4);
+            let v = clarke3(Abc {
+                a: q15(a as f32),
+                b: q15(b as f32),
+                c: q15(c as f32),
+            });
+            assert!((to_f64(v.alpha, 32_768.0) - alpha).abs() < 2e-4);
+            assert!((to_f64(v.beta, 32_768.0) - beta).abs() < 2e-4);
+
+            let v = clarke(q31(a as f32), q31(b as f32));
+            // limited by the f32 inputs, not the arithmetic
+            assert!((to_f64(v.alpha, 2_147_483_648.0) - alpha).abs() < 1e-7);
+            assert!((to_f64(v.beta, 2_147_483_648.0) - beta).abs() < 1e-7);
+
+            let v = clarke(a as f32, b as f32);
+            assert!((v.alpha as f64 - alpha).abs() < 1e-6);
+            assert!((v.beta as f64 - beta).abs() < 1e-6);
+
+            // amplitude invariant
+            assert!(((alpha * alpha + beta * beta).sqrt() - amplitude).abs() < 1e-9);
+        });
+    }
+
+    #[test]
+    fn clarke3_rejects_common_mode() {
+        let v = clarke3(Abc {
+            a: q15(0.3),
+            b: q15(0.3),
+            c: q15(0.3),
+        });
+        assert!(v.alpha.abs() <= 1 && v.beta.abs() <= 1);
+    }
+
+    #[test]
+    fn inverse_clarke_matches_reference() {
+        grid(|amplitude, theta| {
+            let expected = balanced(amplitude, theta);
+            let (alpha, beta) = (amplitude * theta.cos(), amplitude * theta.sin());
+
+            let abc = inverse_clarke(AlphaBeta {
+                alpha: q15(alpha as f32),
+                beta: q15(beta as f32),
+            });
+            for (x, e) in [abc.a, abc.b, abc.c].iter().zip(expected) {
+                assert!((to_f64(*x, 32_768.0) - e).abs() < 1e-4);
+            }
+            let abc = inverse_clarke(AlphaBeta {
+                alpha: q31(alpha as f32),
+                beta: q31(beta as f32),
+            });
+            for (x, e) in [abc.a, abc.b, abc.c].iter().zip(expected) {
+                assert!((to_f64(*x, 2_147_483_648.0) - e).abs() < 1e-7);
+            }
+            let abc = inverse_clarke(AlphaBeta {
+                alpha: alpha as f32,
+                beta: beta as f32,
+            });
+            for (x, e) in [abc.a, abc.b, abc.c].iter().zip(expected) {
+                assert!((*x as f64 - e).abs() < 1e-6);
+            }
+        });
+    }
+
+    #[test]
+    fn park_matches_reference() {
+        grid(|amplitude, theta| {
+            // a vector 30 degrees ahead of the frame
+            let (alpha, beta) = (
+                amplitude * (theta + PI / 6.0).cos(),
+                amplitude * (theta + PI / 6.0).sin(),
+            );
+            let (d, q) = reference_park(alpha, beta, theta);
+            let (sin, cos) = (theta.sin() as f32, theta.cos() as f32);
+
+            let v = park(
+                AlphaBeta {
+                    alpha: q15(alpha as f32),
+                    beta: q15(beta as f32),
+                },
+                q15(sin),
+                q15(cos),
+            );
+            assert!((to_f64(v.d, 32_768.0) - d).abs() < 1e-4);
+            assert!((to_f64(v.q, 32_768.0) - q).abs() < 1e-4);
+
+            let v = park(
+                AlphaBeta {
+                    alpha: q31(alpha as f32),
+                    beta: q31(beta as f32),
+                },
+                q31(sin),
+                q31(cos),
+            );
+            assert!((to_f64(v.d, 2_147_483_648.0) - d).abs() < 1e-7);
+            assert!((to_f64(v.q, 2_147_483_648.0) - q).abs() < 1e-7);
+
+            let v = park(
+                AlphaBeta {
+                    alpha: alpha as f32,
+                    beta: beta as f32,
+                },
+                sin,
+                cos,
+            );
+            assert!((v.d as f64 - d).abs() < 1e-6);
+            assert!((v.q as f64 - q).abs() < 1e-6);
+        });
+    }
+
+    #[test]
+    fn park_round_trip() {
+        grid(|amplitude, theta| {
+            let v = Dq {
+                d: q15((0.6 * amplitude) as f32),
+                q: q15((-0.7 * amplitude) as f32),
+            };
+            let (sin, cos) = (q15(theta.sin() as f32), q15(theta.cos() as f32));
+            let back = park(inverse_park(v, sin, cos), sin, cos);
+            assert!((back.d - v.d).abs() <= 4 && (back.q - v.q).abs() <= 4);
+
+            let v = Dq {
+                d: 0.6 * amplitude as f32,
+                q: -0.7 * amplitude as f32,
+            };
+            let (sin, cos) = (theta.sin() as f32, theta.cos() as f32);
+            let back = park(inverse_park(v, sin, cos), sin, cos);
+            assert!((back.d - v.d).abs() < 1e-6 && (back.q - v.q).abs() < 1e-6);
+        });
+    }
+
+    #[test]
+    fn currents_to_dq() {
+        // balanced currents seen from a frame locked to them are constant
+        grid(|amplitude, theta| {
+            let [a, b, _] = balanced(amplitude, theta);
+            let v = clarke(q15(a as f32), q15(b as f32));
+            let dq = park(v, q15(theta.sin() as f32), q15(theta.cos() as f32));
+            assert!((to_f64(dq.d, 32_768.0) - amplitude).abs() < 3e-4);
+            assert!(to_f64(dq.q, 32_768.0).abs() < 3e-4);
+        });
+    }
+}
@@ -0,0 +1,329 @@
+//! Velocity loop cascaded on the current loop.
+//!
+//! Velocities are `i32` angle counts per second, 65536 counts to a turn as in
+//! [`crate::trig`], so a `u16` electrical angle advances by `velocity / pwm_hz` per
+//! period. The output is the `Iq` setpoint of a [`crate::current::CurrentLoop`] as a
+//! Q15 fraction of the full scale current.
+//!
+//! The loop is called every PWM period and runs once every `decimation` periods, the
+//! mechanical time constants are orders of magnitude slower than the electrical ones.
+
+use crate::pid::{Gains, Pid};
+
+/// Fractional bits of the feed-forward gains.
+const SHIFT: u32 = 24;
+
+/// Velocity of a number of turns per second.
+pub const fn turns_per_second(turns: f32) -> i32 {
+    (turns as f64 * 65_536.0) as i32
+}
+
+/// Velocity of a mechanical speed in rpm for a motor with `pole_pairs`, electrical.
+pub const fn rpm(rpm: f32, pole_pairs: u8) -> i32 {
+    (rpm as f64 * pole_pairs as f64 * 65_536.0 / 60.0) as i32
+}
+
+/// `Iq` added from the reference, ahead of the error.
+///
+/// `viscous` is Q15 current per count/s, e.g. friction and iron losses, and `inertia`
+/// Q15 current per count/s².
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FeedForward {
+    pub viscous: f32,
+    pub inertia: f32,
+}
+
+/// Velocity PI controller producing an `Iq` command.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VelocityLoop {
+    pid: Pid,
+    sample_hz: f32,
+    decimation: u16,
+    count: u16,
+    /// largest reference change per sample, i32::MAX when unlimited
+    max_step: i32,
+    torque_limit: i16,
+    setpoint: i32,
+    /// setpoint after the acceleration limit
+    reference: i32,
+    /// Q24, per count/s
+    viscous: i64,
+    /// Q24, per count/s of reference change in one sample
+    inertia: i64,
+    reset_on_reversal: bool,
+    output: i16,
+}
+
+impl VelocityLoop {
+    /// Loop run every `decimation` periods of a `pwm_hz` current loop, e.g. the
+    /// `Pwm3::get_frequency`, limited to the full scale current.
+    pub fn new(gains: Gains, pwm_hz: u32, decimation: u16) -> Self {
+        let decimation = decimation.max(1);
+        let sample_hz = pwm_hz as f32 / decimation as f32;
+        let mut velocity_loop = Self {
+            pid: Pid::new(gains, sample_hz),
+            sample_hz,
+            decimation,
+            count: 0,
+            max_step: i32::MAX,
+            torque_limit: 0,
+            setpoint: 0,
+            reference: 0,
+            viscous: 0,
+            inertia: 0,
+            reset_on_reversal: true,
+            output: 0,
+        };
+        velocity_loop.set_torque_limit(i16::MAX);
+        velocity_loop
+    }
+
+    /// Rate the loop runs at.
+    pub fn sample_hz(&self) -> f32 {
+        self.sample_hz
+    }
+
+    pub fn set_gains(&mut self, gains: Gains) {
+        self.pid.set_gains(gains);
+    }
+
+    /// Largest `Iq` command in either direction, Q15.
+    pub fn set_torque_limit(&mut self, limit: i16) {
+        self.torque_limit = limit.max(0);
+        self.pid
+            .set_limits(-self.torque_limit as i32, self.torque_limit as i32);
+    }
+
+    /// Largest change of the reference in counts/s², `None` to follow steps.
+    pub fn set_acceleration_limit(&mut self, acceleration: Option<u32>) {
+        self.max_step = match acceleration {
+            Some(acceleration) => ((acceleration as f32 / self.sample_hz) as i32).max(1),
+            None => i32::MAX,
+        };
+    }
+
+    pub fn set_feed_forward(&mut self, feed_forward: FeedForward) {
+        let fixed = |x: f32| {
+            let x = x as f64 * (1u64 << SHIFT) as f64;
+            (if x < 0.0 { x - 0.5 } else { x + 0.5 }) as i64
+        };
+        self.viscous = fixed(feed_forward.viscous);
+        self.inertia = fixed(feed_forward.inertia * self.sample_hz);
+    }
+
+    /// Clear the integral when the reference changes direction, on by default.
+    ///
+    /// Friction flips sign with the direction, so the integral built up against it
+    /// would otherwise push the wrong way until it has unwound.
+    pub fn set_reset_on_reversal(&mut self, reset: bool) {
+        self.reset_on_reversal = reset;
+    }
+
+    /// Target velocity, approached at the acceleration limit.
+    pub fn set_setpoint(&mut self, velocity: i32) {
+        self.setpoint = velocity;
+    }
+
+    pub fn setpoint(&self) -> i32 {
+        self.setpoint
+    }
+
+    /// Setpoint after the acceleration limit.
+    pub fn reference(&self) -> i32 {
+        self.reference
+    }
+
+    /// Call every PWM period with the measured velocity, returns a new `Iq` command on
+    /// the periods the loop runs.
+    #[inline]
+    pub fn update(&mut self, measurement: i32) -> Option<i16> {
+        self.tick().then(|| self.run(measurement))
+    }
+
+    /// Count a PWM period, true on the periods the loop is due.
+    #[inline]
+    pub fn tick(&mut self) -> bool {
+        self.count += 1;
+        if self.count < self.decimation {
+            return false;
+        }
+        self.count = 0;
+        true
+    }
+
+    /// Run the loop now, regardless of the decimation.
+    pub fn run(&mut self, measurement: i32) -> i16 {
+        let step = self
+            .setpoint
+            .saturating_sub(self.reference)
+            .clamp(-self.max_step, self.max_step);
+        let reference = self.reference.saturating_add(step);
+        if self.reset_on_reversal && reference.signum() * self.reference.signum() < 0 {
+            self.pid.reset(measurement);
+        }
+        self.reference = reference;
+
+        let feed_forward = (self.viscous * reference as i64 + self.inertia * step as i64) >> SHIFT;
+        let limit = self.torque_limit as i64;
+        let feed_forward = feed_forward.clamp(-limit, limit) as i32;
+        self.output = self.pid.update(reference, measurement, feed_forward) as i16;
+        self.output
+    }
+
+    /// Latest `Iq` command.
+    pub fn output(&self) -> i16 {
+        self.output
+    }
+
+    /// Take over at `velocity` from an `Iq` applied by someone else, e.g. a startup.
+    pub fn track(&mut self, iq: i16, velocity: i32) {
+        self.setpoint = velocity;
+        self.reference = velocity;
+        let feed_forward = ((self.viscous * velocity as i64) >> SHIFT) as i32;
+        self.pid.track(iq as i32, velocity, velocity, feed_forward);
+        self.output = self.pid.output() as i16;
+    }
+
+    /// Stop at rest, e.g. after a fault.
+    pub fn reset(&mut self, measurement: i32) {
+        self.pid.reset(measurement);
+        self.setpoint = 0;
+        self.reference = 0;
+        self.count = 0;
+        self.output = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::q15;
+
+    const PWM_HZ: u32 = 16_000;
+    const DECIMATION: u16 = 16;
+
+    /// Rotor with friction, `Iq` straight into torque.
+    struct Rotor {
+        velocity: f64,
+        /// counts/s² per Q15 unit of current
+        torque: f64,
+        /// counts/s² of coulomb friction
+        friction: f64,
+    }
+
+    impl Rotor {
+        fn step(&mut self, iq: i16, dt: f64) -> i32 {
+            let friction = self.friction * self.velocity.signum();
+            self.velocity += (iq as f64 * self.torque - friction) * dt;
+            self.velocity as i32
+        }
+    }
+
+    fn velocity_loop() -> VelocityLoop {
+        VelocityLoop::new(Gains::pi(1.0, 20.0), PWM_HZ, DECIMATION)
+    }
+
+    fn rotor() -> Rotor {
+        Rotor {
+            velocity: 0.0,
+            torque: 20.0,
+            friction: 50_000.0,
+        }
+    }
+
+    #[test]
+    fn runs_decimated() {
+        let mut velocity_loop = velocity_loop();
+        assert_eq!(velocity_loop.sample_hz(), 1_000.0);
+        let runs = (0..160).filter(|_| velocity_loop.update(0).is_some());
+        assert_eq!(runs.count(), 10);
+        assert_eq!(rpm(60.0, 7), turns_per_second(7.0));
+    }
+
+    #[test]
+    fn reaches_setpoint_within_limits() {
+        let mut velocity_loop = velocity_loop();
+        let limit = q15(0.5);
+        velocity_loop.set_torque_limit(limit);
+        velocity_loop.set_acceleration_limit(Some(200_000));
+        velocity_loop.set_setpoint(turns_per_second(10.0));
+        let mut rotor = rotor();
+        let mut velocity = 0;
+        let mut iq = 0;
+        for n in 0..96_000 {
+            if let Some(output) = velocity_loop.update(velocity) {
+                iq = output;
+                assert!(iq.abs() <= limit);
+                // the reference ramps at 200 counts per sample
+                if n < 16 * 1_000 {
+                    assert_eq!(velocity_loop.reference(), (n / 16 + 1) * 200);
+                }
+            }
+            velocity = rotor.step(iq, 1.0 / PWM_HZ as f64);
+        }
+        assert!(
+            (velocity - turns_per_second(10.0)).abs() < 500,
+            "{velocity}"
+        );
+    }
+
+    #[test]
+    fn feed_forward_leads_the_error() {
+        let mut velocity_loop = velocity_loop();
+        velocity_loop.set_gains(Gains::pi(0.0, 0.0));
+        velocity_loop.set_acceleration_limit(Some(100_000));
+        velocity_loop.set_feed_forward(FeedForward {
+            viscous: 0.001,
+            inertia: 0.01,
+        });
+        velocity_loop.set_setpoint(turns_per_second(10.0));
+        // reference step of 100 counts at 1 kHz: 100 * 1000 * 0.01 + 100 * 0.001
+        assert_eq!(velocity_loop.run(0), 1_000);
+        velocity_loop.set_acceleration_limit(None);
+        velocity_loop.run(0);
+        velocity_loop.set_setpoint(velocity_loop.reference());
+        assert_eq!(velocity_loop.run(0), 655);
+    }
+
+    #[test]
+    fn reversal_clears_the_integral() {
+        let mut velocity_loop = velocity_loop();
+        velocity_loop.set_setpoint(turns_per_second(0.1));
+        let mut rotor = rotor();
+        let mut velocity = 0;
+        for _ in 0..2_000 {
+            let iq = velocity_loop.run(velocity);
+            velocity = rotor.step(iq, 0.001);
+        }
+        // the integral holds the friction
+        assert!(velocity_loop.output() > 0);
+
+        velocity_loop.set_setpoint(-turns_per_second(0.1));
+        velocity_loop.run(velocity);
+        // the reference is past zero at once, only the error of this sample remains
+        let error = (velocity_loop.reference() - velocity) as f32;
+        let output = velocity_loop.output() as f32;
+        assert!((output - error * 1.02).abs() <= 1.0, "{output} {error}");
+
+        velocity_loop.set_reset_on_reversal(false);
+        velocity_loop.set_setpoint(turns_per_second(0.1));
+        for _ in 0..2_000 {
+            let iq = velocity_loop.run(velocity);
+            velocity = rotor.step(iq, 0.001);
+        }
+        velocity_loop.set_setpoint(-turns_per_second(0.1));
+        velocity_loop.run(velocity);
+        let error = (velocity_loop.reference() - velocity) as f32;
+        let output = velocity_loop.output() as f32;
+        assert!(output > error * 1.02 + 1_000.0, "{output} {error}");
+    }
+
+    #[test]
+    fn takes_over_without_a_step() {
+        let mut velocity_loop = velocity_loop();
+        velocity_loop.track(q15(0.2), 30_000);
+        assert_eq!(velocity_loop.run(30_000), q15(0.2));
+    }
+}
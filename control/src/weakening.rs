@@ -0,0 +1,274 @@
+//! Field weakening above the base speed.
+//!
+//! The back EMF grows with the speed until the current loop runs out of voltage and
+//! the speed saturates. A negative `Id` opposes the magnet flux and lowers it, so
+//! [`FieldWeakening`] integrates the headroom of the voltage magnitude into a negative
+//! `Id` as the magnitude nears the limit of the current loop, and gives it back below
+//! the base speed. This needs no motor parameters and follows the bus voltage, as the
+//! limit is a fraction of it.
+//!
+//! `Id` and `Iq` share the current circle: the weakening `Id` is added to the `d` of
+//! the reference, e.g. from a [`crate::mtpa::Mtpa`], and `Iq` gets what is left of the
+//! circle, so at high speed the torque gives way to the flux. Once the weakening `Id`
+//! is at its largest, the same integrator goes on to take `Iq` away, which is all that
+//! is left to lower the voltage.
+//! [`FieldWeakening::speed_range`] works out the speeds the motor reaches from its
+//! parameters.
+//!
+//! Currents are Q15 of the full scale current, voltages Q15 of the bus voltage, as in
+//! [`crate::current`].
+
+use crate::math::sqrt;
+use crate::pll::COUNTS_PER_RADIAN;
+use crate::svpwm::{circle_headroom, magnitude};
+use crate::transform::{q15, Dq};
+
+/// Motor and tuning of a [`FieldWeakening`].
+///
+/// `resistance` and the inductances are phase values in ohm and henry and
+/// `flux_linkage` the magnet flux in Vs, only used for [`FieldWeakening::speed_range`].
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FieldWeakeningConfig {
+    pub resistance: f32,
+    pub d_inductance: f32,
+    pub q_inductance: f32,
+    pub flux_linkage: f32,
+    pub full_scale_amps: f32,
+    /// fraction of the voltage limit the magnitude is held at, leaving some for the
+    /// current loop to act, e.g. 0.95
+    pub threshold: f32,
+    /// per unit `Id` per second per unit of voltage above the threshold
+    pub gain: f32,
+    /// largest weakening `Id`, Q15 of the full scale current
+    pub max_current: i16,
+    /// radius of the current circle, Q15 of the full scale current
+    pub current_limit: i16,
+}
+
+/// Speeds at the voltage limit, electrical angle counts per second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SpeedRange {
+    /// highest speed with the full current as torque and no weakening
+    pub base: i32,
+    /// highest speed without load at the largest weakening `Id`, `i32::MAX` if it
+    /// cancels the magnet flux
+    pub max: i32,
+}
+
+/// Voltage feedback field weakening, see the module documentation.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FieldWeakening {
+    config: FieldWeakeningConfig,
+    /// Q15 fraction of the voltage limit
+    threshold: i32,
+    /// Q16 `Id` per period per Q15 of voltage
+    gain: i64,
+    /// Q31 weakening `Id`, zero or negative, beyond the largest `Id` the `Iq` taken
+    /// away
+    current: i64,
+}
+
+impl FieldWeakening {
+    /// Controller run every period of a `sample_hz` current loop.
+    pub fn new(config: FieldWeakeningConfig, sample_hz: u32) -> Self {
+        Self {
+            config,
+            threshold: q15(config.threshold) as i32,
+            gain: (config.gain as f64 / sample_hz as f64 * 65_536.0 + 0.5) as i64,
+            current: 0,
+        }
+    }
+
+    /// Current setpoint for the `reference` with the field weakened against the
+    /// `voltage` of the last period and the `limit` of the current loop, see
+    /// [`crate::current::CurrentLoop::voltage`] and [`crate::current::CurrentLoop::limit`].
+    pub fn update(&mut self, reference: Dq<i16>, voltage: Dq<i16>, limit: i16) -> Dq<i16> {
+        let target = (limit as i32 * self.threshold) >> 15;
+        let error = target - magnitude(voltage) as i32;
+        let radius = self.config.current_limit;
+        let floor = -((self.config.max_current as i64 + radius as i64) << 16);
+        self.current = (self.current + self.gain * error as i64).max(floor).min(0);
+
+        let d = (reference.d as i32 + self.weakening() as i32).max(-(radius as i32)) as i16;
+        // beyond the largest Id the torque goes
+        let taken = (-(self.config.max_current as i32) - self.total()).max(0);
+        let headroom = (circle_headroom(d, radius) as i32 - taken).max(0) as i16;
+        Dq {
+            d: d.min(radius),
+            q: reference.q.max(-headroom).min(headroom),
+        }
+    }
+
+    /// Weakening `Id` added to the reference, Q15.
+    pub fn weakening(&self) -> i16 {
+        self.total().max(-self.config.max_current as i32) as i16
+    }
+
+    /// Weakening `Id` and `Iq` taken away, Q15.
+    fn total(&self) -> i32 {
+        ((self.current + (1 << 15)) >> 16) as i32
+    }
+
+    /// Give the field back at once, e.g. after the stage was switched off.
+    pub fn reset(&mut self) {
+        self.current = 0;
+    }
+
+    /// Speeds reached at `bus_volts` with the voltage held at the threshold of `limit`,
+    /// the radius of the voltage circle as a Q15 fraction of the bus.
+    pub fn speed_range(&self, bus_volts: f32, limit: i16) -> SpeedRange {
+        let config = &self.config;
+        let volts = bus_volts * limit as f32 / 32_768.0 * config.threshold;
+        let amps = |x: i16| x as f32 / 32_768.0 * config.full_scale_amps;
+        let (r, flux) = (config.resistance, config.flux_linkage);
+        let counts = |omega: f32| (omega * COUNTS_PER_RADIAN).min(i32::MAX as f32) as i32;
+
+        // |(R id - ω Lq iq, R iq + ω (ψ + Ld id))| = V with id = 0, quadratic in ω
+        let iq = amps(config.current_limit);
+        let a = (config.q_inductance * iq) * (config.q_inductance * iq) + flux * flux;
+        let b = r * iq * flux;
+        let c = r * iq * r * iq - volts * volts;
+        let base = (sqrt(b * b - a * c) - b) / a;
+
+        // and with iq = 0 at the largest weakening current
+        let id = -amps(config.max_current);
+        let remaining = flux + config.d_inductance * id;
+        let max = if remaining > 0.0 {
+            counts(sqrt(volts * volts - r * id * r * id) / remaining)
+        } else {
+            i32::MAX
+        };
+        SpeedRange {
+            base: counts(base.max(0.0)),
+            max,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{
+        current_loop, periods, Motor, BUS_VOLTS, D_INDUCTANCE, FLUX, FULL_SCALE_AMPS, PWM_HZ,
+        Q_INDUCTANCE, RESISTANCE,
+    };
+    use crate::svpwm::LINEAR_LIMIT;
+
+    fn weakening() -> FieldWeakening {
+        let config = FieldWeakeningConfig {
+            resistance: RESISTANCE as f32,
+            d_inductance: D_INDUCTANCE as f32,
+            q_inductance: Q_INDUCTANCE as f32,
+            flux_linkage: FLUX as f32,
+            full_scale_amps: FULL_SCALE_AMPS as f32,
+            threshold: 0.95,
+            gain: 1_000.0,
+            max_current: q15(0.5),
+            current_limit: q15(0.6),
+        };
+        FieldWeakening::new(config, PWM_HZ)
+    }
+
+    /// Motor turned at `hz(t)` electrical under field oriented control with a torque
+    /// current of 0.5 through the field weakening, calls `check` every period with the
+    /// time, the setpoint, the measured currents and the voltage.
+    fn run(
+        weakening: &mut FieldWeakening,
+        hz: impl Fn(f64) -> f64,
+        seconds: f64,
+        mut check: impl FnMut(f64, Dq<i16>, Dq<i16>, Dq<i16>),
+    ) {
+        let mut current_loop = current_loop(Q_INDUCTANCE, 1_000.0);
+        let mut motor = Motor::salient(0.0);
+        for n in 0..periods(seconds) {
+            let t = n as f64 / PWM_HZ as f64;
+            let setpoint = weakening.update(
+                Dq { d: 0, q: q15(0.5) },
+                current_loop.voltage(),
+                current_loop.limit(),
+            );
+            current_loop.set_setpoint(setpoint);
+            let voltage = current_loop.update(motor.currents(), motor.angle());
+            check(t, setpoint, current_loop.current(), current_loop.voltage());
+            motor.step(voltage, hz(t));
+        }
+    }
+
+    #[test]
+    fn holds_the_current_above_base_speed() {
+        // up to about three times the base speed, after the step of Iq at the start
+        let mut weakening = weakening();
+        let limit = q15(LINEAR_LIMIT) as i32;
+        run(
+            &mut weakening,
+            |t| 1_500.0 * t,
+            0.4,
+            |t, setpoint, current, voltage| {
+                if t > 0.02 && t < 0.1 {
+                    assert_eq!(setpoint.d, 0);
+                }
+                assert!(magnitude(voltage) as i32 <= limit);
+                assert!(magnitude(setpoint) <= q15(0.6) + 1);
+                if t > 0.05 {
+                    // the current loop keeps up
+                    let error = magnitude(setpoint - current);
+                    assert!(error < q15(0.02), "{t}: {setpoint:?} {current:?}");
+                }
+            },
+        );
+        assert!(
+            weakening.weakening() < -q15(0.2),
+            "{}",
+            weakening.weakening()
+        );
+    }
+
+    #[test]
+    fn predicts_the_base_speed() {
+        let mut weakening = weakening();
+        let range = weakening.speed_range(BUS_VOLTS as f32, q15(LINEAR_LIMIT));
+        // the full current of 0.6 at the base speed
+        let base = range.base as f64 / 65_536.0;
+        assert!((215.0..225.0).contains(&base), "{base}");
+        // 3 mVs of the 4 weakened
+        let max = range.max as f64 / 65_536.0;
+        assert!((2_000.0..2_100.0).contains(&max), "{max}");
+
+        // with 0.5 as torque the weakening starts a little above it
+        let mut start = None;
+        run(
+            &mut weakening,
+            |t| 1_000.0 * t,
+            0.4,
+            |t, setpoint, _, _| {
+                if t > 0.02 && setpoint.d < 0 && start.is_none() {
+                    start = Some(1_000.0 * t);
+                }
+            },
+        );
+        let start = start.unwrap();
+        assert!(1.1 * base < start && start < 1.25 * base, "{start}");
+    }
+
+    #[test]
+    fn torque_gives_way_to_the_field() {
+        let mut weakening = weakening();
+        let saturated = Dq { d: 0, q: 30_000 };
+        for _ in 0..PWM_HZ {
+            weakening.update(Dq { d: 0, q: -q15(0.6) }, saturated, 18_000);
+        }
+        assert_eq!(weakening.weakening(), -q15(0.5));
+        // first the flux, then all of the torque
+        let setpoint = weakening.update(Dq { d: 0, q: -q15(0.6) }, saturated, 18_000);
+        assert_eq!(setpoint, Dq { d: -q15(0.5), q: 0 });
+
+        // and the field comes back with the headroom
+        for _ in 0..PWM_HZ {
+            weakening.update(Dq { d: 0, q: q15(0.6) }, Dq::default(), 18_000);
+        }
+        assert_eq!(weakening.weakening(), 0);
+    }
+}
@@ -0,0 +1,168 @@
+//! Self tests of the current sense chain.
+//!
+//! Run [`check_offsets`] after `Isense::calibrate_offsets` with the stage idle, then
+//! [`check_response`] with the stage enabled, and feed a [`RateWatchdog`] from the
+//! application while the control loop runs.
+
+use embassy_stm32::timer::{GeneralInstance4Channel, TimerChannel};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::isense::{Instance, Isense, ADC_CODES, MAX_INJECTED};
+use crate::pwm::{Phase, Pwm3};
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Diagnostic {
+    /// Offset outside the plausible band, e.g. a missing amplifier reference.
+    Offset { rank: usize, raw: u16 },
+    /// Reading at a rail, a disconnected input or a saturated amplifier.
+    Saturated { rank: usize, raw: u16 },
+    /// Reading did not follow the test voltage vector.
+    Stuck { rank: usize, delta: u16 },
+    /// Injected conversions stopped or run at the wrong rate.
+    ConversionRate { expected_hz: u32, measured_hz: u32 },
+}
+
+/// Thresholds in raw ADC codes.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Limits {
+    /// Allowed distance of a calibrated offset from the configured one.
+    pub offset_tolerance: u16,
+    /// Readings this close to either rail count as saturated.
+    pub rail_margin: u16,
+    /// Smallest change a channel must show under the test vector.
+    pub min_response: u16,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            offset_tolerance: 200,
+            rail_margin: 16,
+            min_response: 20,
+        }
+    }
+}
+
+fn saturated(raw: u16, limits: &Limits) -> bool {
+    raw <= limits.rail_margin || raw as i64 >= ADC_CODES - 1 - limits.rail_margin as i64
+}
+
+/// Check the calibrated offsets against the configured one.
+pub fn check_offsets<T: Instance>(
+    isense: &Isense<'_, T>,
+    limits: &Limits,
+) -> Result<(), Diagnostic> {
+    let nominal = isense.config().offset;
+    for rank in 0..isense.injected_len() {
+        let raw = isense.offset(rank);
+        if saturated(raw, limits) {
+            return Err(Diagnostic::Saturated { rank, raw });
+        }
+        if raw.abs_diff(nominal) > limits.offset_tolerance {
+            return Err(Diagnostic::Offset { rank, raw });
+        }
+    }
+    Ok(())
+}
+
+/// Apply `duty` to each phase in turn, the others held low, and check that every
+/// channel moves away from its offset.
+///
+/// Each vector is held for `settle` before converting, long enough for the current to
+/// build up in the winding. The phases and the driver must be enabled; all duties are
+/// zero on return.
+pub async fn check_response<
+    T: Instance,
+    P: GeneralInstance4Channel,
+    A: TimerChannel,
+    B: TimerChannel,
+    C: TimerChannel,
+>(
+    isense: &mut Isense<'_, T>,
+    pwm: &mut Pwm3<'_, P, A, B, C>,
+    duty: u16,
+    settle: Duration,
+    limits: &Limits,
+) -> Result<(), Diagnostic> {
+    let mut deltas = [0u16; MAX_INJECTED];
+    let mut result = Ok(());
+    'vectors: for phase in [Phase::A, Phase::B, Phase::C] {
+        for other in [Phase::A, Phase::B, Phase::C] {
+            pwm.set_duty(other, if other == phase { duty } else { 0 });
+        }
+        Timer::after(settle).await;
+        let raw = isense.convert().await;
+        for (rank, raw) in raw.iter().enumerate() {
+            if saturated(*raw, limits) {
+                result = Err(Diagnostic::Saturated { rank, raw: *raw });
+                break 'vectors;
+            }
+            deltas[rank] = deltas[rank].max(raw.abs_diff(isense.offset(rank)));
+        }
+    }
+    for phase in [Phase::A, Phase::B, Phase::C] {
+        pwm.set_duty(phase, 0);
+    }
+    result?;
+
+    match deltas[..isense.injected_len()]
+        .iter()
+        .position(|delta| *delta < limits.min_response)
+    {
+        Some(rank) => Err(Diagnostic::Stuck {
+            rank,
+            delta: deltas[rank],
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Checks that injected conversions keep completing at the PWM triggered rate.
+pub struct RateWatchdog {
+    expected_hz: u32,
+    tolerance_percent: u32,
+    last: Option<(Instant, u32)>,
+}
+
+impl RateWatchdog {
+    pub fn new(expected_hz: u32, tolerance_percent: u32) -> Self {
+        Self {
+            expected_hz,
+            tolerance_percent,
+            last: None,
+        }
+    }
+
+    /// Compare the conversions since the last check, pass `Isense::injected_count`.
+    ///
+    /// Call at a steady interval of a few milliseconds or more, shorter intervals are
+    /// skipped until enough time has passed.
+    pub fn check(&mut self, count: u32) -> Result<(), Diagnostic> {
+        let now = Instant::now();
+        let Some((since, start)) = self.last else {
+            self.last = Some((now, count));
+            return Ok(());
+        };
+        let elapsed_us = (now - since).as_micros();
+        if elapsed_us < 1_000 {
+            return Ok(());
+        }
+        self.last = Some((now, count));
+
+        let measured_hz = (count.wrapping_sub(start) as u64 * 1_000_000 / elapsed_us) as u32;
+        let tolerance = self.expected_hz * self.tolerance_percent / 100;
+        if measured_hz.abs_diff(self.expected_hz) > tolerance {
+            Err(Diagnostic::ConversionRate {
+                expected_hz: self.expected_hz,
+                measured_hz,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Forget the last check, e.g. after the loop was stopped on purpose.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
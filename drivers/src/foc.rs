@@ -0,0 +1,277 @@
+//! Field oriented current control on `Pwm3`.
+//!
+//! [`CurrentControl::update`] runs once per PWM period from the injected results,
+//! normally in [`crate::isense::ControlLoop::on_injected`]: the phase currents are
+//! reconstructed with the duties that were applied while they were sampled, regulated
+//! in the rotor frame by a [`CurrentLoop`] and the new duties are written to the timer.
+//! They take effect at the next update event, the usual one period delay.
+//!
+//! Without a position sensor the period is split into [`CurrentControl::measure`] and
+//! [`CurrentControl::regulate`], with an [`Estimator`] such as a
+//! [`control::flux::FluxObserver`] or a [`control::smo::SlidingModeObserver`]
+//! estimating the angle in between from the currents and the
+//! [`CurrentControl::applied_voltage`], as [`CurrentControl::update_sensorless`] does.
+//! The estimators, [`control::startup::Startup`] and [`FieldWeakening`] run every
+//! period and take the sample rate from `Pwm3::get_frequency`.
+//! Down to standstill [`CurrentControl::update_injected`] runs an [`Hfi`] instead,
+//! which injects on the d axis and blends into a back EMF estimator above a speed.
+//!
+//! Above base speed [`CurrentControl::set_current_weakened`] passes the setpoint
+//! through a [`FieldWeakening`] fed with the voltage the loop applied, and
+//! [`CurrentControl::set_torque_mtpa`] splits a torque into `Id` and `Iq` with an
+//! [`Mtpa`] first.
+
+use control::current::CurrentLoop;
+use control::estimator::Estimator;
+use control::hfi::Hfi;
+use control::mtpa::Mtpa;
+use control::pid::Gains;
+use control::svpwm::duties;
+use control::transform::{clarke3, AlphaBeta, Dq, Scalar};
+use control::velocity::VelocityLoop;
+use control::weakening::{FieldWeakening, SpeedRange};
+use embassy_stm32::timer::{GeneralInstance4Channel, TimerChannel};
+
+use crate::isense::Calibration;
+use crate::phase_current::{PhaseCurrents, Reconstruction};
+use crate::pwm::Pwm3;
+
+pub struct CurrentControl {
+    calibration: Calibration,
+    reconstruction: Reconstruction,
+    /// milliamps of a per unit current
+    full_scale_ma: i32,
+    current_loop: CurrentLoop,
+    /// duties and voltage applied during the latest samples
+    duties: [u16; 3],
+    voltage: AlphaBeta<i16>,
+    phase_currents: PhaseCurrents,
+}
+
+impl CurrentControl {
+    /// Controller run at `pwm_hz` with the same PI gains on both axes, see
+    /// `control::current::tuned_gains`.
+    ///
+    /// `full_scale_ma` is the per unit base of the currents, e.g.
+    /// `CurrentSenseConfig::full_scale_ma`, and `max_duty` the `Pwm3::get_max_duty`
+    /// the phases idle at half of.
+    pub fn new(
+        calibration: Calibration,
+        reconstruction: Reconstruction,
+        full_scale_ma: i32,
+        gains: Gains,
+        pwm_hz: u32,
+        max_duty: u16,
+    ) -> Self {
+        Self {
+            calibration,
+            reconstruction,
+            full_scale_ma,
+            current_loop: CurrentLoop::new(gains, pwm_hz as f32),
+            duties: [max_duty / 2; 3],
+            voltage: AlphaBeta::default(),
+            phase_currents: PhaseCurrents::default(),
+        }
+    }
+
+    /// Use new offsets, e.g. after `Isense::calibrate_offsets`.
+    pub fn set_calibration(&mut self, calibration: Calibration) {
+        self.calibration = calibration;
+    }
+
+    /// `Id` and `Iq` setpoints in milliamps.
+    pub fn set_current(&mut self, d_ma: i32, q_ma: i32) {
+        self.current_loop.set_setpoint(Dq {
+            d: i16::from_ratio(d_ma, self.full_scale_ma),
+            q: i16::from_ratio(q_ma, self.full_scale_ma),
+        });
+    }
+
+    /// `Id` and `Iq` setpoints as Q15 fractions of the full scale current, e.g. the
+    /// output of a [`Startup`].
+    pub fn set_current_per_unit(&mut self, current: Dq<i16>) {
+        self.current_loop.set_setpoint(current);
+    }
+
+    /// `Iq` setpoint as a Q15 fraction of the full scale current, e.g. from a
+    /// [`VelocityLoop`], keeping the `Id` setpoint.
+    pub fn set_torque(&mut self, iq: i16) {
+        let setpoint = self.current_loop.setpoint();
+        self.current_loop.set_setpoint(Dq {
+            d: setpoint.d,
+            q: iq,
+        });
+    }
+
+    /// `Id` and `Iq` setpoints from a `reference` in Q15 per unit with the field
+    /// weakened by `weakening` against the voltage of the latest period, call every
+    /// period before the update.
+    pub fn set_current_weakened(&mut self, weakening: &mut FieldWeakening, reference: Dq<i16>) {
+        let setpoint = weakening.update(
+            reference,
+            self.current_loop.voltage(),
+            self.current_loop.limit(),
+        );
+        self.current_loop.set_setpoint(setpoint);
+    }
+
+    /// Setpoints with the least current for a `torque`, the `Iq` that makes it with
+    /// `Id` zero as for [`Self::set_torque`], weakened as in
+    /// [`Self::set_current_weakened`].
+    pub fn set_torque_mtpa(&mut self, mtpa: &Mtpa, weakening: &mut FieldWeakening, torque: i16) {
+        self.set_current_weakened(weakening, mtpa.reference(torque));
+    }
+
+    /// Speeds reached with `weakening` at `bus_volts` and the voltage limit of the
+    /// current loop.
+    pub fn speed_range(&self, weakening: &FieldWeakening, bus_volts: f32) -> SpeedRange {
+        weakening.speed_range(bus_volts, self.current_loop.limit())
+    }
+
+    /// Measured `Id` and `Iq` in milliamps.
+    pub fn current(&self) -> Dq<i32> {
+        let current = self.current_loop.current();
+        let milliamps = |x: i16| ((x as i64 * self.full_scale_ma as i64) >> 15) as i32;
+        Dq {
+            d: milliamps(current.d),
+            q: milliamps(current.q),
+        }
+    }
+
+    /// Latest reconstructed phase currents.
+    pub fn phase_currents(&self) -> PhaseCurrents {
+        self.phase_currents
+    }
+
+    /// The underlying controller, for limits, gains and feed-forward.
+    pub fn current_loop(&mut self) -> &mut CurrentLoop {
+        &mut self.current_loop
+    }
+
+    /// Voltage applied while the latest currents developed, Q15 of the bus voltage, for
+    /// an observer.
+    pub fn applied_voltage(&self) -> AlphaBeta<i16> {
+        self.voltage
+    }
+
+    /// Run one period with the raw injected results and the electrical angle.
+    pub fn update<T, A, B, C>(&mut self, pwm: &mut Pwm3<'_, T, A, B, C>, raw: &[u16], angle: u16)
+    where
+        T: GeneralInstance4Channel,
+        A: TimerChannel,
+        B: TimerChannel,
+        C: TimerChannel,
+    {
+        let currents = self.measure(raw);
+        self.regulate(pwm, currents, angle);
+    }
+
+    /// Run one period on the angle of `estimator`, updated from this period's currents.
+    pub fn update_sensorless<T, A, B, C>(
+        &mut self,
+        pwm: &mut Pwm3<'_, T, A, B, C>,
+        raw: &[u16],
+        estimator: &mut impl Estimator,
+    ) where
+        T: GeneralInstance4Channel,
+        A: TimerChannel,
+        B: TimerChannel,
+        C: TimerChannel,
+    {
+        let currents = self.measure(raw);
+        estimator.update(currents, self.voltage);
+        self.regulate(pwm, currents, estimator.angle());
+    }
+
+    /// Run one period on the angle of `hfi`: its injection is added to the d
+    /// feed-forward, the current loop regulates the currents without the injection
+    /// response, and while the magnet polarity is detected its probe current replaces
+    /// the `Id` setpoint. The setpoint and feed-forward of the caller are only changed
+    /// for this period.
+    pub fn update_injected<T, A, B, C, E>(
+        &mut self,
+        pwm: &mut Pwm3<'_, T, A, B, C>,
+        raw: &[u16],
+        hfi: &mut Hfi<E>,
+    ) where
+        T: GeneralInstance4Channel,
+        A: TimerChannel,
+        B: TimerChannel,
+        C: TimerChannel,
+        E: Estimator,
+    {
+        let currents = self.measure(raw);
+        hfi.update(currents, self.voltage);
+        let setpoint = self.current_loop.setpoint();
+        let feed_forward = self.current_loop.feed_forward();
+        if let Some(d) = hfi.probe_current() {
+            self.current_loop.set_setpoint(Dq { d, ..setpoint });
+        }
+        self.current_loop.set_feed_forward(Dq {
+            d: feed_forward.d.saturating_add(hfi.injection()),
+            ..feed_forward
+        });
+        self.regulate(pwm, hfi.fundamental(), hfi.angle());
+        self.current_loop.set_setpoint(setpoint);
+        self.current_loop.set_feed_forward(feed_forward);
+    }
+
+    /// First half of [`Self::update`], the stationary currents in Q15 per unit from the
+    /// raw injected results, for an observer to estimate the angle from before
+    /// [`Self::regulate`].
+    pub fn measure(&mut self, raw: &[u16]) -> AlphaBeta<i16> {
+        let mut milliamps = [0; crate::isense::MAX_INJECTED];
+        for (rank, (ma, raw)) in milliamps.iter_mut().zip(raw).enumerate() {
+            *ma = self.calibration.milliamps(rank, *raw);
+        }
+        self.phase_currents = self.reconstruction.reconstruct(&milliamps, self.duties);
+        clarke3(self.phase_currents.per_unit::<i16>(self.full_scale_ma))
+    }
+
+    /// Second half of [`Self::update`], regulate the `currents` at the electrical angle
+    /// and write the new duties.
+    pub fn regulate<T, A, B, C>(
+        &mut self,
+        pwm: &mut Pwm3<'_, T, A, B, C>,
+        currents: AlphaBeta<i16>,
+        angle: u16,
+    ) where
+        T: GeneralInstance4Channel,
+        A: TimerChannel,
+        B: TimerChannel,
+        C: TimerChannel,
+    {
+        self.voltage = self.current_loop.update(currents, angle);
+        self.duties = duties(self.voltage, pwm.get_max_duty());
+        pwm.set_duties(self.duties);
+    }
+
+    /// Idle the phases at half duty and clear the integrators.
+    pub fn stop<T, A, B, C>(&mut self, pwm: &mut Pwm3<'_, T, A, B, C>)
+    where
+        T: GeneralInstance4Channel,
+        A: TimerChannel,
+        B: TimerChannel,
+        C: TimerChannel,
+    {
+        self.current_loop.reset();
+        self.voltage = AlphaBeta::default();
+        self.duties = [pwm.get_max_duty() / 2; 3];
+        pwm.set_duties(self.duties);
+    }
+}
+
+/// Velocity loop run every `decimation` periods of the frequency configured on `pwm`.
+pub fn velocity_loop<T, A, B, C>(
+    pwm: &Pwm3<'_, T, A, B, C>,
+    gains: Gains,
+    decimation: u16,
+) -> VelocityLoop
+where
+    T: GeneralInstance4Channel,
+    A: TimerChannel,
+    B: TimerChannel,
+    C: TimerChannel,
+{
+    VelocityLoop::new(gains, pwm.get_frequency().0, decimation)
+}
@@ -1,15 +1,77 @@
+use core::cell::Cell;
 use core::future::poll_fn;
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
 use core::task::Poll;
-use embassy_stm32::adc::{AdcChannel, AnyAdcChannel};
-use embassy_stm32::gpio::Pin;
-use embassy_stm32::gpio::{AfType, Flex, OutputType, Speed};
+use embassy_stm32::adc::{AdcChannel, AnyAdcChannel, RxDma};
+use embassy_stm32::dma::{ReadableRingBuffer, Transfer, TransferOptions};
+use embassy_stm32::interrupt;
 use embassy_stm32::interrupt::typelevel::Interrupt;
-use embassy_stm32::peripherals::ADC1;
+use embassy_stm32::peripherals::{ADC1, ADC2};
 use embassy_stm32::time::Hertz;
-use embassy_stm32::{adc::SampleTime, pac, rcc, Peri};
-use embassy_stm32::{interrupt, PeripheralType};
+use embassy_stm32::{adc::SampleTime, rcc, Peri};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::waitqueue::AtomicWaker;
+use heapless::Vec;
+
+use crate::pwm::Trip;
+
+#[cfg(feature = "f1")]
+mod f1;
+#[cfg(feature = "f1")]
+use f1 as family;
+#[cfg(feature = "f4")]
+mod f4;
+#[cfg(feature = "f4")]
+use f4 as family;
+#[cfg(feature = "g4")]
+mod g4;
+#[cfg(feature = "g4")]
+use g4 as family;
+
+/// Maximum number of channels in the injected sequence.
+pub const MAX_INJECTED: usize = 4;
+
+/// Maximum number of channels in the regular sequence.
+pub const MAX_REGULAR: usize = 16;
+
+/// Number of codes of the 12 bit converter.
+pub(crate) const ADC_CODES: i64 = 1 << 12;
+
+use family::{INTERNAL_SAMPLE_US, TEMPERATURE_CHANNEL, VREFINT_CHANNEL};
+
+/// Factory calibration of the internal channels, converted at `cal_mv` and 30°C.
+#[cfg(any(feature = "f4", feature = "g4"))]
+struct FactoryCalibration {
+    cal_mv: u32,
+    vrefint: usize,
+    /// sensor reading at 30°C and at `ts_cal2_mc`
+    ts_cal: [usize; 2],
+    ts_cal2_mc: i32,
+}
+
+#[cfg(any(feature = "f4", feature = "g4"))]
+impl FactoryCalibration {
+    fn read(address: usize) -> u16 {
+        // system memory, programmed during production and always readable
+        unsafe { core::ptr::read_volatile(address as *const u16) }
+    }
+
+    fn vdda_mv(&self, vrefint: u16) -> u16 {
+        (self.cal_mv * Self::read(self.vrefint) as u32 / vrefint as u32) as u16
+    }
+
+    /// Interpolate between the two calibration points, after scaling `raw` to `cal_mv`.
+    fn temperature_mc(&self, raw: u16, vdda_mv: u16) -> i32 {
+        let raw = raw as i64 * vdda_mv as i64 / self.cal_mv as i64;
+        let [cal1, cal2] = self.ts_cal.map(|address| Self::read(address) as i64);
+        (30_000 + (self.ts_cal2_mc as i64 - 30_000) * (raw - cal1) / (cal2 - cal1)) as i32
+    }
+}
+
+/// Injected conversions between samples of the internal channels.
+pub const DEFAULT_INTERNAL_INTERVAL: u16 = 1024;
 
 #[allow(unused)]
 pub(crate) fn blocking_delay_us(us: u32) {
@@ -20,6 +82,16 @@ pub(crate) fn blocking_delay_us(us: u32) {
 
 pub struct State {
     pub waker: AtomicWaker,
+    /// last known analog supply, shared with [`RegularScan`] readers
+    vdda_mv: AtomicU16,
+    /// regular group owned by an [`AdcStream`]
+    streaming: AtomicBool,
+    /// injected results go to the [`ControlLoop`] instead of `convert`
+    control_loop: AtomicBool,
+    /// completed injected sequences, wraps
+    injected_count: AtomicU32,
+    trip: Mutex<CriticalSectionRawMutex, Cell<Option<Trip>>>,
+    overcurrent: Mutex<CriticalSectionRawMutex, Cell<Option<Overcurrent>>>,
 }
 
 impl State {
@@ -27,6 +99,12 @@ impl State {
     pub const fn new() -> Self {
         Self {
             waker: AtomicWaker::new(),
+            vdda_mv: AtomicU16::new(0),
+            streaming: AtomicBool::new(false),
+            control_loop: AtomicBool::new(false),
+            injected_count: AtomicU32::new(0),
+            trip: Mutex::new(Cell::new(None)),
+            overcurrent: Mutex::new(Cell::new(None)),
         }
     }
 }
@@ -39,109 +117,461 @@ pub trait Instance: embassy_stm32::PeripheralType + embassy_stm32::rcc::RccPerip
     type Interrupt: embassy_stm32::interrupt::typelevel::Interrupt;
 }
 
+/// Start of conversion source for the injected group.
+///
+/// Each family maps these onto its own JEXTSEL codes; hardware triggers fire on the
+/// rising edge.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum InjectedTrigger {
+    Tim1Trgo,
+    Tim1Cc4,
+    Tim2Trgo,
+    Tim2Cc1,
+    Tim3Cc4,
+    Tim4Trgo,
+    Exti15,
+    /// Software start, conversions are started by [`Isense::convert`].
+    Software,
+}
+
+/// Start of conversion source for the regular group.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RegularTrigger {
+    Tim1Cc1,
+    Tim1Cc2,
+    Tim1Cc3,
+    Tim2Cc2,
+    /// TIM3 trigger out, e.g. the `Pwm3` update to sample once per PWM period.
+    Tim3Trgo,
+    Tim4Cc4,
+    Exti11,
+    /// Software start once, then back to back conversions.
+    Continuous,
+}
+
+/// Direction of the amplifier output relative to the phase current.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Polarity {
+    /// Current flowing into the motor raises the amplifier output.
+    Normal,
+    /// Current flowing into the motor lowers the amplifier output.
+    Inverted,
+}
+
+/// Analog front end of the phase current shunts.
+///
+/// Used to turn raw injected results into physical units. For the FOC8313 board
+/// the measurement in `adc_regular_hal.rs` (+196mV at the ADC for +0.65A) gives a
+/// 6 mOhm shunt behind a gain of 50, biased to mid supply.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct CurrentSenseConfig {
+    /// Shunt resistance in micro ohms.
+    pub shunt_micro_ohms: u32,
+    /// Voltage gain of the shunt amplifier.
+    pub gain: u16,
+    /// ADC reference voltage (VDDA) in millivolts.
+    pub vref_mv: u16,
+    pub polarity: Polarity,
+    /// Raw reading at zero current.
+    pub offset: u16,
+}
+
+impl CurrentSenseConfig {
+    /// Milliamps per ADC code in Q16.16 for a given reference voltage, signed by polarity.
+    pub const fn milliamps_per_code_q16(&self, vref_mv: u16) -> i32 {
+        let num = ((vref_mv as i64) * 1_000_000) << 16;
+        let den = ADC_CODES * self.gain as i64 * self.shunt_micro_ohms as i64;
+        let scale = (num / den) as i32;
+        match self.polarity {
+            Polarity::Normal => scale,
+            Polarity::Inverted => -scale,
+        }
+    }
+
+    /// Convert a raw reading to milliamps using the nominal reference voltage.
+    pub const fn to_milliamps(&self, raw: u16) -> i32 {
+        milliamps(raw, self.offset, self.milliamps_per_code_q16(self.vref_mv))
+    }
+
+    /// Convert a raw reading to amps in Q16.16 using the nominal reference voltage.
+    pub const fn to_amps_q16(&self, raw: u16) -> i32 {
+        amps_q16(raw, self.offset, self.milliamps_per_code_q16(self.vref_mv))
+    }
+
+    /// Largest measurable current in milliamps, half the ADC range around mid supply.
+    ///
+    /// A natural full scale for per unit currents, see `PhaseCurrents::per_unit`.
+    pub const fn full_scale_ma(&self) -> i32 {
+        let scale = self.milliamps_per_code_q16(self.vref_mv).unsigned_abs() as i64;
+        ((ADC_CODES / 2 * scale) >> 16) as i32
+    }
+}
+
+#[inline(always)]
+const fn milliamps(raw: u16, offset: u16, scale_q16: i32) -> i32 {
+    (((raw as i64 - offset as i64) * scale_q16 as i64) >> 16) as i32
+}
+
+#[inline(always)]
+const fn amps_q16(raw: u16, offset: u16, scale_q16: i32) -> i32 {
+    ((raw as i64 - offset as i64) * scale_q16 as i64 / 1000) as i32
+}
+
+/// Offsets and scale of the current ranks at one moment, to convert raw injected
+/// results in an interrupt without access to the [`Isense`].
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Calibration {
+    offsets: [u16; MAX_INJECTED],
+    /// milliamps per code in Q16.16
+    scale: i32,
+}
+
+impl Calibration {
+    /// Convert a raw result of an injected rank to milliamps.
+    #[inline(always)]
+    pub fn milliamps(&self, rank: usize, raw: u16) -> i32 {
+        milliamps(raw, self.offsets[rank], self.scale)
+    }
+}
+
 pub struct Isense<'d, T: Instance> {
     #[allow(unused)]
     adc: Peri<'d, T>,
-    cha: AnyAdcChannel<T>,
-    #[allow(unused)]
+    injected: Vec<AnyAdcChannel<T>, MAX_INJECTED>,
+    config: CurrentSenseConfig,
+    /// zero current reading per injected rank
+    offsets: [u16; MAX_INJECTED],
+    /// injected ranks carrying a voltage instead of a current
+    voltages: [Option<AnalogInput>; MAX_INJECTED],
+    /// milliamps per code in Q16.16
+    scale: i32,
     sample_time: SampleTime,
+    trigger: InjectedTrigger,
+    vref_enabled: bool,
+    temperature_enabled: bool,
+    /// injected conversions between internal channel samples, 0 to disable
+    internal_interval: u16,
+    conversions: u16,
+    vdda_mv: u16,
+    temperature_mc: i32,
+    overcurrent_limit_ma: Option<u32>,
+    regular: Vec<(AnyAdcChannel<T>, AnalogInput), MAX_REGULAR>,
+    scan: Option<Scan<'d>>,
+}
+
+/// Circular DMA transfer of the regular sequence.
+struct Scan<'d> {
+    _transfer: Transfer<'d>,
+    buffer: *const u16,
+    ranks: usize,
+    depth: usize,
+    vref_rank: Option<usize>,
+    temperature_rank: Option<usize>,
 }
 
-pub struct InterruptHandler<T: Instance> {
+impl Scan<'_> {
+    /// Average of the samples of a rank in the buffer.
+    fn average(&self, rank: usize) -> u16 {
+        average(self.buffer, self.ranks, self.depth, rank)
+    }
+}
+
+fn average(buffer: *const u16, ranks: usize, depth: usize, rank: usize) -> u16 {
+    let sum: u32 = (0..depth)
+        .map(|n| {
+            // the DMA keeps writing the buffer, every element is always a valid result
+            unsafe { core::ptr::read_volatile(buffer.add(n * ranks + rank)) as u32 }
+        })
+        .sum();
+    (sum / depth as u32) as u16
+}
+
+/// Scaling of a regular or injected voltage input.
+#[derive(Clone, Copy, defmt::Format)]
+pub enum AnalogInput {
+    /// Millivolts at the pin.
+    Pin,
+    /// Resistive divider to ground, millivolts at the top of the divider.
+    Divider { top_ohms: u32, bottom_ohms: u32 },
+    /// NTC to ground with a pull-up to VDDA, millidegrees Celsius from the beta model.
+    Ntc {
+        pullup_ohms: u32,
+        r25_ohms: u32,
+        beta: u16,
+    },
+}
+
+impl AnalogInput {
+    /// Scale a raw result given the analog supply.
+    pub fn scale(&self, raw: u16, vdda_mv: u16) -> i32 {
+        let pin_mv = raw as i64 * vdda_mv as i64 / ADC_CODES;
+        match *self {
+            AnalogInput::Pin => pin_mv as i32,
+            AnalogInput::Divider {
+                top_ohms,
+                bottom_ohms,
+            } => (pin_mv * (top_ohms + bottom_ohms) as i64 / bottom_ohms.max(1) as i64) as i32,
+            AnalogInput::Ntc {
+                pullup_ohms,
+                r25_ohms,
+                beta,
+            } => {
+                // the divider ratio does not depend on the supply
+                let raw = (raw as i64).clamp(1, ADC_CODES - 1);
+                let ohms = pullup_ohms as f32 * raw as f32 / (ADC_CODES - raw) as f32;
+                let kelvin = 1.0 / (1.0 / 298.15 + ln(ohms / r25_ohms as f32) / beta as f32);
+                ((kelvin - 273.15) * 1000.0) as i32
+            }
+        }
+    }
+}
+
+/// Natural logarithm for positive, finite `x`, accurate to about 1e-6.
+fn ln(x: f32) -> f32 {
+    const LN_2: f32 = core::f32::consts::LN_2;
+    // split into x = m * 2^e with m in [1, 2)
+    let bits = x.to_bits();
+    let e = ((bits >> 23) & 0xff) as i32 - 127;
+    let m = f32::from_bits((bits & 0x007f_ffff) | 0x3f80_0000);
+    // ln(m) = 2 atanh((m - 1) / (m + 1))
+    let t = (m - 1.0) / (m + 1.0);
+    let t2 = t * t;
+    let series =
+        t * (2.0 + t2 * (2.0 / 3.0 + t2 * (2.0 / 5.0 + t2 * (2.0 / 7.0 + t2 * 2.0 / 9.0))));
+    e as f32 * LN_2 + series
+}
+
+/// The DMA overtook an [`AdcStream`] reader and samples were lost.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Overrun;
+
+/// The analog watchdog guards either every injected channel or a single one, so it
+/// can not watch more than one current next to a voltage.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Unguarded;
+
+/// Regular conversions streamed through a circular DMA buffer.
+///
+/// The DMA signals every half and full buffer, so reading half a buffer at a time
+/// hands out one half while the other is being filled.
+pub struct AdcStream<'d, T: Instance> {
+    ring: ReadableRingBuffer<'d, u16>,
+    ranks: usize,
+    overruns: u32,
     _phantom: PhantomData<T>,
 }
 
-impl<T: Instance> interrupt::typelevel::Handler<T::Interrupt> for InterruptHandler<T> {
-    unsafe fn on_interrupt() {
-        defmt::info!("adc interrupt!");
+impl<T: Instance> AdcStream<'_, T> {
+    /// Number of interleaved ranks in the stream, samples come in rank order.
+    pub fn ranks(&self) -> usize {
+        self.ranks
+    }
 
-        defmt::info!("adc.sr {:?}", T::regs().sr().read());
-        if T::regs().sr().read().jeoc() {
-            defmt::info!("injected scan complete");
-            //T::regs().cr1().modify(|w| w.set_jeocie(false));
-            //T::regs().cr1().modify(|w| w.set_eocie(false));
-            T::regs().sr().modify(|w| w.set_jeoc(false));
-            T::regs().sr().modify(|w| w.set_eoc(false));
+    /// Read length that matches one half of the buffer.
+    pub fn half_len(&self) -> usize {
+        self.ring.capacity() / 2
+    }
 
-            defmt::info!("waking future!");
-            T::state().waker.wake();
+    /// Fill `samples`, waiting for the DMA as needed.
+    ///
+    /// On an overrun the buffered samples are dropped and the stream restarts, so the
+    /// next read starts on a fresh frame.
+    pub async fn read(&mut self, samples: &mut [u16]) -> Result<(), Overrun> {
+        match self.ring.read_exact(samples).await {
+            Ok(_) => Ok(()),
+            Err(_) => {
+                self.overruns += 1;
+                self.ring.clear();
+                Err(Overrun)
+            }
         }
+    }
 
-        //pac::ADC1.sr().modify(|w| w.set_jeoc(false));
-        //pac::ADC1.sr().modify(|w| w.set_eoc(false));
-        //pac::ADC1.sr().modify(|w| w.set_jstrt(false));
-        //pac::ADC1.sr().modify(|w| w.set_strt(false));
-        //pac::ADC1.cr1().modify(|w| w.set_jeocie(true));
-        //pac::ADC1.cr1().modify(|w| w.set_eocie(true));
+    /// Number of overruns since the stream started.
+    pub fn overruns(&self) -> u32 {
+        self.overruns
+    }
+}
 
-        //if T::regs().sr().read().jeoc() {
-        //    T::regs().cr1().modify(|w| w.set_jeocie(false));
-        //    T::state().waker.wake();
-        //}
+impl<T: Instance> Drop for AdcStream<'_, T> {
+    fn drop(&mut self) {
+        family::stop_regular(T::regs());
+        T::state().streaming.store(false, Ordering::Relaxed);
     }
 }
 
-impl<'d, T: Instance> Isense<'d, T> {
-    pub fn new(adc: Peri<'d, T>, cha: impl AdcChannel<T>) -> Self {
-        rcc::enable_and_reset::<T>();
-        T::regs().cr2().modify(|reg| reg.set_adon(true));
+/// Filtered view of a running regular scan.
+///
+/// Reads only touch memory written by the DMA, so they never wait on the ADC and can
+/// be made from any context.
+#[derive(Clone)]
+pub struct RegularScan<'d, T: Instance> {
+    buffer: *const u16,
+    ranks: usize,
+    depth: usize,
+    inputs: Vec<AnalogInput, MAX_REGULAR>,
+    _phantom: PhantomData<(&'d [u16], T)>,
+}
+
+// the buffer is only ever read
+unsafe impl<T: Instance> Send for RegularScan<'_, T> {}
 
-        // 11.4: Before starting a calibration, the ADC must have been in power-on state (ADON bit = ‘1’)
-        // for at least two ADC clock cycles.
-        blocking_delay_us((1_000_000 * 2) / Self::freq().0 + 1);
+impl<T: Instance> RegularScan<'_, T> {
+    /// Raw result of a rank averaged over the buffer depth.
+    pub fn raw(&self, rank: usize) -> u16 {
+        average(self.buffer, self.ranks, self.depth, rank)
+    }
+
+    /// Millivolts at the pin of a rank.
+    pub fn millivolts(&self, rank: usize) -> u16 {
+        let vdda_mv = T::state().vdda_mv.load(Ordering::Relaxed);
+        (self.raw(rank) as u32 * vdda_mv as u32 / ADC_CODES as u32) as u16
+    }
 
-        // Reset calibration
-        T::regs().cr2().modify(|reg| reg.set_rstcal(true));
-        while T::regs().cr2().read().rstcal() {
-            // spin
+    /// Value of a rank scaled by its [`AnalogInput`].
+    pub fn value(&self, rank: usize) -> i32 {
+        let vdda_mv = T::state().vdda_mv.load(Ordering::Relaxed);
+        self.inputs[rank].scale(self.raw(rank), vdda_mv)
+    }
+}
+
+/// Latched analog watchdog event.
+#[derive(Clone, Copy, defmt::Format)]
+pub struct Overcurrent {
+    /// Rank in the injected sequence.
+    pub rank: usize,
+    /// ADC channel number.
+    pub channel: u8,
+    /// Offending raw result.
+    pub raw: u16,
+}
+
+/// Work done directly in the ADC interrupt once the injected sequence completes.
+///
+/// Bound at compile time through the interrupt handler, e.g.
+/// `ADC1_2 => InterruptHandler<ADC1, Foc>`, and run from
+/// [`Isense::start_control_loop`] on. Keep it short, the next PWM period triggers
+/// the next conversion.
+pub trait ControlLoop {
+    /// Raw injected results in rank order.
+    fn on_injected(raw: &[u16]);
+}
+
+/// No control loop, injected results are only handed to [`Isense::convert`].
+impl ControlLoop for () {
+    fn on_injected(_raw: &[u16]) {}
+}
+
+/// Latest injected results of an instance, e.g. ADC2 from a dual mode control loop.
+#[inline(always)]
+pub fn injected_results<T: Instance>() -> Vec<u16, MAX_INJECTED> {
+    let r = T::regs();
+    (0..family::injected_len(r))
+        .map(|rank| family::injected_result(r, rank))
+        .collect()
+}
+
+pub struct InterruptHandler<T: Instance, C: ControlLoop = ()> {
+    _phantom: PhantomData<(T, C)>,
+}
+
+impl<T: Instance, C: ControlLoop> InterruptHandler<T, C> {
+    fn on_watchdog() {
+        // shut the stage down before anything else
+        if let Some(trip) = T::state().trip.lock(|trip| trip.get()) {
+            trip.fire();
         }
+        let r = T::regs();
+        family::listen_watchdog(r, false);
+        family::clear_watchdog(r);
 
-        // Calibrate
-        T::regs().cr2().modify(|reg| reg.set_cal(true));
-        while T::regs().cr2().read().cal() {
-            // spin
+        // the watchdog does not say which channel tripped, look for the result outside
+        // the window
+        let (low, high) = family::watchdog_window(r);
+        let watched = family::watched_channel(r);
+        let overcurrent = (0..family::injected_len(r))
+            .map(|rank| Overcurrent {
+                rank,
+                channel: family::injected_channel(r, rank),
+                raw: family::injected_result(r, rank),
+            })
+            .filter(|o| watched.is_none_or(|channel| o.channel == channel))
+            .find(|o| o.raw > high || o.raw < low);
+        T::state().overcurrent.lock(|latch| {
+            // keep the first fault until re-armed
+            if latch.get().is_none() {
+                latch.set(overcurrent.or(Some(Overcurrent {
+                    rank: 0,
+                    channel: family::injected_channel(r, 0),
+                    raw: family::injected_result(r, 0),
+                })));
+            }
+        });
+        T::state().waker.wake();
+    }
+}
+
+impl<T: Instance, C: ControlLoop> interrupt::typelevel::Handler<T::Interrupt>
+    for InterruptHandler<T, C>
+{
+    unsafe fn on_interrupt() {
+        let r = T::regs();
+        if family::watchdog_flag(r) && family::listening_watchdog(r) {
+            Self::on_watchdog();
         }
 
-        // One cycle after calibration
-        blocking_delay_us(1_000_000 / Self::freq().0 + 1);
-
-        let cha = cha.degrade_adc();
-
-        // set up scanning injected mode
-        T::regs().cr1().modify(|w| w.set_scan(true));
-        T::regs().cr2().modify(|w| w.set_cont(false));
-        T::regs().cr1().modify(|w| w.set_discen(false));
-        T::regs().cr2().modify(|w| w.set_extsel(0b111)); // ADC SOFTWARE START
-        T::regs().cr2().modify(|w| w.set_align(false));
-        T::regs().cr2().modify(|w| w.set_exttrig(false));
-        T::regs().cr2().modify(|w| w.set_jexttrig(true));
-
-        T::regs().cr1().modify(|w| w.set_jdiscen(false));
-        //T::regs().cr2().modify(|w| w.set_jextsel(0b100)); // TIM3 CC4 event
-        T::regs().cr2().modify(|w| w.set_jextsel(0b111)); // JSWSTART
-        T::regs().cr1().modify(|w| w.set_jauto(false));
-
-        // configure injected channels
-        T::regs().jsqr().modify(|w| w.set_jl(1)); // 2 conversions
-        T::regs().jsqr().modify(|w| w.set_jsq(0, 0)); // JSQ3[4:0] = ADC_CHANNEL_4
-        T::regs().jsqr().modify(|w| w.set_jsq(1, 0)); // JSQ4[4:0] = ADC_CHANNEL_5
-        T::regs().jsqr().modify(|w| w.set_jsq(2, 3)); // JSQ4[4:0] = ADC_CHANNEL_5
-        T::regs().jsqr().modify(|w| w.set_jsq(3, 4)); // JSQ4[4:0] = ADC_CHANNEL_5
-
-        T::regs()
-            .smpr2()
-            .modify(|w| w.set_smp(5, SampleTime::CYCLES1_5));
-        T::regs()
-            .smpr2()
-            .modify(|w| w.set_smp(4, SampleTime::CYCLES1_5));
+        #[cfg(feature = "trace")]
+        defmt::info!("adc interrupt");
+        if family::injected_done(r) && family::listening_injected(r) {
+            T::state().injected_count.fetch_add(1, Ordering::Relaxed);
+            if T::state().control_loop.load(Ordering::Relaxed) {
+                family::clear_injected_done(r);
+                C::on_injected(&injected_results::<T>());
+            } else {
+                #[cfg(feature = "trace")]
+                defmt::info!("injected scan complete");
+                // leave the flag set for the future, disabling the interrupt marks completion
+                family::listen_injected(r, false);
+                T::state().waker.wake();
+            }
+        }
+        if family::regular_done(r) && family::listening_regular(r) {
+            #[cfg(feature = "trace")]
+            defmt::info!("regular conversion complete");
+            family::listen_regular(r, false);
+            T::state().waker.wake();
+        }
+    }
+}
+
+impl<'d, T: Instance> Isense<'d, T> {
+    pub fn new(adc: Peri<'d, T>, config: CurrentSenseConfig) -> Self {
+        rcc::enable_and_reset::<T>();
+        family::power_up(T::regs(), Self::freq().0);
 
         T::Interrupt::unpend();
         unsafe { T::Interrupt::enable() };
 
+        T::state().vdda_mv.store(config.vref_mv, Ordering::Relaxed);
+
         Self {
             adc,
+            injected: Vec::new(),
+            config,
+            offsets: [config.offset; MAX_INJECTED],
+            voltages: [None; MAX_INJECTED],
+            scale: config.milliamps_per_code_q16(config.vref_mv),
             sample_time: SampleTime::from_bits(0),
-            cha,
+            trigger: InjectedTrigger::Software,
+            vref_enabled: false,
+            temperature_enabled: false,
+            internal_interval: DEFAULT_INTERNAL_INTERVAL,
+            conversions: 0,
+            vdda_mv: config.vref_mv,
+            temperature_mc: 0,
+            overcurrent_limit_ma: None,
+            regular: Vec::new(),
+            scan: None,
         }
     }
 
@@ -149,114 +579,538 @@ impl<'d, T: Instance> Isense<'d, T> {
         rcc::frequency::<T>()
     }
 
+    /// Append a channel to the injected sequence, returning its rank.
+    pub fn add_injected(&mut self, channel: impl AdcChannel<T>) -> usize {
+        let channel = channel.degrade_adc();
+        Self::set_channel_sample_time(channel.get_hw_channel(), self.sample_time);
+        defmt::assert!(self.injected.push(channel).is_ok());
+        self.configure_injected();
+        self.injected.len() - 1
+    }
+
+    /// Append a voltage channel to the injected sequence, returning its rank.
+    ///
+    /// The channel keeps its own `sample_time` and is left out of offset calibration.
+    /// With a voltage in the sequence the overcurrent trip can only guard a single
+    /// current channel, see [`Isense::enable_overcurrent_trip`].
+    pub fn add_injected_voltage(
+        &mut self,
+        channel: impl AdcChannel<T>,
+        input: AnalogInput,
+        sample_time: SampleTime,
+    ) -> usize {
+        let channel = channel.degrade_adc();
+        Self::set_channel_sample_time(channel.get_hw_channel(), sample_time);
+        defmt::assert!(self.injected.push(channel).is_ok());
+        self.configure_injected();
+        let rank = self.injected.len() - 1;
+        self.voltages[rank] = Some(input);
+        rank
+    }
+
+    /// Scale a raw result of a voltage rank, millivolts or m°C depending on the input.
+    pub fn voltage(&self, rank: usize, raw: u16) -> i32 {
+        match self.voltages[rank] {
+            Some(input) => input.scale(raw, self.vdda_mv),
+            None => self.millivolts(raw) as i32,
+        }
+    }
+
+    /// Injected ranks carrying a current.
+    fn current_ranks(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.injected.len()).filter(|rank| self.voltages[*rank].is_none())
+    }
+
+    fn configure_injected(&self) {
+        let sequence: Vec<u8, MAX_INJECTED> = self
+            .injected
+            .iter()
+            .map(|channel| channel.get_hw_channel())
+            .collect();
+        family::set_injected_sequence(T::regs(), &sequence);
+    }
+
+    /// Select what starts the injected sequence, e.g. [`InjectedTrigger::Tim3Cc4`] to
+    /// sample in step with the trigger channel of `Pwm3` on TIM3.
+    pub fn set_injected_trigger(&mut self, trigger: InjectedTrigger) {
+        self.trigger = trigger;
+        family::set_injected_trigger(T::regs(), trigger);
+    }
+
+    pub fn config(&self) -> &CurrentSenseConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: CurrentSenseConfig) {
+        self.config = config;
+        self.offsets = [config.offset; MAX_INJECTED];
+        let vdda_mv = if self.vref_enabled {
+            self.vdda_mv
+        } else {
+            config.vref_mv
+        };
+        self.set_vdda(vdda_mv);
+    }
+
+    fn set_vdda(&mut self, vdda_mv: u16) {
+        self.vdda_mv = vdda_mv;
+        self.scale = self.config.milliamps_per_code_q16(vdda_mv);
+        T::state().vdda_mv.store(vdda_mv, Ordering::Relaxed);
+        self.set_watchdog_thresholds();
+    }
+
+    /// Number of channels in the injected sequence.
+    pub fn injected_len(&self) -> usize {
+        self.injected.len()
+    }
+
+    /// Injected sequences completed so far, wrapping.
+    pub fn injected_count(&self) -> u32 {
+        T::state().injected_count.load(Ordering::Relaxed)
+    }
+
+    /// Zero current reading of an injected rank.
+    pub fn offset(&self, rank: usize) -> u16 {
+        self.offsets[rank]
+    }
+
+    pub fn set_offset(&mut self, rank: usize, offset: u16) {
+        self.offsets[rank] = offset;
+        self.set_watchdog_thresholds();
+    }
+
+    /// Average `samples` conversions to find the zero current reading of every current
+    /// rank.
+    ///
+    /// The phases must not be driven while this runs.
+    pub async fn calibrate_offsets(&mut self, samples: u16) {
+        let mut sums = [0u32; MAX_INJECTED];
+        for _ in 0..samples {
+            let results = self.convert().await;
+            for (sum, raw) in sums.iter_mut().zip(results.iter()) {
+                *sum += *raw as u32;
+            }
+        }
+        self.set_current_offsets(&sums, samples);
+    }
+
+    /// Take the averages of `samples` summed conversions as the offsets of the current
+    /// ranks, leaving the voltage ranks alone.
+    fn set_current_offsets(&mut self, sums: &[u32; MAX_INJECTED], samples: u16) {
+        for rank in self.current_ranks().collect::<Vec<usize, MAX_INJECTED>>() {
+            self.offsets[rank] = (sums[rank] / samples.max(1) as u32) as u16;
+        }
+        self.set_watchdog_thresholds();
+    }
+
+    /// Guard every injected current channel with the analog watchdog.
+    ///
+    /// When any phase current exceeds `limit_ma` in either direction the ADC interrupt
+    /// fires `trip` and latches an [`Overcurrent`]. The interrupt should run at a high
+    /// priority so the stage is shut down within a conversion time.
+    pub fn enable_overcurrent_trip(&mut self, limit_ma: u32, trip: Trip) -> Result<(), Unguarded> {
+        // voltages would leave the window, fall back to watching the one current
+        let single = match self.current_ranks().count() {
+            count if count == self.injected.len() => None,
+            1 => self.current_ranks().next(),
+            _ => return Err(Unguarded),
+        };
+        T::state().trip.lock(|t| t.set(Some(trip)));
+        self.overcurrent_limit_ma = Some(limit_ma);
+        self.set_watchdog_thresholds();
+        let r = T::regs();
+        family::clear_watchdog(r);
+        family::watch_injected(r, single.map(|rank| self.injected[rank].get_hw_channel()));
+        family::listen_watchdog(r, true);
+        Ok(())
+    }
+
+    pub fn disable_overcurrent_trip(&mut self) {
+        family::unwatch_injected(T::regs());
+        family::listen_watchdog(T::regs(), false);
+        self.overcurrent_limit_ma = None;
+        T::state().trip.lock(|t| t.set(None));
+    }
+
+    /// Latched overcurrent fault, if the watchdog has tripped since the last re-arm.
+    pub fn overcurrent(&self) -> Option<Overcurrent> {
+        T::state().overcurrent.lock(|latch| latch.get())
+    }
+
+    /// Clear a latched fault and re-enable the watchdog interrupt.
+    ///
+    /// The power stage is left off, enable the phases and the driver once ready.
+    pub fn rearm_overcurrent(&mut self) {
+        T::state().overcurrent.lock(|latch| latch.set(None));
+        family::clear_watchdog(T::regs());
+        if self.overcurrent_limit_ma.is_some() {
+            family::listen_watchdog(T::regs(), true);
+        }
+    }
+
+    fn set_watchdog_thresholds(&self) {
+        let Some(limit_ma) = self.overcurrent_limit_ma else {
+            return;
+        };
+        let codes = ((limit_ma as i64) << 16) / (self.scale as i64).abs().max(1);
+        // one window covers all channels, keep it inside the limit for every offset
+        let offsets = || self.current_ranks().map(|rank| self.offsets[rank]);
+        let lowest = offsets().min().unwrap_or(self.config.offset) as i64;
+        let highest = offsets().max().unwrap_or(self.config.offset) as i64;
+        let high = (lowest + codes).clamp(0, ADC_CODES - 1) as u16;
+        let low = (highest - codes).clamp(0, ADC_CODES - 1) as u16;
+        family::set_watchdog_window(T::regs(), low, high);
+    }
+
+    /// Convert a raw result of an injected rank to milliamps.
+    pub fn milliamps(&self, rank: usize, raw: u16) -> i32 {
+        milliamps(raw, self.offsets[rank], self.scale)
+    }
+
+    /// Current offsets and scale, take a new copy after calibration or a VDDA update.
+    pub fn calibration(&self) -> Calibration {
+        Calibration {
+            offsets: self.offsets,
+            scale: self.scale,
+        }
+    }
+
+    /// Convert a raw result of an injected rank to amps in Q16.16.
+    pub fn amps_q16(&self, rank: usize, raw: u16) -> i32 {
+        amps_q16(raw, self.offsets[rank], self.scale)
+    }
+
     pub fn sample_time_for_us(&self, us: u32) -> SampleTime {
-        match us * Self::freq().0 / 1_000_000 {
-            0..=1 => SampleTime::CYCLES1_5,
-            2..=7 => SampleTime::CYCLES7_5,
-            8..=13 => SampleTime::CYCLES13_5,
-            14..=28 => SampleTime::CYCLES28_5,
-            29..=41 => SampleTime::CYCLES41_5,
-            42..=55 => SampleTime::CYCLES55_5,
-            56..=71 => SampleTime::CYCLES71_5,
-            _ => SampleTime::CYCLES239_5,
+        family::sample_time_for_cycles(us * Self::freq().0 / 1_000_000)
+    }
+
+    /// Sample Vrefint in the regular group and scale conversions by the measured VDDA.
+    ///
+    /// Enable before [`Isense::start_regular_scan`], which adds it to the scan.
+    pub fn enable_vref(&mut self) {
+        defmt::assert!(self.scan.is_none());
+        family::enable_vref(T::regs());
+        let sample_time = self.sample_time_for_us(INTERNAL_SAMPLE_US);
+        Self::set_channel_sample_time(VREFINT_CHANNEL, sample_time);
+        self.vref_enabled = true;
+    }
+
+    /// Sample the internal temperature sensor in the regular group.
+    ///
+    /// Enable before [`Isense::start_regular_scan`], which adds it to the scan.
+    pub fn enable_temperature(&mut self) {
+        defmt::assert!(self.scan.is_none());
+        family::enable_temperature(T::regs());
+        let sample_time = self.sample_time_for_us(INTERNAL_SAMPLE_US);
+        Self::set_channel_sample_time(TEMPERATURE_CHANNEL, sample_time);
+        self.temperature_enabled = true;
+    }
+
+    /// Sample the internal channels every `conversions` injected conversions, 0 to only
+    /// sample on [`Isense::sample_internal`].
+    pub fn set_internal_interval(&mut self, conversions: u16) {
+        self.internal_interval = conversions;
+        self.conversions = 0;
+    }
+
+    /// Convert the enabled internal channels and update the supply compensation.
+    ///
+    /// While a regular scan runs the internal channels are part of it and this only
+    /// reads the latest results, a single conversion would stop the scan.
+    pub async fn sample_internal(&mut self) {
+        if self.scan.is_none() && T::state().streaming.load(Ordering::Relaxed) {
+            return;
+        }
+        if self.vref_enabled {
+            let raw = match self
+                .scan
+                .as_ref()
+                .and_then(|s| s.vref_rank.map(|r| s.average(r)))
+            {
+                Some(raw) => raw,
+                None => self.read_regular(VREFINT_CHANNEL).await,
+            };
+            // a zero reading would mean VDDA is out of range, keep the last good value
+            if raw != 0 {
+                self.set_vdda(family::vdda_mv(raw));
+            }
+        }
+        if self.temperature_enabled {
+            let scanned = self
+                .scan
+                .as_ref()
+                .and_then(|s| s.temperature_rank.map(|r| s.average(r)));
+            let raw = match scanned {
+                Some(raw) => raw,
+                None => self.read_regular(TEMPERATURE_CHANNEL).await,
+            };
+            self.temperature_mc = family::temperature_mc(raw, self.vdda_mv);
         }
     }
 
-    //pub fn enable_vref(&self) -> Vref {
-    //    T::regs().cr2().modify(|reg| {
-    //        reg.set_tsvrefe(true);
-    //    });
-    //    Vref {}
-    //}
+    /// Analog supply in millivolts, measured through Vrefint when enabled.
+    pub fn vdda_mv(&self) -> u16 {
+        self.vdda_mv
+    }
+
+    /// Convert a raw result to millivolts at the ADC pin, compensated for the supply.
+    pub fn millivolts(&self, raw: u16) -> u16 {
+        (raw as u32 * self.vdda_mv as u32 / ADC_CODES as u32) as u16
+    }
 
-    //pub fn enable_temperature(&self) -> Temperature {
-    //    T::regs().cr2().modify(|reg| {
-    //        reg.set_tsvrefe(true);
-    //    });
-    //    Temperature {}
-    //}
+    /// MCU temperature in millidegrees Celsius from the last internal sample.
+    pub fn temperature_mc(&self) -> i32 {
+        self.temperature_mc
+    }
 
     pub fn set_sample_time(&mut self, sample_time: SampleTime) {
         self.sample_time = sample_time;
+        for rank in self.current_ranks() {
+            Self::set_channel_sample_time(self.injected[rank].get_hw_channel(), sample_time);
+        }
     }
 
-    /// Perform a single conversion.
-    pub async fn convert(&mut self) -> u16 {
+    /// Perform a single conversion of the injected sequence.
+    ///
+    /// With an external trigger this waits for the next triggered conversion instead.
+    pub async fn convert(&mut self) -> Vec<u16, MAX_INJECTED> {
+        defmt::assert!(!T::state().control_loop.load(Ordering::Relaxed));
+        #[cfg(feature = "trace")]
         defmt::info!("starting conversion");
-        T::regs().cr2().modify(|reg| {
-            reg.set_adon(true);
-            reg.set_jswstart(true);
+        let r = T::regs();
+        family::clear_injected_done(r);
+        family::listen_injected(r, true);
+        family::start_injected(r, self.trigger);
+
+        poll_fn(|cx| {
+            T::state().waker.register(cx.waker());
+            if family::listening_injected(r) {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await;
+
+        family::clear_injected_done(r);
+
+        let results = (0..self.injected.len())
+            .map(|rank| family::injected_result(r, rank))
+            .collect();
+        #[cfg(feature = "trace")]
+        defmt::info!("injected: {:?}", results);
+
+        if self.internal_interval != 0 && (self.vref_enabled || self.temperature_enabled) {
+            self.conversions += 1;
+            if self.conversions >= self.internal_interval {
+                self.conversions = 0;
+                self.sample_internal().await;
+            }
+        }
+        results
+    }
+
+    /// Hand every injected sequence to the bound [`ControlLoop`] from the interrupt.
+    ///
+    /// The sequence should be started by a timer, see [`Isense::set_injected_trigger`].
+    /// [`Isense::convert`] is unavailable and internal channels are no longer sampled
+    /// periodically until [`Isense::stop_control_loop`].
+    pub fn start_control_loop(&mut self) {
+        let r = T::regs();
+        T::state().control_loop.store(true, Ordering::Relaxed);
+        family::clear_injected_done(r);
+        family::listen_injected(r, true);
+        // arm the timer trigger where the family needs it
+        if self.trigger != InjectedTrigger::Software {
+            family::start_injected(r, self.trigger);
+        }
+    }
+
+    pub fn stop_control_loop(&mut self) {
+        family::listen_injected(T::regs(), false);
+        T::state().control_loop.store(false, Ordering::Relaxed);
+    }
+
+    /// Perform a single conversion of the injected sequence, scaled to milliamps.
+    pub async fn convert_milliamps(&mut self) -> Vec<i32, MAX_INJECTED> {
+        let results = self.convert().await;
+        results
+            .iter()
+            .enumerate()
+            .map(|(rank, raw)| self.milliamps(rank, *raw))
+            .collect()
+    }
+
+    /// Append a channel to the regular scan, returning its rank.
+    ///
+    /// Regular channels use the longest sampling time to suit high impedance dividers.
+    pub fn add_regular(&mut self, channel: impl AdcChannel<T>, input: AnalogInput) -> usize {
+        defmt::assert!(self.scan.is_none());
+        let channel = channel.degrade_adc();
+        Self::set_channel_sample_time(channel.get_hw_channel(), family::SLOW_SAMPLE_TIME);
+        defmt::assert!(self.regular.push((channel, input)).is_ok());
+        self.regular.len() - 1
+    }
+
+    /// Continuously convert the regular channels into `buffer` with circular DMA.
+    ///
+    /// Enabled internal channels are appended after the added channels. The buffer
+    /// holds `buffer.len() / ranks` results per rank which are averaged on read. The
+    /// injected group keeps priority and interrupts the scan whenever it is triggered.
+    pub fn start_regular_scan(
+        &mut self,
+        dma: Peri<'d, impl RxDma<T>>,
+        buffer: &'d mut [u16],
+    ) -> RegularScan<'d, T>
+    where
+        T: embassy_stm32::adc::Instance,
+    {
+        defmt::assert!(self.scan.is_none() && !T::state().streaming.load(Ordering::Relaxed));
+        let mut sequence: Vec<u8, MAX_REGULAR> = self
+            .regular
+            .iter()
+            .map(|(channel, _)| channel.get_hw_channel())
+            .collect();
+        let mut internal = |enabled: bool, channel: u8| {
+            (enabled && sequence.push(channel).is_ok()).then_some(sequence.len() - 1)
+        };
+        let vref_rank = internal(self.vref_enabled, VREFINT_CHANNEL);
+        let temperature_rank = internal(self.temperature_enabled, TEMPERATURE_CHANNEL);
+
+        let ranks = sequence.len();
+        defmt::assert!(ranks > 0 && buffer.len() >= ranks);
+        let depth = buffer.len() / ranks;
+        family::set_regular_sequence(T::regs(), &sequence);
+
+        // the request is a unit on BDMA chips like the F1
+        #[allow(clippy::let_unit_value)]
+        let request = dma.request();
+        let buffer = &mut buffer[..ranks * depth];
+        let pointer = buffer.as_ptr();
+        let mut options = TransferOptions::default();
+        options.circular = true;
+        options.half_transfer_ir = false;
+        options.complete_transfer_ir = false;
+        let transfer = unsafe {
+            Transfer::new_read_raw(
+                dma,
+                request,
+                T::regs().dr().as_ptr() as *mut u16,
+                buffer as *mut [u16],
+                options,
+            )
+        };
+
+        family::start_regular(T::regs(), RegularTrigger::Continuous);
+
+        self.scan = Some(Scan {
+            _transfer: transfer,
+            buffer: pointer,
+            ranks,
+            depth,
+            vref_rank,
+            temperature_rank,
         });
-        T::regs().cr1().modify(|w| w.set_eocie(true));
-        T::regs().cr1().modify(|w| w.set_jeocie(true));
+        RegularScan {
+            buffer: pointer,
+            ranks,
+            depth,
+            inputs: self.regular.iter().map(|(_, input)| *input).collect(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Stream the regular channels through a circular DMA `buffer`.
+    ///
+    /// Samples are interleaved in rank order, so the buffer length should be an even
+    /// multiple of the number of channels for every half to hold whole frames.
+    pub fn start_stream(
+        &mut self,
+        dma: Peri<'d, impl RxDma<T>>,
+        buffer: &'d mut [u16],
+        trigger: RegularTrigger,
+    ) -> AdcStream<'d, T>
+    where
+        T: embassy_stm32::adc::Instance,
+    {
+        defmt::assert!(self.scan.is_none() && !T::state().streaming.load(Ordering::Relaxed));
+        let sequence: Vec<u8, MAX_REGULAR> = self
+            .regular
+            .iter()
+            .map(|(channel, _)| channel.get_hw_channel())
+            .collect();
+        let ranks = sequence.len();
+        defmt::assert!(ranks > 0 && buffer.len() >= 2 * ranks);
+        family::set_regular_sequence(T::regs(), &sequence);
+
+        // the request is a unit on BDMA chips like the F1
+        #[allow(clippy::let_unit_value)]
+        let request = dma.request();
+        let mut ring = unsafe {
+            ReadableRingBuffer::new(
+                dma,
+                request,
+                T::regs().dr().as_ptr() as *mut u16,
+                buffer,
+                TransferOptions::default(),
+            )
+        };
+        ring.start();
+        T::state().streaming.store(true, Ordering::Relaxed);
+        family::start_regular(T::regs(), trigger);
+
+        AdcStream {
+            ring,
+            ranks,
+            overruns: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Stop the regular scan, the DMA transfer is stopped with it.
+    pub fn stop_regular_scan(&mut self) {
+        family::stop_regular(T::regs());
+        self.scan = None;
+    }
+
+    /// Perform a single regular conversion of a channel.
+    async fn read_regular(&mut self, channel: u8) -> u16 {
+        let r = T::regs();
+        family::set_regular_sequence(r, &[channel]);
+        family::clear_regular_done(r);
+        family::listen_regular(r, true);
+        family::start_single(r);
 
         poll_fn(|cx| {
             T::state().waker.register(cx.waker());
-            defmt::info!("running poll fn!");
-            Poll::Ready(())
+            if family::listening_regular(r) {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
         })
         .await;
 
-        T::regs().sr().modify(|w| w.set_jeoc(false));
-        T::regs().sr().modify(|w| w.set_eoc(false));
-        T::regs().sr().modify(|w| w.set_jstrt(false));
-        T::regs().sr().modify(|w| w.set_strt(false));
-        T::regs().cr1().modify(|w| w.set_eocie(false));
-        T::regs().cr1().modify(|w| w.set_jeocie(false));
-
-        let result = T::regs().jdr(0).read().0 as u16;
-        defmt::info!("jdr0: {:?}", result);
-        defmt::info!("jdr1: {:?}", pac::ADC1.jdr(1).read());
-        defmt::info!("jdr2: {:?}", pac::ADC1.jdr(2).read());
-        defmt::info!("jdr3: {:?}", pac::ADC1.jdr(3).read());
-        defmt::info!("adc.sr {:?}", pac::ADC1.sr().read());
-        result
-    }
-
-    // pub async fn read(&mut self, channel: &mut impl AdcChannel<T>) -> u16 {
-    //     Self::set_channel_sample_time(channel.channel(), self.sample_time);
-    //     T::regs().cr1().modify(|reg| {
-    //         reg.set_scan(false);
-    //         reg.set_discen(false);
-    //     });
-    //     T::regs().sqr1().modify(|reg| reg.set_l(0));
-
-    //     T::regs().cr2().modify(|reg| {
-    //         reg.set_cont(false);
-    //         reg.set_exttrig(true);
-    //         reg.set_swstart(false);
-    //         reg.set_extsel(7); // SWSTART
-    //     });
-
-    //     // Configure the channel to sample
-    //     T::regs()
-    //         .sqr3()
-    //         .write(|reg| reg.set_sq(0, channel.channel()));
-    //     self.convert().await
-    // }
+        let raw = family::regular_result(r);
+        family::clear_regular_done(r);
+        raw
+    }
 
     fn set_channel_sample_time(ch: u8, sample_time: SampleTime) {
-        if ch <= 9 {
-            T::regs()
-                .smpr2()
-                .modify(|reg| reg.set_smp(ch as _, sample_time));
-        } else {
-            T::regs()
-                .smpr1()
-                .modify(|reg| reg.set_smp((ch - 10) as _, sample_time));
-        }
+        family::set_sample_time(T::regs(), ch, sample_time);
     }
 }
 
 impl<'d, T: Instance> Drop for Isense<'d, T> {
     fn drop(&mut self) {
-        T::regs().cr2().modify(|reg| reg.set_adon(false));
+        self.stop_control_loop();
+        family::power_down(T::regs());
 
         rcc::disable::<T>();
     }
 }
 
-// manually created instance for f1
+// manually created instances
 impl Instance for ADC1 {
     fn regs() -> embassy_stm32::pac::adc::Adc {
         embassy_stm32::pac::ADC1
@@ -265,5 +1119,120 @@ impl Instance for ADC1 {
         static STATE: State = State::new();
         &STATE
     }
+    #[cfg(not(feature = "f4"))]
     type Interrupt = embassy_stm32::interrupt::typelevel::ADC1_2;
+    #[cfg(feature = "f4")]
+    type Interrupt = embassy_stm32::interrupt::typelevel::ADC;
+}
+
+impl Instance for ADC2 {
+    fn regs() -> embassy_stm32::pac::adc::Adc {
+        embassy_stm32::pac::ADC2
+    }
+    fn state() -> &'static State {
+        static STATE: State = State::new();
+        &STATE
+    }
+    #[cfg(not(feature = "f4"))]
+    type Interrupt = embassy_stm32::interrupt::typelevel::ADC1_2;
+    #[cfg(feature = "f4")]
+    type Interrupt = embassy_stm32::interrupt::typelevel::ADC;
+}
+
+/// ADC1 and ADC2 in injected simultaneous mode.
+///
+/// Each rank is converted on both ADCs at the same instant, so two phase shunts can
+/// be sampled without skew. ADC1 is the master and owns the trigger, the regular group
+/// and the interrupt; only `InterruptHandler<ADC1>` needs to be bound.
+pub struct DualIsense<'d> {
+    master: Isense<'d, ADC1>,
+    slave: Isense<'d, ADC2>,
+}
+
+impl<'d> DualIsense<'d> {
+    pub fn new(master: Peri<'d, ADC1>, slave: Peri<'d, ADC2>, config: CurrentSenseConfig) -> Self {
+        let master = Isense::new(master, config);
+        let slave = Isense::new(slave, config);
+
+        // in dual mode the slave must use a software trigger so it is only started by
+        // the master
+        family::set_injected_trigger(ADC2::regs(), InjectedTrigger::Software);
+        family::set_dual_injected(true);
+
+        Self { master, slave }
+    }
+
+    /// Append a channel pair to both injected sequences, returning the shared rank.
+    pub fn add_injected(
+        &mut self,
+        master: impl AdcChannel<ADC1>,
+        slave: impl AdcChannel<ADC2>,
+    ) -> usize {
+        let rank = self.master.add_injected(master);
+        defmt::assert_eq!(rank, self.slave.add_injected(slave));
+        rank
+    }
+
+    pub fn master(&mut self) -> &mut Isense<'d, ADC1> {
+        &mut self.master
+    }
+
+    pub fn slave(&mut self) -> &mut Isense<'d, ADC2> {
+        &mut self.slave
+    }
+
+    /// Average `samples` conversions to find the zero current reading of every current
+    /// rank on both ADCs.
+    ///
+    /// The phases must not be driven while this runs.
+    pub async fn calibrate_offsets(&mut self, samples: u16) {
+        let mut sums = [[0u32; MAX_INJECTED]; 2];
+        for _ in 0..samples {
+            let results = self.convert().await;
+            for (rank, raw) in results.iter().enumerate() {
+                sums[0][rank] += raw[0] as u32;
+                sums[1][rank] += raw[1] as u32;
+            }
+        }
+        self.master.set_current_offsets(&sums[0], samples);
+        self.slave.set_current_offsets(&sums[1], samples);
+    }
+
+    /// Perform a simultaneous conversion, returning `[adc1, adc2]` per rank.
+    pub async fn convert(&mut self) -> Vec<[u16; 2], MAX_INJECTED> {
+        let master = self.master.convert().await;
+        family::clear_injected_done(ADC2::regs());
+
+        // the slave has no Vrefint of its own, follow the supply measured by the master
+        if self.slave.vdda_mv != self.master.vdda_mv {
+            self.slave.set_vdda(self.master.vdda_mv);
+        }
+
+        master
+            .iter()
+            .enumerate()
+            .map(|(rank, raw)| [*raw, family::injected_result(ADC2::regs(), rank)])
+            .collect()
+    }
+
+    /// Perform a simultaneous conversion scaled to milliamps, `[adc1, adc2]` per rank.
+    pub async fn convert_milliamps(&mut self) -> Vec<[i32; 2], MAX_INJECTED> {
+        let results = self.convert().await;
+        results
+            .iter()
+            .enumerate()
+            .map(|(rank, raw)| {
+                [
+                    self.master.milliamps(rank, raw[0]),
+                    self.slave.milliamps(rank, raw[1]),
+                ]
+            })
+            .collect()
+    }
+}
+
+impl<'d> Drop for DualIsense<'d> {
+    fn drop(&mut self) {
+        family::set_dual_injected(false);
+    }
 }
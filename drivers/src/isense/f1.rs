@@ -0,0 +1,318 @@
+//! STM32F1 ADC registers.
+
+use embassy_stm32::adc::SampleTime;
+use embassy_stm32::pac;
+use embassy_stm32::pac::adc::vals::Dualmod;
+use embassy_stm32::pac::adc::Adc;
+
+use super::{blocking_delay_us, InjectedTrigger, RegularTrigger, ADC_CODES, MAX_INJECTED};
+
+// The F1 has no factory calibration of the internal channels, typical values from
+// http://www.st.com/resource/en/datasheet/CD00161566.pdf
+// 5.3.4 Embedded reference voltage
+const VREFINT_MV: u32 = 1200;
+pub const VREFINT_CHANNEL: u8 = 17;
+// 5.3.19 Temperature sensor characteristics, V25 = 1.43V and -4.3mV/°C
+const TEMPERATURE_REF_UV: i32 = 1_430_000;
+const TEMPERATURE_REF_MC: i32 = 25_000;
+const TEMPERATURE_SLOPE_UV: i32 = -4_300;
+pub const TEMPERATURE_CHANNEL: u8 = 16;
+// minimum sampling time of the internal channels is 17.1us
+pub const INTERNAL_SAMPLE_US: u32 = 18;
+pub const SLOW_SAMPLE_TIME: SampleTime = SampleTime::CYCLES239_5;
+
+/// Analog supply from a Vrefint reading.
+pub fn vdda_mv(vrefint: u16) -> u16 {
+    (VREFINT_MV * ADC_CODES as u32 / vrefint as u32) as u16
+}
+
+/// Temperature sensor reading in m°C.
+pub fn temperature_mc(raw: u16, vdda_mv: u16) -> i32 {
+    let sense_uv = (raw as i64 * vdda_mv as i64 * 1000 / ADC_CODES) as i32;
+    (sense_uv - TEMPERATURE_REF_UV) * 10 / (TEMPERATURE_SLOPE_UV / 100) + TEMPERATURE_REF_MC
+}
+
+pub fn sample_time_for_cycles(cycles: u32) -> SampleTime {
+    match cycles {
+        0..=1 => SampleTime::CYCLES1_5,
+        2..=7 => SampleTime::CYCLES7_5,
+        8..=13 => SampleTime::CYCLES13_5,
+        14..=28 => SampleTime::CYCLES28_5,
+        29..=41 => SampleTime::CYCLES41_5,
+        42..=55 => SampleTime::CYCLES55_5,
+        56..=71 => SampleTime::CYCLES71_5,
+        _ => SampleTime::CYCLES239_5,
+    }
+}
+
+fn jextsel(trigger: InjectedTrigger) -> u8 {
+    match trigger {
+        InjectedTrigger::Tim1Trgo => 0b000,
+        InjectedTrigger::Tim1Cc4 => 0b001,
+        InjectedTrigger::Tim2Trgo => 0b010,
+        InjectedTrigger::Tim2Cc1 => 0b011,
+        InjectedTrigger::Tim3Cc4 => 0b100,
+        InjectedTrigger::Tim4Trgo => 0b101,
+        InjectedTrigger::Exti15 => 0b110,
+        InjectedTrigger::Software => 0b111,
+    }
+}
+
+fn extsel(trigger: RegularTrigger) -> u8 {
+    match trigger {
+        RegularTrigger::Tim1Cc1 => 0b000,
+        RegularTrigger::Tim1Cc2 => 0b001,
+        RegularTrigger::Tim1Cc3 => 0b010,
+        RegularTrigger::Tim2Cc2 => 0b011,
+        RegularTrigger::Tim3Trgo => 0b100,
+        RegularTrigger::Tim4Cc4 => 0b101,
+        RegularTrigger::Exti11 => 0b110,
+        RegularTrigger::Continuous => 0b111,
+    }
+}
+
+pub fn power_up(r: Adc, adc_hz: u32) {
+    r.cr2().modify(|reg| reg.set_adon(true));
+
+    // 11.4: Before starting a calibration, the ADC must have been in power-on state (ADON bit = ‘1’)
+    // for at least two ADC clock cycles.
+    blocking_delay_us((1_000_000 * 2) / adc_hz + 1);
+
+    // Reset calibration
+    r.cr2().modify(|reg| reg.set_rstcal(true));
+    while r.cr2().read().rstcal() {
+        // spin
+    }
+
+    // Calibrate
+    r.cr2().modify(|reg| reg.set_cal(true));
+    while r.cr2().read().cal() {
+        // spin
+    }
+
+    // One cycle after calibration
+    blocking_delay_us(1_000_000 / adc_hz + 1);
+
+    // set up scanning injected mode
+    r.cr1().modify(|w| w.set_scan(true));
+    r.cr2().modify(|w| w.set_cont(false));
+    r.cr1().modify(|w| w.set_discen(false));
+    r.cr2().modify(|w| w.set_extsel(0b111)); // ADC SOFTWARE START
+    r.cr2().modify(|w| w.set_align(false));
+    r.cr2().modify(|w| w.set_exttrig(false));
+    r.cr2().modify(|w| w.set_jexttrig(true));
+
+    r.cr1().modify(|w| w.set_jdiscen(false));
+    r.cr2()
+        .modify(|w| w.set_jextsel(jextsel(InjectedTrigger::Software)));
+    r.cr1().modify(|w| w.set_jauto(false));
+}
+
+pub fn power_down(r: Adc) {
+    r.cr2().modify(|reg| reg.set_adon(false));
+}
+
+pub fn set_injected_sequence(r: Adc, channels: &[u8]) {
+    // with fewer than 4 conversions the sequence starts at JSQ(4 - len), results
+    // still land in JDR1 upwards
+    let first = MAX_INJECTED - channels.len();
+    r.jsqr().modify(|w| {
+        w.set_jl(channels.len() as u8 - 1);
+        for (rank, channel) in channels.iter().enumerate() {
+            w.set_jsq(first + rank, *channel);
+        }
+    });
+}
+
+pub fn injected_len(r: Adc) -> usize {
+    r.jsqr().read().jl() as usize + 1
+}
+
+pub fn injected_channel(r: Adc, rank: usize) -> u8 {
+    r.jsqr().read().jsq(MAX_INJECTED - injected_len(r) + rank)
+}
+
+#[inline(always)]
+pub fn injected_result(r: Adc, rank: usize) -> u16 {
+    r.jdr(rank).read().jdata()
+}
+
+pub fn set_injected_trigger(r: Adc, trigger: InjectedTrigger) {
+    r.cr2().modify(|w| w.set_jextsel(jextsel(trigger)));
+}
+
+/// Start a software triggered sequence, hardware triggers need no arming.
+pub fn start_injected(r: Adc, trigger: InjectedTrigger) {
+    if trigger == InjectedTrigger::Software {
+        r.cr2().modify(|reg| {
+            reg.set_adon(true);
+            reg.set_jswstart(true);
+        });
+    }
+}
+
+#[inline(always)]
+pub fn injected_done(r: Adc) -> bool {
+    r.sr().read().jeoc()
+}
+
+#[inline(always)]
+pub fn clear_injected_done(r: Adc) {
+    r.sr().modify(|w| {
+        w.set_jeoc(false);
+        w.set_jstrt(false);
+    });
+}
+
+#[inline(always)]
+pub fn listen_injected(r: Adc, enable: bool) {
+    r.cr1().modify(|w| w.set_jeocie(enable));
+}
+
+#[inline(always)]
+pub fn listening_injected(r: Adc) -> bool {
+    r.cr1().read().jeocie()
+}
+
+pub fn regular_done(r: Adc) -> bool {
+    r.sr().read().eoc()
+}
+
+pub fn clear_regular_done(r: Adc) {
+    r.sr().modify(|w| {
+        w.set_eoc(false);
+        w.set_strt(false);
+    });
+}
+
+pub fn listen_regular(r: Adc, enable: bool) {
+    r.cr1().modify(|w| w.set_eocie(enable));
+}
+
+pub fn listening_regular(r: Adc) -> bool {
+    r.cr1().read().eocie()
+}
+
+#[inline(always)]
+pub fn watchdog_flag(r: Adc) -> bool {
+    r.sr().read().awd()
+}
+
+#[inline(always)]
+pub fn clear_watchdog(r: Adc) {
+    r.sr().modify(|w| w.set_awd(false));
+}
+
+#[inline(always)]
+pub fn listen_watchdog(r: Adc, enable: bool) {
+    r.cr1().modify(|w| w.set_awdie(enable));
+}
+
+#[inline(always)]
+pub fn listening_watchdog(r: Adc) -> bool {
+    r.cr1().read().awdie()
+}
+
+pub fn set_watchdog_window(r: Adc, low: u16, high: u16) {
+    r.htr().write(|w| w.set_ht(high));
+    r.ltr().write(|w| w.set_lt(low));
+}
+
+pub fn watchdog_window(r: Adc) -> (u16, u16) {
+    (r.ltr().read().lt(), r.htr().read().ht())
+}
+
+/// Guard the injected group, all channels or only `single`.
+pub fn watch_injected(r: Adc, single: Option<u8>) {
+    r.cr1().modify(|w| {
+        if let Some(channel) = single {
+            w.set_awdch(channel);
+        }
+        w.set_awdsgl(single.is_some());
+        w.set_awden(false);
+        w.set_jawden(true);
+    });
+}
+
+pub fn unwatch_injected(r: Adc) {
+    r.cr1().modify(|w| w.set_jawden(false));
+}
+
+pub fn watched_channel(r: Adc) -> Option<u8> {
+    let cr1 = r.cr1().read();
+    cr1.awdsgl().then_some(cr1.awdch())
+}
+
+pub fn set_sample_time(r: Adc, ch: u8, sample_time: SampleTime) {
+    if ch <= 9 {
+        r.smpr2().modify(|reg| reg.set_smp(ch as _, sample_time));
+    } else {
+        r.smpr1()
+            .modify(|reg| reg.set_smp((ch - 10) as _, sample_time));
+    }
+}
+
+pub fn set_regular_sequence(r: Adc, channels: &[u8]) {
+    r.sqr1().modify(|w| w.set_l(channels.len() as u8 - 1));
+    for (rank, channel) in channels.iter().enumerate() {
+        match rank {
+            0..=5 => r.sqr3().modify(|w| w.set_sq(rank, *channel)),
+            6..=11 => r.sqr2().modify(|w| w.set_sq(rank - 6, *channel)),
+            _ => r.sqr1().modify(|w| w.set_sq(rank - 12, *channel)),
+        }
+    }
+}
+
+/// Run the regular sequence into the DMA on every `trigger`.
+pub fn start_regular(r: Adc, trigger: RegularTrigger) {
+    r.cr2().modify(|w| {
+        w.set_dma(true);
+        w.set_cont(trigger == RegularTrigger::Continuous);
+        w.set_exttrig(true);
+        w.set_extsel(extsel(trigger));
+    });
+    if trigger == RegularTrigger::Continuous {
+        r.cr2().modify(|w| w.set_swstart(true));
+    }
+}
+
+/// Convert the regular sequence once, without DMA.
+pub fn start_single(r: Adc) {
+    r.cr2().modify(|reg| {
+        reg.set_cont(false);
+        reg.set_exttrig(true);
+        reg.set_extsel(extsel(RegularTrigger::Continuous));
+    });
+    r.cr2().modify(|reg| reg.set_swstart(true));
+}
+
+pub fn stop_regular(r: Adc) {
+    r.cr2().modify(|w| {
+        w.set_cont(false);
+        w.set_dma(false);
+    });
+}
+
+pub fn regular_result(r: Adc) -> u16 {
+    // reading DR clears EOC
+    r.dr().read().data()
+}
+
+pub fn enable_vref(r: Adc) {
+    r.cr2().modify(|reg| reg.set_tsvrefe(true));
+}
+
+pub fn enable_temperature(r: Adc) {
+    r.cr2().modify(|reg| reg.set_tsvrefe(true));
+}
+
+/// Injected simultaneous mode of ADC1 and ADC2.
+pub fn set_dual_injected(enable: bool) {
+    pac::ADC1.cr1().modify(|w| {
+        w.set_dualmod(if enable {
+            Dualmod::INJECTED
+        } else {
+            Dualmod::INDEPENDENT
+        })
+    });
+}
@@ -0,0 +1,314 @@
+//! STM32F4 ADC registers.
+//!
+//! Close to the F1, but triggers have an edge selection, the internal channels and
+//! the dual mode live in the common registers and there is no calibration.
+
+use embassy_stm32::adc::SampleTime;
+use embassy_stm32::pac;
+use embassy_stm32::pac::adc::vals::{Awdsgl, Dds, Exten};
+use embassy_stm32::pac::adc::Adc;
+use embassy_stm32::pac::adccommon::vals::Multi;
+
+use super::{blocking_delay_us, FactoryCalibration, InjectedTrigger, RegularTrigger, MAX_INJECTED};
+
+// Addresses from the STM32F405 datasheet, the values are taken at VDDA = 3.3V
+const CALIBRATION: FactoryCalibration = FactoryCalibration {
+    cal_mv: 3300,
+    vrefint: 0x1FFF_7A2A,
+    ts_cal: [0x1FFF_7A2C, 0x1FFF_7A2E],
+    ts_cal2_mc: 110_000,
+};
+pub const VREFINT_CHANNEL: u8 = 17;
+pub const TEMPERATURE_CHANNEL: u8 = 16;
+// minimum sampling time of the internal channels is 10us
+pub const INTERNAL_SAMPLE_US: u32 = 10;
+pub const SLOW_SAMPLE_TIME: SampleTime = SampleTime::CYCLES480;
+
+/// Analog supply from a Vrefint reading.
+pub fn vdda_mv(vrefint: u16) -> u16 {
+    CALIBRATION.vdda_mv(vrefint)
+}
+
+/// Temperature sensor reading in m°C.
+pub fn temperature_mc(raw: u16, vdda_mv: u16) -> i32 {
+    CALIBRATION.temperature_mc(raw, vdda_mv)
+}
+
+pub fn sample_time_for_cycles(cycles: u32) -> SampleTime {
+    match cycles {
+        0..=3 => SampleTime::CYCLES3,
+        4..=15 => SampleTime::CYCLES15,
+        16..=28 => SampleTime::CYCLES28,
+        29..=56 => SampleTime::CYCLES56,
+        57..=84 => SampleTime::CYCLES84,
+        85..=112 => SampleTime::CYCLES112,
+        113..=144 => SampleTime::CYCLES144,
+        _ => SampleTime::CYCLES480,
+    }
+}
+
+fn jextsel(trigger: InjectedTrigger) -> Option<u8> {
+    match trigger {
+        InjectedTrigger::Tim1Cc4 => Some(0b0000),
+        InjectedTrigger::Tim1Trgo => Some(0b0001),
+        InjectedTrigger::Tim2Cc1 => Some(0b0010),
+        InjectedTrigger::Tim2Trgo => Some(0b0011),
+        InjectedTrigger::Tim3Cc4 => Some(0b0101),
+        InjectedTrigger::Tim4Trgo => Some(0b1001),
+        InjectedTrigger::Exti15 => Some(0b1111),
+        InjectedTrigger::Software => None,
+    }
+}
+
+fn extsel(trigger: RegularTrigger) -> Option<u8> {
+    match trigger {
+        RegularTrigger::Tim1Cc1 => Some(0b0000),
+        RegularTrigger::Tim1Cc2 => Some(0b0001),
+        RegularTrigger::Tim1Cc3 => Some(0b0010),
+        RegularTrigger::Tim2Cc2 => Some(0b0011),
+        RegularTrigger::Tim3Trgo => Some(0b1000),
+        RegularTrigger::Tim4Cc4 => Some(0b1001),
+        RegularTrigger::Exti11 => Some(0b1111),
+        RegularTrigger::Continuous => None,
+    }
+}
+
+pub fn power_up(r: Adc, _adc_hz: u32) {
+    r.cr2().modify(|reg| reg.set_adon(true));
+    // tSTAB, 3us from power on to the first conversion
+    blocking_delay_us(3);
+
+    r.cr1().modify(|w| {
+        w.set_scan(true);
+        w.set_discen(false);
+        w.set_jdiscen(false);
+        w.set_jauto(false);
+    });
+    r.cr2().modify(|w| {
+        w.set_cont(false);
+        w.set_exten(Exten::DISABLED);
+        w.set_jexten(Exten::DISABLED);
+    });
+}
+
+pub fn power_down(r: Adc) {
+    r.cr2().modify(|reg| reg.set_adon(false));
+}
+
+pub fn set_injected_sequence(r: Adc, channels: &[u8]) {
+    // as on the F1 a short sequence starts at JSQ(4 - len)
+    let first = MAX_INJECTED - channels.len();
+    r.jsqr().modify(|w| {
+        w.set_jl(channels.len() as u8 - 1);
+        for (rank, channel) in channels.iter().enumerate() {
+            w.set_jsq(first + rank, *channel);
+        }
+    });
+}
+
+pub fn injected_len(r: Adc) -> usize {
+    r.jsqr().read().jl() as usize + 1
+}
+
+pub fn injected_channel(r: Adc, rank: usize) -> u8 {
+    r.jsqr().read().jsq(MAX_INJECTED - injected_len(r) + rank)
+}
+
+#[inline(always)]
+pub fn injected_result(r: Adc, rank: usize) -> u16 {
+    r.jdr(rank).read().jdata()
+}
+
+pub fn set_injected_trigger(r: Adc, trigger: InjectedTrigger) {
+    r.cr2().modify(|w| match jextsel(trigger) {
+        Some(code) => {
+            w.set_jextsel(code);
+            w.set_jexten(Exten::RISING_EDGE);
+        }
+        None => w.set_jexten(Exten::DISABLED),
+    });
+}
+
+/// Start a software triggered sequence, hardware triggers need no arming.
+pub fn start_injected(r: Adc, trigger: InjectedTrigger) {
+    if trigger == InjectedTrigger::Software {
+        r.cr2().modify(|reg| reg.set_jswstart(true));
+    }
+}
+
+#[inline(always)]
+pub fn injected_done(r: Adc) -> bool {
+    r.sr().read().jeoc()
+}
+
+#[inline(always)]
+pub fn clear_injected_done(r: Adc) {
+    r.sr().modify(|w| {
+        w.set_jeoc(false);
+        w.set_jstrt(false);
+    });
+}
+
+#[inline(always)]
+pub fn listen_injected(r: Adc, enable: bool) {
+    r.cr1().modify(|w| w.set_jeocie(enable));
+}
+
+#[inline(always)]
+pub fn listening_injected(r: Adc) -> bool {
+    r.cr1().read().jeocie()
+}
+
+pub fn regular_done(r: Adc) -> bool {
+    r.sr().read().eoc()
+}
+
+pub fn clear_regular_done(r: Adc) {
+    r.sr().modify(|w| {
+        w.set_eoc(false);
+        w.set_strt(false);
+        w.set_ovr(false);
+    });
+}
+
+pub fn listen_regular(r: Adc, enable: bool) {
+    r.cr1().modify(|w| w.set_eocie(enable));
+}
+
+pub fn listening_regular(r: Adc) -> bool {
+    r.cr1().read().eocie()
+}
+
+#[inline(always)]
+pub fn watchdog_flag(r: Adc) -> bool {
+    r.sr().read().awd()
+}
+
+#[inline(always)]
+pub fn clear_watchdog(r: Adc) {
+    r.sr().modify(|w| w.set_awd(false));
+}
+
+#[inline(always)]
+pub fn listen_watchdog(r: Adc, enable: bool) {
+    r.cr1().modify(|w| w.set_awdie(enable));
+}
+
+#[inline(always)]
+pub fn listening_watchdog(r: Adc) -> bool {
+    r.cr1().read().awdie()
+}
+
+pub fn set_watchdog_window(r: Adc, low: u16, high: u16) {
+    r.htr().write(|w| w.set_ht(high));
+    r.ltr().write(|w| w.set_lt(low));
+}
+
+pub fn watchdog_window(r: Adc) -> (u16, u16) {
+    (r.ltr().read().lt(), r.htr().read().ht())
+}
+
+/// Guard the injected group, all channels or only `single`.
+pub fn watch_injected(r: Adc, single: Option<u8>) {
+    r.cr1().modify(|w| {
+        if let Some(channel) = single {
+            w.set_awdch(channel);
+        }
+        w.set_awdsgl(match single {
+            Some(_) => Awdsgl::SINGLE_CHANNEL,
+            None => Awdsgl::ALL_CHANNELS,
+        });
+        w.set_awden(false);
+        w.set_jawden(true);
+    });
+}
+
+pub fn unwatch_injected(r: Adc) {
+    r.cr1().modify(|w| w.set_jawden(false));
+}
+
+pub fn watched_channel(r: Adc) -> Option<u8> {
+    let cr1 = r.cr1().read();
+    (cr1.awdsgl() == Awdsgl::SINGLE_CHANNEL).then_some(cr1.awdch())
+}
+
+pub fn set_sample_time(r: Adc, ch: u8, sample_time: SampleTime) {
+    if ch <= 9 {
+        r.smpr2().modify(|reg| reg.set_smp(ch as _, sample_time));
+    } else {
+        r.smpr1()
+            .modify(|reg| reg.set_smp((ch - 10) as _, sample_time));
+    }
+}
+
+pub fn set_regular_sequence(r: Adc, channels: &[u8]) {
+    r.sqr1().modify(|w| w.set_l(channels.len() as u8 - 1));
+    for (rank, channel) in channels.iter().enumerate() {
+        match rank {
+            0..=5 => r.sqr3().modify(|w| w.set_sq(rank, *channel)),
+            6..=11 => r.sqr2().modify(|w| w.set_sq(rank - 6, *channel)),
+            _ => r.sqr1().modify(|w| w.set_sq(rank - 12, *channel)),
+        }
+    }
+}
+
+/// Run the regular sequence into the DMA on every `trigger`.
+pub fn start_regular(r: Adc, trigger: RegularTrigger) {
+    r.cr2().modify(|w| {
+        w.set_dma(true);
+        // keep requesting after the first buffer for circular transfers
+        w.set_dds(Dds::CONTINUOUS);
+        w.set_cont(trigger == RegularTrigger::Continuous);
+        match extsel(trigger) {
+            Some(code) => {
+                w.set_extsel(code);
+                w.set_exten(Exten::RISING_EDGE);
+            }
+            None => w.set_exten(Exten::DISABLED),
+        }
+    });
+    if trigger == RegularTrigger::Continuous {
+        r.cr2().modify(|w| w.set_swstart(true));
+    }
+}
+
+/// Convert the regular sequence once, without DMA.
+pub fn start_single(r: Adc) {
+    r.cr2().modify(|reg| {
+        reg.set_cont(false);
+        reg.set_exten(Exten::DISABLED);
+    });
+    r.cr2().modify(|reg| reg.set_swstart(true));
+}
+
+pub fn stop_regular(r: Adc) {
+    r.cr2().modify(|w| {
+        w.set_cont(false);
+        w.set_dma(false);
+        w.set_dds(Dds::SINGLE);
+    });
+}
+
+pub fn regular_result(r: Adc) -> u16 {
+    // reading DR clears EOC
+    r.dr().read().data()
+}
+
+pub fn enable_vref(_r: Adc) {
+    pac::ADC123_COMMON.ccr().modify(|w| w.set_tsvrefe(true));
+}
+
+pub fn enable_temperature(_r: Adc) {
+    pac::ADC123_COMMON.ccr().modify(|w| w.set_tsvrefe(true));
+}
+
+/// Injected simultaneous mode of ADC1 and ADC2.
+pub fn set_dual_injected(enable: bool) {
+    pac::ADC123_COMMON.ccr().modify(|w| {
+        w.set_multi(if enable {
+            Multi::DUAL_J
+        } else {
+            Multi::INDEPENDENT
+        })
+    });
+}
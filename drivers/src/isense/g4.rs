@@ -0,0 +1,385 @@
+//! STM32G4 ADC registers.
+//!
+//! Flags are cleared by writing one, the injected sequence and its trigger share JSQR
+//! and a hardware triggered group must be armed with ADSTART or JADSTART. Most
+//! configuration is ignored while a group is armed, so it is written with both groups
+//! briefly stopped.
+
+use embassy_stm32::adc::SampleTime;
+use embassy_stm32::pac;
+use embassy_stm32::pac::adc::vals::{Adcaldif, Adstp, Awd1sgl, Dmacfg, Dmaen, Exten, Ovrmod};
+use embassy_stm32::pac::adc::Adc;
+use embassy_stm32::pac::adccommon::vals::Dual;
+
+use super::{blocking_delay_us, FactoryCalibration, InjectedTrigger, RegularTrigger};
+
+// Addresses from the STM32G431 datasheet, the values are taken at VREF+ = 3.0V
+const CALIBRATION: FactoryCalibration = FactoryCalibration {
+    cal_mv: 3000,
+    vrefint: 0x1FFF_75AA,
+    ts_cal: [0x1FFF_75A8, 0x1FFF_75CA],
+    ts_cal2_mc: 130_000,
+};
+pub const VREFINT_CHANNEL: u8 = 18;
+pub const TEMPERATURE_CHANNEL: u8 = 16;
+// minimum sampling time of the internal channels is 5us
+pub const INTERNAL_SAMPLE_US: u32 = 5;
+pub const SLOW_SAMPLE_TIME: SampleTime = SampleTime::CYCLES640_5;
+
+/// Analog supply from a Vrefint reading.
+pub fn vdda_mv(vrefint: u16) -> u16 {
+    CALIBRATION.vdda_mv(vrefint)
+}
+
+/// Temperature sensor reading in m°C.
+pub fn temperature_mc(raw: u16, vdda_mv: u16) -> i32 {
+    CALIBRATION.temperature_mc(raw, vdda_mv)
+}
+
+pub fn sample_time_for_cycles(cycles: u32) -> SampleTime {
+    match cycles {
+        0..=2 => SampleTime::CYCLES2_5,
+        3..=6 => SampleTime::CYCLES6_5,
+        7..=12 => SampleTime::CYCLES12_5,
+        13..=24 => SampleTime::CYCLES24_5,
+        25..=47 => SampleTime::CYCLES47_5,
+        48..=92 => SampleTime::CYCLES92_5,
+        93..=247 => SampleTime::CYCLES247_5,
+        _ => SampleTime::CYCLES640_5,
+    }
+}
+
+// ADC1 and ADC2 triggers, the first seven match the F1
+fn jextsel(trigger: InjectedTrigger) -> Option<u8> {
+    match trigger {
+        InjectedTrigger::Tim1Trgo => Some(0),
+        InjectedTrigger::Tim1Cc4 => Some(1),
+        InjectedTrigger::Tim2Trgo => Some(2),
+        InjectedTrigger::Tim2Cc1 => Some(3),
+        InjectedTrigger::Tim3Cc4 => Some(4),
+        InjectedTrigger::Tim4Trgo => Some(5),
+        InjectedTrigger::Exti15 => Some(6),
+        InjectedTrigger::Software => None,
+    }
+}
+
+fn extsel(trigger: RegularTrigger) -> Option<u8> {
+    match trigger {
+        RegularTrigger::Tim1Cc1 => Some(0),
+        RegularTrigger::Tim1Cc2 => Some(1),
+        RegularTrigger::Tim1Cc3 => Some(2),
+        RegularTrigger::Tim2Cc2 => Some(3),
+        RegularTrigger::Tim3Trgo => Some(4),
+        RegularTrigger::Tim4Cc4 => Some(5),
+        RegularTrigger::Exti11 => Some(6),
+        RegularTrigger::Continuous => None,
+    }
+}
+
+fn stop_regular_group(r: Adc) -> bool {
+    let running = r.cr().read().adstart();
+    if running {
+        r.cr().modify(|w| w.set_adstp(Adstp::STOP));
+        while r.cr().read().adstart() {}
+    }
+    running
+}
+
+fn stop_injected_group(r: Adc) -> bool {
+    let running = r.cr().read().jadstart();
+    if running {
+        r.cr().modify(|w| w.set_jadstp(Adstp::STOP));
+        while r.cr().read().jadstart() {}
+    }
+    running
+}
+
+/// Run `f` with both groups stopped and re-arm them afterwards.
+fn stopped(r: Adc, f: impl FnOnce()) {
+    let regular = stop_regular_group(r);
+    let injected = stop_injected_group(r);
+    f();
+    if injected {
+        r.cr().modify(|w| w.set_jadstart(true));
+    }
+    if regular {
+        r.cr().modify(|w| w.set_adstart(true));
+    }
+}
+
+pub fn power_up(r: Adc, adc_hz: u32) {
+    r.cr().modify(|w| {
+        w.set_deeppwd(false);
+        w.set_advregen(true);
+    });
+    // tADCVREG_STUP
+    blocking_delay_us(20);
+
+    r.cr().modify(|w| w.set_adcaldif(Adcaldif::SINGLE_ENDED));
+    r.cr().modify(|w| w.set_adcal(true));
+    while r.cr().read().adcal() {
+        // spin
+    }
+    // four ADC clock cycles between the end of calibration and ADEN
+    blocking_delay_us(4_000_000 / adc_hz + 1);
+
+    r.isr().write(|w| w.set_adrdy(true));
+    r.cr().modify(|w| w.set_aden(true));
+    while !r.isr().read().adrdy() {
+        // spin
+    }
+    r.isr().write(|w| w.set_adrdy(true));
+
+    r.cfgr().modify(|w| {
+        w.set_cont(false);
+        w.set_discen(false);
+        w.set_jdiscen(false);
+        w.set_jauto(false);
+        w.set_align(false);
+        w.set_exten(Exten::DISABLED);
+        w.set_ovrmod(Ovrmod::OVERWRITE);
+        // JSQR may only be written while the injected group is stopped
+        w.set_jqdis(true);
+    });
+    r.jsqr().modify(|w| w.set_jexten(Exten::DISABLED));
+}
+
+pub fn power_down(r: Adc) {
+    stop_regular_group(r);
+    stop_injected_group(r);
+    r.cr().modify(|w| w.set_addis(true));
+    while r.cr().read().aden() {}
+    r.cr().modify(|w| w.set_advregen(false));
+}
+
+pub fn set_injected_sequence(r: Adc, channels: &[u8]) {
+    // the sequence starts at JSQ1 whatever its length
+    stopped(r, || {
+        r.jsqr().modify(|w| {
+            w.set_jl(channels.len() as u8 - 1);
+            for (rank, channel) in channels.iter().enumerate() {
+                w.set_jsq(rank, *channel);
+            }
+        })
+    });
+}
+
+pub fn injected_len(r: Adc) -> usize {
+    r.jsqr().read().jl() as usize + 1
+}
+
+pub fn injected_channel(r: Adc, rank: usize) -> u8 {
+    r.jsqr().read().jsq(rank)
+}
+
+#[inline(always)]
+pub fn injected_result(r: Adc, rank: usize) -> u16 {
+    r.jdr(rank).read().jdata()
+}
+
+pub fn set_injected_trigger(r: Adc, trigger: InjectedTrigger) {
+    // a changed trigger takes effect when the group is armed again
+    stop_injected_group(r);
+    r.jsqr().modify(|w| match jextsel(trigger) {
+        Some(code) => {
+            w.set_jextsel(code);
+            w.set_jexten(Exten::RISING_EDGE);
+        }
+        None => w.set_jexten(Exten::DISABLED),
+    });
+}
+
+/// Start a software triggered sequence or arm the hardware trigger.
+pub fn start_injected(r: Adc, _trigger: InjectedTrigger) {
+    if !r.cr().read().jadstart() {
+        r.cr().modify(|w| w.set_jadstart(true));
+    }
+}
+
+#[inline(always)]
+pub fn injected_done(r: Adc) -> bool {
+    r.isr().read().jeos()
+}
+
+#[inline(always)]
+pub fn clear_injected_done(r: Adc) {
+    r.isr().write(|w| {
+        w.set_jeoc(true);
+        w.set_jeos(true);
+    });
+}
+
+#[inline(always)]
+pub fn listen_injected(r: Adc, enable: bool) {
+    r.ier().modify(|w| w.set_jeosie(enable));
+}
+
+#[inline(always)]
+pub fn listening_injected(r: Adc) -> bool {
+    r.ier().read().jeosie()
+}
+
+pub fn regular_done(r: Adc) -> bool {
+    r.isr().read().eoc()
+}
+
+pub fn clear_regular_done(r: Adc) {
+    r.isr().write(|w| {
+        w.set_eoc(true);
+        w.set_eos(true);
+        w.set_ovr(true);
+    });
+}
+
+pub fn listen_regular(r: Adc, enable: bool) {
+    r.ier().modify(|w| w.set_eocie(enable));
+}
+
+pub fn listening_regular(r: Adc) -> bool {
+    r.ier().read().eocie()
+}
+
+#[inline(always)]
+pub fn watchdog_flag(r: Adc) -> bool {
+    r.isr().read().awd1()
+}
+
+#[inline(always)]
+pub fn clear_watchdog(r: Adc) {
+    r.isr().write(|w| w.set_awd1(true));
+}
+
+#[inline(always)]
+pub fn listen_watchdog(r: Adc, enable: bool) {
+    r.ier().modify(|w| w.set_awd1ie(enable));
+}
+
+#[inline(always)]
+pub fn listening_watchdog(r: Adc) -> bool {
+    r.ier().read().awd1ie()
+}
+
+pub fn set_watchdog_window(r: Adc, low: u16, high: u16) {
+    stopped(r, || {
+        r.tr1().modify(|w| {
+            w.set_lt1(low);
+            w.set_ht1(high);
+        })
+    });
+}
+
+pub fn watchdog_window(r: Adc) -> (u16, u16) {
+    let tr1 = r.tr1().read();
+    (tr1.lt1(), tr1.ht1())
+}
+
+/// Guard the injected group, all channels or only `single`.
+pub fn watch_injected(r: Adc, single: Option<u8>) {
+    stopped(r, || {
+        r.cfgr().modify(|w| {
+            if let Some(channel) = single {
+                w.set_awd1ch(channel);
+            }
+            w.set_awd1sgl(match single {
+                Some(_) => Awd1sgl::SINGLE,
+                None => Awd1sgl::ALL,
+            });
+            w.set_awd1en(false);
+            w.set_jawd1en(true);
+        })
+    });
+}
+
+pub fn unwatch_injected(r: Adc) {
+    stopped(r, || r.cfgr().modify(|w| w.set_jawd1en(false)));
+}
+
+pub fn watched_channel(r: Adc) -> Option<u8> {
+    let cfgr = r.cfgr().read();
+    (cfgr.awd1sgl() == Awd1sgl::SINGLE).then_some(cfgr.awd1ch())
+}
+
+pub fn set_sample_time(r: Adc, ch: u8, sample_time: SampleTime) {
+    stopped(r, || {
+        if ch <= 9 {
+            r.smpr().modify(|reg| reg.set_smp(ch as _, sample_time));
+        } else {
+            r.smpr2()
+                .modify(|reg| reg.set_smp((ch - 10) as _, sample_time));
+        }
+    });
+}
+
+pub fn set_regular_sequence(r: Adc, channels: &[u8]) {
+    stop_regular_group(r);
+    r.sqr1().modify(|w| w.set_l(channels.len() as u8 - 1));
+    for (rank, channel) in channels.iter().enumerate() {
+        match rank {
+            0..=3 => r.sqr1().modify(|w| w.set_sq(rank, *channel)),
+            4..=8 => r.sqr2().modify(|w| w.set_sq(rank - 4, *channel)),
+            9..=13 => r.sqr3().modify(|w| w.set_sq(rank - 9, *channel)),
+            _ => r.sqr4().modify(|w| w.set_sq(rank - 14, *channel)),
+        }
+    }
+}
+
+/// Run the regular sequence into the DMA on every `trigger`.
+pub fn start_regular(r: Adc, trigger: RegularTrigger) {
+    stop_regular_group(r);
+    r.cfgr().modify(|w| {
+        w.set_dmaen(Dmaen::ENABLE);
+        w.set_dmacfg(Dmacfg::CIRCULAR);
+        w.set_cont(trigger == RegularTrigger::Continuous);
+        match extsel(trigger) {
+            Some(code) => {
+                w.set_extsel(code);
+                w.set_exten(Exten::RISING_EDGE);
+            }
+            None => w.set_exten(Exten::DISABLED),
+        }
+    });
+    r.cr().modify(|w| w.set_adstart(true));
+}
+
+/// Convert the regular sequence once, without DMA.
+pub fn start_single(r: Adc) {
+    stop_regular_group(r);
+    r.cfgr().modify(|w| {
+        w.set_dmaen(Dmaen::DISABLE);
+        w.set_cont(false);
+        w.set_exten(Exten::DISABLED);
+    });
+    r.cr().modify(|w| w.set_adstart(true));
+}
+
+pub fn stop_regular(r: Adc) {
+    stop_regular_group(r);
+    r.cfgr().modify(|w| {
+        w.set_cont(false);
+        w.set_dmaen(Dmaen::DISABLE);
+    });
+}
+
+pub fn regular_result(r: Adc) -> u16 {
+    // reading DR clears EOC
+    r.dr().read().rdata()
+}
+
+pub fn enable_vref(_r: Adc) {
+    pac::ADC12_COMMON.ccr().modify(|w| w.set_vrefen(true));
+}
+
+pub fn enable_temperature(_r: Adc) {
+    pac::ADC12_COMMON.ccr().modify(|w| w.set_vsenseen(true));
+}
+
+/// Injected simultaneous mode of ADC1 and ADC2.
+pub fn set_dual_injected(enable: bool) {
+    pac::ADC12_COMMON.ccr().modify(|w| {
+        w.set_dual(if enable {
+            Dual::DUAL_J
+        } else {
+            Dual::INDEPENDENT
+        })
+    });
+}
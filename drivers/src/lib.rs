@@ -1,4 +1,18 @@
 #![no_std]
 
+#[cfg(not(any(feature = "f1", feature = "f4", feature = "g4")))]
+compile_error!("select a chip feature, e.g. stm32f103cb");
+#[cfg(any(
+    all(feature = "f1", feature = "f4"),
+    all(feature = "f1", feature = "g4"),
+    all(feature = "f4", feature = "g4")
+))]
+compile_error!("select only one chip feature, use default-features = false to drop stm32f103cb");
+
+pub mod diagnostics;
+pub mod foc;
 pub mod isense;
+pub mod phase_current;
+pub mod phase_voltage;
 pub mod pwm;
+pub mod single_shunt;
@@ -0,0 +1,17 @@
+//! Phase currents from the injected results of `Isense`, see
+//! [`control::phase_current`] for the reconstruction.
+
+pub use control::phase_current::{highest_phase, sector, PhaseCurrents, Reconstruction, Shunts};
+
+use crate::isense::{Instance, Isense};
+
+/// Convert the injected sequence and reconstruct the phase currents from the duties
+/// applied while it was sampled.
+pub async fn convert<T: Instance>(
+    reconstruction: &Reconstruction,
+    isense: &mut Isense<'_, T>,
+    duties: [u16; 3],
+) -> PhaseCurrents {
+    let milliamps = isense.convert_milliamps().await;
+    reconstruction.reconstruct(&milliamps, duties)
+}
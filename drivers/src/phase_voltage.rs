@@ -0,0 +1,136 @@
+//! Phase terminal voltages for back-EMF measurement.
+//!
+//! Terminal voltages are read through resistive dividers. In the injected group they
+//! are sampled together with the currents on the `Pwm3` trigger channel; placing the
+//! trigger at the top of the count with [`sync_to_off_time`] samples the middle of the
+//! off-time, where every driven phase sits on its low side and no edge is near. In the
+//! regular group they are converted free running, which suits a stage that is switched
+//! off, e.g. a flying start.
+
+use embassy_stm32::adc::{AdcChannel, SampleTime};
+use embassy_stm32::timer::{GeneralInstance4Channel, TimerChannel};
+
+use crate::isense::{AnalogInput, Instance, Isense, RegularScan};
+use crate::pwm::{Phase, Pwm3};
+
+/// Phase terminal voltages in millivolts against ground.
+#[derive(Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct PhaseVoltages {
+    pub a: i32,
+    pub b: i32,
+    pub c: i32,
+}
+
+impl PhaseVoltages {
+    pub fn get(&self, phase: Phase) -> i32 {
+        match phase {
+            Phase::A => self.a,
+            Phase::B => self.b,
+            Phase::C => self.c,
+        }
+    }
+
+    /// Virtual neutral, the star point of three equal resistors across the terminals.
+    pub fn neutral(&self) -> i32 {
+        (self.a + self.b + self.c) / 3
+    }
+
+    /// Phase voltage against the virtual neutral, the back-EMF of a floating phase.
+    pub fn to_neutral(&self, phase: Phase) -> i32 {
+        self.get(phase) - self.neutral()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+enum Group {
+    Injected,
+    Regular,
+}
+
+/// Ranks of the three phase voltage channels.
+pub struct PhaseVoltageSense {
+    group: Group,
+    ranks: [usize; 3],
+}
+
+impl PhaseVoltageSense {
+    /// Add the dividers of phase a, b and c to the injected sequence.
+    ///
+    /// The injected sequence holds four channels, so this leaves room for one current,
+    /// e.g. a single DC link shunt. Boards with two or more shunts, like the FOC8313,
+    /// have to use [`PhaseVoltageSense::regular`].
+    pub fn injected<T: Instance>(
+        isense: &mut Isense<'_, T>,
+        a: impl AdcChannel<T>,
+        b: impl AdcChannel<T>,
+        c: impl AdcChannel<T>,
+        divider: AnalogInput,
+        sample_time: SampleTime,
+    ) -> Self {
+        defmt::assert!(
+            isense.injected_len() <= 1,
+            "phase voltages leave room for one injected current"
+        );
+        Self {
+            group: Group::Injected,
+            ranks: [
+                isense.add_injected_voltage(a, divider, sample_time),
+                isense.add_injected_voltage(b, divider, sample_time),
+                isense.add_injected_voltage(c, divider, sample_time),
+            ],
+        }
+    }
+
+    /// Add the dividers of phase a, b and c to the regular scan.
+    pub fn regular<T: Instance>(
+        isense: &mut Isense<'_, T>,
+        a: impl AdcChannel<T>,
+        b: impl AdcChannel<T>,
+        c: impl AdcChannel<T>,
+        divider: AnalogInput,
+    ) -> Self {
+        Self {
+            group: Group::Regular,
+            ranks: [
+                isense.add_regular(a, divider),
+                isense.add_regular(b, divider),
+                isense.add_regular(c, divider),
+            ],
+        }
+    }
+
+    /// Phase voltages from the raw injected results, e.g. in a `ControlLoop`.
+    pub fn from_injected<T: Instance>(&self, isense: &Isense<'_, T>, raw: &[u16]) -> PhaseVoltages {
+        defmt::assert!(self.group == Group::Injected);
+        let [a, b, c] = self.ranks.map(|rank| isense.voltage(rank, raw[rank]));
+        PhaseVoltages { a, b, c }
+    }
+
+    /// Convert the injected sequence and return the phase voltages.
+    pub async fn convert<T: Instance>(&self, isense: &mut Isense<'_, T>) -> PhaseVoltages {
+        let raw = isense.convert().await;
+        self.from_injected(isense, &raw)
+    }
+
+    /// Latest averaged phase voltages of a running regular scan.
+    pub fn from_scan<T: Instance>(&self, scan: &RegularScan<'_, T>) -> PhaseVoltages {
+        defmt::assert!(self.group == Group::Regular);
+        let [a, b, c] = self.ranks.map(|rank| scan.value(rank));
+        PhaseVoltages { a, b, c }
+    }
+}
+
+/// Move the `Pwm3` trigger to the top of the count, the middle of the off-time.
+///
+/// The injected group must be triggered by the trigger channel, e.g.
+/// `InjectedTrigger::Tim3Cc4`.
+pub fn sync_to_off_time<
+    T: GeneralInstance4Channel,
+    A: TimerChannel,
+    B: TimerChannel,
+    C: TimerChannel,
+>(
+    pwm: &mut Pwm3<'_, T, A, B, C>,
+) {
+    pwm.set_trigger(pwm.get_max_duty() - 1);
+}
@@ -1,19 +1,16 @@
+pub use control::phase_current::Phase;
 use core::marker::PhantomData;
-use embassy_stm32::gpio::{AfType, Flex, OutputType, Speed};
+use embassy_stm32::gpio::{AfType, AnyPin, Flex, OutputType, Pin, Speed};
+use embassy_stm32::pac::gpio::Gpio;
 use embassy_stm32::pac::timer::regs::Ccr1ch;
 pub use embassy_stm32::pac::timer::vals::Mms;
+use embassy_stm32::pac::timer::TimGp16;
 use embassy_stm32::time::Hertz;
-use embassy_stm32::timer::low_level::{CountingMode, OutputCompareMode, Timer as LLTimer};
+pub use embassy_stm32::timer::low_level::CountingMode;
+use embassy_stm32::timer::low_level::{OutputCompareMode, Timer as LLTimer};
 use embassy_stm32::timer::{Channel, GeneralInstance4Channel, TimerChannel, TimerPin};
 use embassy_stm32::Peri;
 
-#[derive(Clone, Copy)]
-pub enum Phase {
-    A,
-    B,
-    C,
-}
-
 pub enum MaybeChannel {
     Valid(Channel),
     Invalid,
@@ -66,8 +63,36 @@ impl TriggerOut for CompareOC4 {
     const CHANNEL: MaybeChannel = MaybeChannel::Valid(Channel::Ch4);
 }
 
+/// Register level handle that shuts the power stage down from interrupt context.
+///
+/// Firing clears the output enables of the three phases and pulls the driver enable
+/// pin low. Bring the stage back with [`Pwm3::enable`] and the enable pin once the
+/// cause has been dealt with.
+#[derive(Clone, Copy)]
+pub struct Trip {
+    tim: TimGp16,
+    channels: [usize; 3],
+    enable_block: Gpio,
+    enable_pin: usize,
+}
+
+impl Trip {
+    #[inline(always)]
+    pub fn fire(&self) {
+        self.enable_block
+            .bsrr()
+            .write(|w| w.set_br(self.enable_pin, true));
+        self.tim.ccer().modify(|w| {
+            for channel in self.channels {
+                w.set_cce(channel, false);
+            }
+        });
+    }
+}
+
 pub struct Pwm3<'d, T: GeneralInstance4Channel, A: TimerChannel, B: TimerChannel, C: TimerChannel> {
     tim: LLTimer<'d, T>,
+    trigger: Option<Channel>,
     _cha: Flex<'d>,
     _chb: Flex<'d>,
     _chc: Flex<'d>,
@@ -102,6 +127,10 @@ impl<'d, T: GeneralInstance4Channel, A: TimerChannel, B: TimerChannel, C: TimerC
 
         let mut this = Self {
             tim: LLTimer::new(tim),
+            trigger: match E::CHANNEL {
+                MaybeChannel::Valid(chx) => Some(chx),
+                MaybeChannel::Invalid => None,
+            },
             _cha: cha,
             _chb: chb,
             _chc: chc,
@@ -170,6 +199,54 @@ impl<'d, T: GeneralInstance4Channel, A: TimerChannel, B: TimerChannel, C: TimerC
             .modify(|w| w.set_cce(channel.index(), false));
     }
 
+    /// Create a [`Trip`] for this stage and the driver enable pin.
+    ///
+    /// Only the pin number is taken, so the enable pin can still be driven as an `Output`.
+    pub fn trip(&self, enable_pin: &impl Pin) -> Trip {
+        let pin_port = enable_pin.port() * 16 + enable_pin.pin();
+        // only used to look up the register block
+        let enable_block = unsafe { AnyPin::steal(pin_port) }.block();
+        Trip {
+            tim: self.tim.regs_gp16(),
+            channels: [A::CHANNEL.index(), B::CHANNEL.index(), C::CHANNEL.index()],
+            enable_block,
+            enable_pin: enable_pin.pin() as usize,
+        }
+    }
+
+    /// Change the counting mode, briefly stopping the timer.
+    ///
+    /// Only switch between center aligned modes, the frequency is not recalculated.
+    /// [`CountingMode::CenterAlignedBothInterrupts`] produces a trigger on both the up
+    /// and the down counting compare match of the trigger channel.
+    pub fn set_counting_mode(&mut self, mode: CountingMode) {
+        self.tim.stop();
+        self.tim.set_counting_mode(mode);
+        self.tim.start();
+    }
+
+    /// Set the compare value of the trigger out channel.
+    ///
+    /// The value is preloaded like the phase duties and takes effect at the next update,
+    /// which in center aligned mode happens at both ends of the count, unless preload
+    /// has been turned off with [`Self::set_trigger_preload`].
+    pub fn set_trigger(&mut self, compare: u16) {
+        if let Some(channel) = self.trigger {
+            self.tim
+                .regs_gp16()
+                .ccr(channel.index())
+                .write_value(Ccr1ch(compare as u32));
+        }
+    }
+
+    /// Preload the trigger compare value (the default) or write it straight through, to
+    /// move the trigger within one half of the count.
+    pub fn set_trigger_preload(&mut self, preload: bool) {
+        if let Some(channel) = self.trigger {
+            self.tim.set_output_compare_preload(channel, preload);
+        }
+    }
+
     pub fn set_frequency(&mut self, freq: Hertz) {
         let multiplier = if self.tim.get_counting_mode().is_center_aligned() {
             2u8
@@ -179,6 +256,16 @@ impl<'d, T: GeneralInstance4Channel, A: TimerChannel, B: TimerChannel, C: TimerC
         self.tim.set_frequency(freq * multiplier);
     }
 
+    /// PWM frequency, the rate of the trigger and of a control loop run from it.
+    pub fn get_frequency(&self) -> Hertz {
+        let divider = if self.tim.get_counting_mode().is_center_aligned() {
+            2u8
+        } else {
+            1u8
+        };
+        self.tim.get_frequency() / divider
+    }
+
     pub fn get_max_duty(&self) -> u16 {
         let max = self.tim.get_max_compare_value();
         max as u16
@@ -196,4 +283,11 @@ impl<'d, T: GeneralInstance4Channel, A: TimerChannel, B: TimerChannel, C: TimerC
             .ccr(channel.index())
             .write_value(Ccr1ch(duty as u32));
     }
+
+    /// Set the duties of phase a, b and c, e.g. from `control::svpwm::duties`.
+    pub fn set_duties(&mut self, duties: [u16; 3]) {
+        self.set_duty(Phase::A, duties[0]);
+        self.set_duty(Phase::B, duties[1]);
+        self.set_duty(Phase::C, duties[2]);
+    }
 }
@@ -0,0 +1,183 @@
+//! Phase current reconstruction from a single DC link shunt.
+//!
+//! Both samples are taken in the up counting half of a center aligned period, see
+//! [`control::single_shunt`] for the edge placement. The trigger compare value is
+//! written directly between the two samples and once more in the down half, where the
+//! compare values of the following period are loaded, so there are three injected
+//! conversions per period and the last one is discarded.
+//!
+//! The injected group must be triggered by the `Pwm3` trigger channel (e.g.
+//! `InjectedTrigger::Tim3Cc4`) with `CountingMode::CenterAlignedBothInterrupts`.
+
+use control::single_shunt::plan;
+pub use control::single_shunt::Plan;
+use embassy_stm32::timer::{GeneralInstance4Channel, TimerChannel};
+
+use crate::isense::{Instance, Isense};
+use crate::phase_current::PhaseCurrents;
+use crate::pwm::{Phase, Pwm3};
+
+fn phase(index: usize) -> Phase {
+    match index {
+        0 => Phase::A,
+        1 => Phase::B,
+        _ => Phase::C,
+    }
+}
+
+/// Three phase currents from the two DC link samples of a period.
+///
+/// `samples` are the -Imin and +Imax readings in milliamps.
+pub fn reconstruct(plan: &Plan, samples: [i32; 2]) -> PhaseCurrents {
+    let [max, _, min] = plan.order.map(phase);
+    PhaseCurrents::from_pair((max, samples[1]), (min, -samples[0]))
+}
+
+enum Step {
+    /// -Imin in the up half
+    First,
+    /// +Imax in the up half
+    Second,
+    /// down half, loads the next period
+    Reload,
+}
+
+pub struct SingleShunt {
+    /// shortest active vector that can be sampled, in timer ticks
+    min_window: u16,
+    /// ticks between the trigger and the end of the vector, covers the sampling time
+    sample_offset: u16,
+    max_duty: u16,
+    duties: [u16; 3],
+    plan: Plan,
+    next: Step,
+    first: i32,
+}
+
+impl SingleShunt {
+    /// `min_window` and `sample_offset` are in timer ticks, `max_duty` from
+    /// [`Pwm3::get_max_duty`]. `min_window` must include the settling time of the shunt
+    /// amplifier plus `sample_offset`, and cover a conversion and the interrupt latency
+    /// since the trigger is moved between the two samples.
+    pub fn new(min_window: u16, sample_offset: u16, max_duty: u16) -> Self {
+        defmt::assert!(sample_offset < min_window);
+        defmt::assert!(2 * min_window < max_duty);
+        Self {
+            min_window,
+            sample_offset,
+            max_duty,
+            duties: [0; 3],
+            plan: Plan::default(),
+            next: Step::First,
+            first: 0,
+        }
+    }
+
+    /// Plan the compare values of one period for the duties (a, b, c).
+    pub fn plan(&self, duties: [u16; 3]) -> Plan {
+        plan(
+            duties,
+            self.min_window,
+            self.sample_offset,
+            self.max_duty - 1,
+        )
+    }
+
+    /// Duties (a, b, c) to apply from the next period.
+    pub fn set_duties(&mut self, duties: [u16; 3]) {
+        self.duties = duties;
+    }
+
+    /// Load the first period, call before the trigger is enabled.
+    pub fn start<T, A, B, C>(&mut self, pwm: &mut Pwm3<'_, T, A, B, C>, duties: [u16; 3])
+    where
+        T: GeneralInstance4Channel,
+        A: TimerChannel,
+        B: TimerChannel,
+        C: TimerChannel,
+    {
+        self.duties = duties;
+        self.plan = self.plan(duties);
+        self.next = Step::First;
+        pwm.set_trigger_preload(false);
+        Self::load(pwm, self.plan.up);
+        pwm.set_trigger(self.plan.triggers[0]);
+    }
+
+    /// Feed an injected result in milliamps.
+    ///
+    /// Moves the trigger on and returns the phase currents after the second sample of a
+    /// period. The trigger has to be moved before the counter reaches the next one, so
+    /// at full rate this belongs in the ADC interrupt.
+    pub fn on_sample<T, A, B, C>(
+        &mut self,
+        pwm: &mut Pwm3<'_, T, A, B, C>,
+        milliamps: i32,
+    ) -> Option<PhaseCurrents>
+    where
+        T: GeneralInstance4Channel,
+        A: TimerChannel,
+        B: TimerChannel,
+        C: TimerChannel,
+    {
+        match self.next {
+            Step::First => {
+                self.first = milliamps;
+                self.next = Step::Second;
+                pwm.set_trigger(self.plan.triggers[1]);
+                None
+            }
+            Step::Second => {
+                let currents = reconstruct(&self.plan, [self.first, milliamps]);
+                let next = self.plan(self.duties);
+                self.next = Step::Reload;
+                // taken over at the top of the count
+                Self::load(pwm, self.plan.down);
+                pwm.set_trigger(self.plan.reload_trigger(&next));
+                self.plan = next;
+                Some(currents)
+            }
+            Step::Reload => {
+                self.next = Step::First;
+                // taken over at the bottom of the count
+                Self::load(pwm, self.plan.up);
+                pwm.set_trigger(self.plan.triggers[0]);
+                None
+            }
+        }
+    }
+
+    /// Wait for both samples of a period on the DC link rank and reconstruct.
+    pub async fn convert<I, T, A, B, C>(
+        &mut self,
+        isense: &mut Isense<'_, I>,
+        rank: usize,
+        pwm: &mut Pwm3<'_, T, A, B, C>,
+    ) -> PhaseCurrents
+    where
+        I: Instance,
+        T: GeneralInstance4Channel,
+        A: TimerChannel,
+        B: TimerChannel,
+        C: TimerChannel,
+    {
+        loop {
+            let milliamps = isense.convert_milliamps().await;
+            if let Some(currents) = self.on_sample(pwm, milliamps[rank]) {
+                return currents;
+            }
+        }
+    }
+
+    fn load<T, A, B, C>(pwm: &mut Pwm3<'_, T, A, B, C>, compare: [u16; 3])
+    where
+        T: GeneralInstance4Channel,
+        A: TimerChannel,
+        B: TimerChannel,
+        C: TimerChannel,
+    {
+        pwm.set_duty(Phase::A, compare[0]);
+        pwm.set_duty(Phase::B, compare[1]);
+        pwm.set_duty(Phase::C, compare[2]);
+    }
+}
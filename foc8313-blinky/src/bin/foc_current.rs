@@ -0,0 +1,155 @@
+#![no_std]
+#![no_main]
+use control::current::tuned_gains;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU16, Ordering};
+use defmt::*;
+use drivers::diagnostics::{check_offsets, Limits};
+use drivers::foc::CurrentControl;
+use drivers::isense::{ControlLoop, CurrentSenseConfig, InjectedTrigger, Isense, Polarity};
+use drivers::phase_current::Reconstruction;
+use drivers::pwm::{CompareOC4, Phase, Pwm3};
+use embassy_executor::Spawner;
+use embassy_stm32::bind_interrupts;
+use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::peripherals::{ADC1, TIM3};
+use embassy_stm32::time::{khz, Hertz};
+use embassy_stm32::timer::{Ch1, Ch2, Ch3};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Timer;
+use {defmt_rtt as _, panic_probe as _};
+
+const SENSE_CONFIG: CurrentSenseConfig = CurrentSenseConfig {
+    shunt_micro_ohms: 6_000,
+    gain: 50,
+    vref_mv: 3300,
+    polarity: Polarity::Normal,
+    offset: 2044,
+};
+
+const PWM_HZ: u32 = 16_000;
+const BUS_VOLTS: f32 = 12.0;
+// phase resistance and inductance of the gimbal motor
+const RESISTANCE: f32 = 5.0;
+const INDUCTANCE: f32 = 0.002;
+
+// injected ranks of phase C and B
+const PHASE_C: usize = 0;
+const PHASE_B: usize = 1;
+
+type Stage = Pwm3<'static, TIM3, Ch1, Ch2, Ch3>;
+
+static FOC: Mutex<CriticalSectionRawMutex, RefCell<Option<(CurrentControl, Stage)>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Electrical angle advanced every period, no position sensor yet.
+static ANGLE: AtomicU16 = AtomicU16::new(0);
+static ANGLE_STEP: AtomicU16 = AtomicU16::new(0);
+
+/// Runs in the ADC interrupt after every PWM triggered conversion.
+struct Foc;
+
+impl ControlLoop for Foc {
+    fn on_injected(raw: &[u16]) {
+        let step = ANGLE_STEP.load(Ordering::Relaxed);
+        let angle = ANGLE.load(Ordering::Relaxed).wrapping_add(step);
+        ANGLE.store(angle, Ordering::Relaxed);
+        FOC.lock(|foc| {
+            if let Some((control, pwm)) = foc.borrow_mut().as_mut() {
+                control.update(pwm, raw, angle);
+            }
+        });
+    }
+}
+
+bind_interrupts!(struct Irqs {
+    ADC1_2 => drivers::isense::InterruptHandler<ADC1, Foc>;
+});
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    cortex_m::asm::udf()
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("🔌 Hello from Embassy STM32!");
+    let mut config = embassy_stm32::Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz::hz(16_000_000),
+            mode: HseMode::Oscillator,
+        });
+        config.rcc.pll = Some(Pll {
+            src: PllSource::HSE,
+            prediv: PllPreDiv::DIV2,
+            mul: PllMul::MUL9,
+        });
+        config.rcc.sys = Sysclk::PLL1_P;
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = APBPrescaler::DIV2;
+        config.rcc.apb2_pre = APBPrescaler::DIV1;
+        config.rcc.adc_pre = ADCPrescaler::DIV6;
+    }
+    let p = embassy_stm32::init(config);
+
+    let mut isense_driver = Isense::new(p.ADC1, SENSE_CONFIG);
+    defmt::assert_eq!(isense_driver.add_injected(p.PA3), PHASE_C);
+    defmt::assert_eq!(isense_driver.add_injected(p.PA4), PHASE_B);
+
+    let mut pwm_driver = Pwm3::new(p.TIM3, p.PA6, p.PA7, p.PB0, CompareOC4, khz(16));
+    unwrap!(isense_driver.enable_overcurrent_trip(3_000, pwm_driver.trip(&*p.PB1)));
+    let mut enable_pin = Output::new(p.PB1, Level::Low, Speed::Low);
+
+    // sample near the top of the count, where all low sides conduct
+    pwm_driver.set_trigger(pwm_driver.get_max_duty() - 1);
+    isense_driver.set_injected_trigger(InjectedTrigger::Tim3Cc4);
+    isense_driver.calibrate_offsets(64).await;
+    if let Err(diagnostic) = check_offsets(&isense_driver, &Limits::default()) {
+        defmt::panic!("current sense fault: {}", diagnostic);
+    }
+
+    let full_scale_ma = SENSE_CONFIG.full_scale_ma();
+    let gains = tuned_gains(
+        RESISTANCE,
+        INDUCTANCE,
+        1_000.0,
+        BUS_VOLTS,
+        full_scale_ma as f32 / 1000.0,
+    );
+    let mut control = CurrentControl::new(
+        isense_driver.calibration(),
+        Reconstruction::two_shunt(PHASE_B, PHASE_C),
+        full_scale_ma,
+        gains,
+        PWM_HZ,
+        pwm_driver.get_max_duty(),
+    );
+    control.stop(&mut pwm_driver);
+    // align the rotor with 500mA on the d axis
+    control.set_current(500, 0);
+    pwm_driver.enable(Phase::A);
+    pwm_driver.enable(Phase::B);
+    pwm_driver.enable(Phase::C);
+    FOC.lock(|foc| foc.replace(Some((control, pwm_driver))));
+    isense_driver.start_control_loop();
+    enable_pin.set_high();
+    Timer::after_millis(500).await;
+
+    // drag the rotor along at 2Hz electrical
+    ANGLE_STEP.store((65_536 * 2 / PWM_HZ) as u16, Ordering::Relaxed);
+    loop {
+        let (current, voltage) = FOC.lock(|foc| {
+            let mut foc = foc.borrow_mut();
+            let (control, _) = unwrap!(foc.as_mut());
+            (control.current(), control.current_loop().voltage())
+        });
+        info!(
+            "id {}mA iq {}mA, vd {} vq {}",
+            current.d, current.q, voltage.d, voltage.q
+        );
+        Timer::after_millis(100).await;
+    }
+}
@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+use core::cell::Cell;
+use defmt::*;
+use drivers::diagnostics::{check_offsets, Limits, RateWatchdog};
+use drivers::isense::{ControlLoop, CurrentSenseConfig, InjectedTrigger, Isense, Polarity};
+use drivers::phase_current::{PhaseCurrents, Reconstruction};
+use drivers::pwm::{CompareOC4, Phase, Pwm3};
+use embassy_executor::Spawner;
+use embassy_stm32::bind_interrupts;
+use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::peripherals::ADC1;
+use embassy_stm32::time::{khz, Hertz};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Timer;
+use {defmt_rtt as _, panic_probe as _};
+
+const SENSE_CONFIG: CurrentSenseConfig = CurrentSenseConfig {
+    shunt_micro_ohms: 6_000,
+    gain: 50,
+    vref_mv: 3300,
+    polarity: Polarity::Normal,
+    offset: 2044,
+};
+
+// injected ranks of phase C and B
+const PHASE_C: usize = 0;
+const PHASE_B: usize = 1;
+
+static CURRENTS: Mutex<CriticalSectionRawMutex, Cell<PhaseCurrents>> =
+    Mutex::new(Cell::new(PhaseCurrents { a: 0, b: 0, c: 0 }));
+
+/// Runs in the ADC interrupt after every PWM triggered conversion.
+struct Currents;
+
+impl ControlLoop for Currents {
+    fn on_injected(raw: &[u16]) {
+        let milliamps = [
+            SENSE_CONFIG.to_milliamps(raw[PHASE_C]),
+            SENSE_CONFIG.to_milliamps(raw[PHASE_B]),
+        ];
+        let currents = Reconstruction::two_shunt(PHASE_B, PHASE_C).reconstruct(&milliamps, [0; 3]);
+        CURRENTS.lock(|c| c.set(currents));
+    }
+}
+
+bind_interrupts!(struct Irqs {
+    ADC1_2 => drivers::isense::InterruptHandler<ADC1, Currents>;
+});
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    cortex_m::asm::udf()
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("🔌 Hello from Embassy STM32!");
+    let mut config = embassy_stm32::Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz::hz(16_000_000),
+            mode: HseMode::Oscillator,
+        });
+        config.rcc.pll = Some(Pll {
+            src: PllSource::HSE,
+            prediv: PllPreDiv::DIV2,
+            mul: PllMul::MUL9,
+        });
+        config.rcc.sys = Sysclk::PLL1_P;
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = APBPrescaler::DIV2;
+        config.rcc.apb2_pre = APBPrescaler::DIV1;
+        config.rcc.adc_pre = ADCPrescaler::DIV6;
+    }
+    let p = embassy_stm32::init(config);
+
+    let mut isense_driver = Isense::new(p.ADC1, SENSE_CONFIG);
+    defmt::assert_eq!(isense_driver.add_injected(p.PA3), PHASE_C);
+    defmt::assert_eq!(isense_driver.add_injected(p.PA4), PHASE_B);
+
+    let mut pwm_driver = Pwm3::new(p.TIM3, p.PA6, p.PA7, p.PB0, CompareOC4, khz(16));
+    unwrap!(isense_driver.enable_overcurrent_trip(3_000, pwm_driver.trip(&*p.PB1)));
+    let mut enable_pin = Output::new(p.PB1, Level::Low, Speed::Low);
+
+    // sample near the top of the count, where all low sides conduct
+    pwm_driver.set_trigger(pwm_driver.get_max_duty() - 1);
+    isense_driver.set_injected_trigger(InjectedTrigger::Tim3Cc4);
+    isense_driver.calibrate_offsets(64).await;
+    if let Err(diagnostic) = check_offsets(&isense_driver, &Limits::default()) {
+        defmt::panic!("current sense fault: {}", diagnostic);
+    }
+    isense_driver.start_control_loop();
+
+    pwm_driver.enable(Phase::A);
+    pwm_driver.enable(Phase::B);
+    pwm_driver.enable(Phase::C);
+    enable_pin.set_high();
+
+    let mut watchdog = RateWatchdog::new(16_000, 5);
+    loop {
+        if let Err(diagnostic) = watchdog.check(isense_driver.injected_count()) {
+            error!("current sense fault: {}", diagnostic);
+        }
+        let currents = CURRENTS.lock(|c| c.get());
+        info!(
+            "measured: a {}mA b {}mA c {}mA",
+            currents.a, currents.b, currents.c
+        );
+        Timer::after_millis(100).await;
+    }
+}
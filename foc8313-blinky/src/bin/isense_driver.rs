@@ -1,7 +1,8 @@
 #![no_std]
 #![no_main]
 use defmt::*;
-use drivers::isense::Isense;
+use drivers::isense::{CurrentSenseConfig, Isense, Polarity};
+use drivers::phase_current::{self, Reconstruction};
 use drivers::pwm::{CompareOC4, Phase, Pwm3};
 use embassy_executor::Spawner;
 use embassy_stm32::bind_interrupts;
@@ -46,13 +47,33 @@ async fn main(_spawner: Spawner) {
     }
     let p = embassy_stm32::init(config);
 
+    let sense_config = CurrentSenseConfig {
+        shunt_micro_ohms: 6_000,
+        gain: 50,
+        vref_mv: 3300,
+        polarity: Polarity::Normal,
+        offset: 2044,
+    };
+    let mut isense_driver = Isense::new(p.ADC1, sense_config);
+    let phase_c = isense_driver.add_injected(p.PA3);
+    let phase_b = isense_driver.add_injected(p.PA4);
+    let reconstruction = Reconstruction::two_shunt(phase_b, phase_c);
+    isense_driver.enable_vref();
+    isense_driver.enable_temperature();
+    isense_driver.sample_internal().await;
+    info!(
+        "vdda: {}mV mcu temperature: {}m°C",
+        isense_driver.vdda_mv(),
+        isense_driver.temperature_mc()
+    );
+
+    let mut pwm_driver = Pwm3::new(p.TIM3, p.PA6, p.PA7, p.PB0, CompareOC4, khz(16));
+    unwrap!(isense_driver.enable_overcurrent_trip(3_000, pwm_driver.trip(&*p.PB1)));
+
     let mut enable_pin = Output::new(p.PB1, Level::Low, Speed::Low);
     //let mut led = Output::new(p.PC14, Level::Low, Speed::Low);
     enable_pin.set_high();
 
-    let mut isense_driver = Isense::new(p.ADC1, p.PA3);
-
-    let mut pwm_driver = Pwm3::new(p.TIM3, p.PA6, p.PA7, p.PB0, CompareOC4, khz(16));
     pwm_driver.enable(Phase::A);
     pwm_driver.enable(Phase::B);
     pwm_driver.enable(Phase::C);
@@ -72,14 +93,35 @@ async fn main(_spawner: Spawner) {
         (duty, off, duty),
     ];
 
-    loop {
+    // the trip has already shut the stage down, keep it latched off until the board is reset
+    let fault = 'run: loop {
         for (a, b, c) in sequence.iter() {
             //pwm_driver.set_duty(Phase::A, *a);
             //pwm_driver.set_duty(Phase::B, *b);
             //pwm_driver.set_duty(Phase::C, *c);
-            let result = isense_driver.convert().await;
-            info!("measured: {:?}\n\n", result);
+            if let Some(fault) = isense_driver.overcurrent() {
+                break 'run fault;
+            }
+            let currents =
+                phase_current::convert(&reconstruction, &mut isense_driver, [*a, *b, *c]).await;
+            info!(
+                "measured: a {}mA b {}mA c {}mA\n\n",
+                currents.a, currents.b, currents.c
+            );
             //Timer::after_millis(50).await;
         }
+    };
+
+    pwm_driver.disable(Phase::A);
+    pwm_driver.disable(Phase::B);
+    pwm_driver.disable(Phase::C);
+    enable_pin.set_low();
+    loop {
+        error!(
+            "overcurrent on channel {}: {}mA, reset to restart",
+            fault.channel,
+            isense_driver.milliamps(fault.rank, fault.raw)
+        );
+        Timer::after_secs(1).await;
     }
 }
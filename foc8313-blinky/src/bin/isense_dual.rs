@@ -0,0 +1,68 @@
+#![no_std]
+#![no_main]
+use defmt::*;
+use drivers::isense::{CurrentSenseConfig, DualIsense, Polarity};
+use embassy_executor::Spawner;
+use embassy_stm32::bind_interrupts;
+use embassy_stm32::gpio::{Level, Output, Speed};
+use embassy_stm32::peripherals::ADC1;
+use embassy_stm32::time::Hertz;
+use embassy_time::Timer;
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    ADC1_2 => drivers::isense::InterruptHandler<ADC1>;
+});
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    cortex_m::asm::udf()
+}
+
+#[embassy_executor::main]
+async fn main(_spawner: Spawner) {
+    info!("🔌 Hello from Embassy STM32!");
+    let mut config = embassy_stm32::Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz::hz(16_000_000),
+            mode: HseMode::Oscillator,
+        });
+        config.rcc.pll = Some(Pll {
+            src: PllSource::HSE,
+            prediv: PllPreDiv::DIV2,
+            mul: PllMul::MUL9,
+        });
+        config.rcc.sys = Sysclk::PLL1_P;
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = APBPrescaler::DIV2;
+        config.rcc.apb2_pre = APBPrescaler::DIV1;
+        config.rcc.adc_pre = ADCPrescaler::DIV6;
+    }
+    let p = embassy_stm32::init(config);
+
+    let mut enable_pin = Output::new(p.PB1, Level::Low, Speed::Low);
+
+    let sense_config = CurrentSenseConfig {
+        shunt_micro_ohms: 6_000,
+        gain: 50,
+        vref_mv: 3300,
+        polarity: Polarity::Normal,
+        offset: 2044,
+    };
+    // phase B on ADC1 and phase C on ADC2, sampled at the same instant
+    let mut isense_driver = DualIsense::new(p.ADC1, p.ADC2, sense_config);
+    let phases = isense_driver.add_injected(p.PA4, p.PA3);
+    isense_driver.master().enable_vref();
+    isense_driver.calibrate_offsets(64).await;
+
+    enable_pin.set_high();
+
+    loop {
+        let result = isense_driver.convert_milliamps().await;
+        let [b, c] = result[phases];
+        info!("measured: b {}mA c {}mA", b, c);
+        Timer::after_millis(50).await;
+    }
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+use defmt::*;
+use drivers::isense::{
+    AdcStream, AnalogInput, CurrentSenseConfig, Isense, Polarity, RegularTrigger,
+};
+use embassy_executor::Spawner;
+use embassy_stm32::bind_interrupts;
+use embassy_stm32::peripherals::ADC1;
+use embassy_stm32::time::Hertz;
+use embassy_time::Timer;
+use static_cell::StaticCell;
+use {defmt_rtt as _, panic_probe as _};
+
+bind_interrupts!(struct Irqs {
+    ADC1_2 => drivers::isense::InterruptHandler<ADC1>;
+});
+
+#[defmt::panic_handler]
+fn panic() -> ! {
+    cortex_m::asm::udf()
+}
+
+const CHANNELS: usize = 2;
+const FRAMES: usize = 64;
+
+static BUFFER: StaticCell<[u16; 2 * CHANNELS * FRAMES]> = StaticCell::new();
+
+#[embassy_executor::task]
+async fn log_stream(mut stream: AdcStream<'static, ADC1>) {
+    // one half of the buffer at a time, the DMA fills the other half meanwhile
+    let mut half = [0u16; CHANNELS * FRAMES];
+    loop {
+        if stream.read(&mut half).await.is_err() {
+            warn!("stream overrun, {} so far", stream.overruns());
+            continue;
+        }
+        let (bus, ntc) = half
+            .chunks_exact(stream.ranks())
+            .fold((0u32, 0u32), |(bus, ntc), frame| {
+                (bus + frame[0] as u32, ntc + frame[1] as u32)
+            });
+        info!(
+            "average raw: bus {} ntc {}",
+            bus / FRAMES as u32,
+            ntc / FRAMES as u32
+        );
+    }
+}
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    info!("🔌 Hello from Embassy STM32!");
+    let mut config = embassy_stm32::Config::default();
+    {
+        use embassy_stm32::rcc::*;
+        config.rcc.hse = Some(Hse {
+            freq: Hertz::hz(16_000_000),
+            mode: HseMode::Oscillator,
+        });
+        config.rcc.pll = Some(Pll {
+            src: PllSource::HSE,
+            prediv: PllPreDiv::DIV2,
+            mul: PllMul::MUL9,
+        });
+        config.rcc.sys = Sysclk::PLL1_P;
+        config.rcc.ahb_pre = AHBPrescaler::DIV1;
+        config.rcc.apb1_pre = APBPrescaler::DIV2;
+        config.rcc.apb2_pre = APBPrescaler::DIV1;
+        config.rcc.adc_pre = ADCPrescaler::DIV6;
+    }
+    let p = embassy_stm32::init(config);
+
+    let sense_config = CurrentSenseConfig {
+        shunt_micro_ohms: 6_000,
+        gain: 50,
+        vref_mv: 3300,
+        polarity: Polarity::Normal,
+        offset: 2044,
+    };
+    let mut isense_driver = Isense::new(p.ADC1, sense_config);
+    isense_driver.add_regular(
+        p.PA0,
+        AnalogInput::Divider {
+            top_ohms: 39_000,
+            bottom_ohms: 3_300,
+        },
+    );
+    isense_driver.add_regular(
+        p.PA1,
+        AnalogInput::Ntc {
+            pullup_ohms: 10_000,
+            r25_ohms: 10_000,
+            beta: 3_380,
+        },
+    );
+
+    let buffer = BUFFER.init([0; 2 * CHANNELS * FRAMES]);
+    let stream = isense_driver.start_stream(p.DMA1_CH1, buffer, RegularTrigger::Continuous);
+    spawner.spawn(log_stream(stream)).unwrap();
+
+    loop {
+        Timer::after_secs(1).await;
+    }
+}